@@ -1,14 +1,12 @@
 use axum::{
-    Json,
     extract::{Path, State},
     http::{HeaderMap, HeaderValue, header::CONTENT_TYPE},
     response::IntoResponse,
 };
-use serde_json::json;
 use std::time::Instant;
 use tokio::fs;
 
-use crate::{response::SynapseResponse, state::AppState};
+use crate::state::AppState;
 
 pub async fn get_data_from_synapse(
     State(state): State<AppState>,
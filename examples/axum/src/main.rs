@@ -7,7 +7,6 @@ use synapse_rust::SynapseClient;
 use crate::{handler::get_data_from_synapse, state::AppState};
 
 mod handler;
-mod response;
 mod state;
 
 #[tokio::main]
@@ -19,7 +18,7 @@ async fn main() {
         .unwrap_or_else(|_| "examples/fastapi/big_payload.json".to_string());
     let synapse_client = SynapseClient::new(socket_path).await.unwrap();
     let state = AppState {
-        big_file_path: big_file_path,
+        big_file_path,
         synapse_client: Arc::new(synapse_client),
     };
 
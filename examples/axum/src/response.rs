@@ -1,6 +0,0 @@
-use serde::{Deserialize, Serialize};
-
-#[derive(Clone, Serialize, Deserialize)]
-pub struct SynapseResponse {
-    pub data: String,
-}
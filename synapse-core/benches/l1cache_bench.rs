@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use criterion::{Criterion, criterion_group, criterion_main};
 use std::{
     hint::black_box,
@@ -15,7 +16,9 @@ fn bench_l1cache_get_hit(c: &mut Criterion) {
     let key = "bench_key".to_string();
 
     rt.block_on(async {
-        cache.set(key.clone(), vec![1u8; 64], None).await;
+        cache
+            .set(key.clone(), Bytes::from(vec![1u8; 64]), None)
+            .await;
     });
 
     c.bench_function("l1cache_get_hit", |b| {
@@ -48,7 +51,7 @@ fn bench_l1cache_set(c: &mut Criterion) {
         .expect("create runtime");
     let cache = L1Cache::new(1024);
     let keys: Vec<String> = (0..1024).map(|i| format!("key_{:04}", i)).collect();
-    let value = vec![1u8; 64];
+    let value = Bytes::from(vec![1u8; 64]);
     let cursor = AtomicUsize::new(0);
 
     c.bench_function("l1cache_set", |b| {
@@ -59,9 +62,36 @@ fn bench_l1cache_set(c: &mut Criterion) {
     });
 }
 
+/// Hits hand out a reference-counted handle to the cached value, so their cost
+/// should stay flat as values grow.
+fn bench_l1cache_get_hit_large(c: &mut Criterion) {
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("create runtime");
+    let cache = L1Cache::new(1024);
+
+    for size in [64 * 1024_usize, 1024 * 1024, 5 * 1024 * 1024] {
+        let key = format!("bench_key_{}", size);
+        rt.block_on(async {
+            cache
+                .set(key.clone(), Bytes::from(vec![1u8; size]), None)
+                .await;
+        });
+
+        c.bench_function(&format!("l1cache_get_hit_{}b", size), |b| {
+            b.to_async(&rt).iter(|| async {
+                let res = cache.get(black_box(&key)).await;
+                black_box(res);
+            });
+        });
+    }
+}
+
 criterion_group!(
     benches,
     bench_l1cache_get_hit,
+    bench_l1cache_get_hit_large,
     bench_l1cache_get_miss,
     bench_l1cache_set
 );
@@ -1,18 +1,102 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use moka::Expiry;
 use moka::future::Cache;
+use moka::notification::RemovalCause;
+use moka::ops::compute::Op;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot};
 
 pub const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
 
+/// Version spoken by this build. Version 1 is the original unversioned framing,
+/// which is still what a client gets when it never sends `OP_HELLO`.
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub const CAP_BATCH: u32 = 1 << 0;
+pub const CAP_REQUEST_IDS: u32 = 1 << 1;
+pub const CAP_LEASES: u32 = 1 << 3;
+pub const CAP_TTL: u32 = 1 << 4;
+pub const CAP_TRACE_CONTEXT: u32 = 1 << 5;
+
 pub const OP_GET: u8 = 1;
 pub const OP_SET: u8 = 2;
+pub const OP_DEL: u8 = 3;
+pub const OP_MGET: u8 = 4;
+pub const OP_MSET: u8 = 5;
+pub const OP_HELLO: u8 = 6;
+pub const OP_GET_OR_LEASE: u8 = 7;
+pub const OP_TTL: u8 = 8;
+pub const OP_EXPIRE: u8 = 9;
+pub const OP_PERSIST: u8 = 10;
+pub const OP_TOUCH: u8 = 11;
+pub const OP_INFO: u8 = 12;
+pub const OP_RELEASE_LEASE: u8 = 13;
 
 pub const RES_OK: u8 = 0;
 pub const RES_HIT: u8 = 1;
 pub const RES_MISS: u8 = 2;
 pub const RES_ERR: u8 = 3;
+pub const RES_VALUES: u8 = 4;
+pub const RES_HELLO: u8 = 5;
+pub const RES_REJECTED: u8 = 6;
+pub const RES_LEASE: u8 = 7;
+pub const RES_TTL: u8 = 8;
+pub const RES_INFO: u8 = 9;
+
+pub const ERR_UNSUPPORTED_VERSION: u16 = 1;
+pub const ERR_MISSING_CAPABILITY: u16 = 2;
+pub const ERR_UNKNOWN_OPCODE: u16 = 3;
+
+pub const TAG_REQUEST_ID: u8 = 0xF0;
+pub const TAG_TRACE_CONTEXT: u8 = 0xF1;
+
+pub const SET_FLAG_SLIDING: u8 = 1 << 0;
+
+/// How long clients hold a fill lease from `get_or_load` before waiters give
+/// up on it.
+pub const DEFAULT_LEASE_MS: u64 = 10_000;
+
+/// How a TTL passed to `SET` is applied.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExpirationMode {
+    /// The entry expires `ttl` after it was written.
+    #[default]
+    Absolute,
+    /// The entry expires once it has not been read or written for `ttl`.
+    Sliding,
+}
+
+/// When `L1Cache::set_if` may write, like Redis `SET ... NX` / `XX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    /// Only write keys that are not cached (`NX`).
+    IfAbsent,
+    /// Only overwrite keys that are already cached (`XX`).
+    IfPresent,
+}
+
+impl ExpirationMode {
+    pub fn flags(self) -> u8 {
+        match self {
+            ExpirationMode::Absolute => 0,
+            ExpirationMode::Sliding => SET_FLAG_SLIDING,
+        }
+    }
+
+    pub fn from_flags(flags: u8) -> Self {
+        if flags & SET_FLAG_SLIDING != 0 {
+            ExpirationMode::Sliding
+        } else {
+            ExpirationMode::Absolute
+        }
+    }
+}
 
 pub fn encode_get(key: &str) -> Bytes {
     let mut out = BytesMut::new();
@@ -23,6 +107,17 @@ pub fn encode_get(key: &str) -> Bytes {
 }
 
 pub fn encode_set(key: &str, value: &[u8], ttl_secs: Option<u64>) -> Bytes {
+    encode_set_with_mode(key, value, ttl_secs, ExpirationMode::Absolute)
+}
+
+/// Like `encode_set`, with a trailing flags byte selecting how `ttl_secs` is
+/// applied. Servers that predate the flags byte ignore it and use absolute TTLs.
+pub fn encode_set_with_mode(
+    key: &str,
+    value: &[u8],
+    ttl_secs: Option<u64>,
+    mode: ExpirationMode,
+) -> Bytes {
     let mut out = BytesMut::new();
     out.put_u8(OP_SET);
     out.put_u32_le(key.len() as u32);
@@ -30,25 +125,332 @@ pub fn encode_set(key: &str, value: &[u8], ttl_secs: Option<u64>) -> Bytes {
     out.put_u64_le(ttl_secs.unwrap_or(0));
     out.extend_from_slice(key.as_bytes());
     out.extend_from_slice(value);
+    out.put_u8(mode.flags());
+    out.freeze()
+}
+
+pub fn encode_del(key: &str) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(OP_DEL);
+    out.put_u32_le(key.len() as u32);
+    out.extend_from_slice(key.as_bytes());
+    out.freeze()
+}
+
+pub fn encode_mget<K: AsRef<str>>(keys: &[K]) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(OP_MGET);
+    out.put_u32_le(keys.len() as u32);
+    for key in keys {
+        let key = key.as_ref();
+        out.put_u32_le(key.len() as u32);
+        out.extend_from_slice(key.as_bytes());
+    }
+    out.freeze()
+}
+
+pub fn encode_mset<K: AsRef<str>, V: AsRef<[u8]>>(
+    entries: &[(K, V)],
+    ttl_secs: Option<u64>,
+) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(OP_MSET);
+    out.put_u32_le(entries.len() as u32);
+    out.put_u64_le(ttl_secs.unwrap_or(0));
+    for (key, value) in entries {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        out.put_u32_le(key.len() as u32);
+        out.put_u32_le(value.len() as u32);
+        out.extend_from_slice(key.as_bytes());
+        out.extend_from_slice(value);
+    }
+    out.freeze()
+}
+
+pub fn encode_get_or_lease(key: &str, lease_ms: u64) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(OP_GET_OR_LEASE);
+    out.put_u32_le(key.len() as u32);
+    out.put_u64_le(lease_ms);
+    out.extend_from_slice(key.as_bytes());
+    out.freeze()
+}
+
+/// Gives up a fill lease without setting the key, e.g. when the loader failed.
+pub fn encode_release_lease(key: &str) -> Bytes {
+    encode_key_op(OP_RELEASE_LEASE, key)
+}
+
+pub fn encode_ttl(key: &str) -> Bytes {
+    encode_key_op(OP_TTL, key)
+}
+
+pub fn encode_expire(key: &str, ttl_secs: u64) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(OP_EXPIRE);
+    out.put_u32_le(key.len() as u32);
+    out.put_u64_le(ttl_secs);
+    out.extend_from_slice(key.as_bytes());
+    out.freeze()
+}
+
+pub fn encode_persist(key: &str) -> Bytes {
+    encode_key_op(OP_PERSIST, key)
+}
+
+pub fn encode_touch(key: &str) -> Bytes {
+    encode_key_op(OP_TOUCH, key)
+}
+
+pub fn encode_info() -> Bytes {
+    Bytes::from_static(&[OP_INFO])
+}
+
+fn encode_key_op(op: u8, key: &str) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(op);
+    out.put_u32_le(key.len() as u32);
+    out.extend_from_slice(key.as_bytes());
     out.freeze()
 }
 
+pub fn encode_hello(version: u16, capabilities: u32) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(OP_HELLO);
+    out.put_u16_le(version);
+    out.put_u32_le(capabilities);
+    out.freeze()
+}
+
+pub fn tag_frame(request_id: u32, frame: &[u8]) -> Bytes {
+    let mut out = BytesMut::with_capacity(5 + frame.len());
+    out.put_u8(TAG_REQUEST_ID);
+    out.put_u32_le(request_id);
+    out.extend_from_slice(frame);
+    out.freeze()
+}
+
+pub fn untag_frame(buf: &[u8]) -> Result<(Option<u32>, &[u8]), String> {
+    match buf.split_first() {
+        Some((&TAG_REQUEST_ID, mut rest)) => {
+            if rest.remaining() < 4 {
+                return Err("Bad request id".into());
+            }
+            let request_id = rest.get_u32_le();
+            Ok((Some(request_id), rest))
+        }
+        _ => Ok((None, buf)),
+    }
+}
+
+/// W3C `traceparent` of the caller's span, sent so the server can record its
+/// work as a child of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: [u8; 16],
+    pub parent_id: [u8; 8],
+    pub flags: u8,
+}
+
+impl TraceParent {
+    const ENCODED_LEN: usize = 16 + 8 + 1;
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Result<[u8; N], String> {
+    if hex.len() != N * 2 {
+        return Err(format!("Bad traceparent field length: {:?}", hex));
+    }
+    let mut out = [0; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("Bad traceparent hex: {:?}", hex))?;
+    }
+    Ok(out)
+}
+
+impl std::str::FromStr for TraceParent {
+    type Err = String;
+
+    /// Parses `00-<trace id>-<parent id>-<flags>`. Later versions may append
+    /// fields, which are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split('-');
+        let (Some(version), Some(trace_id), Some(parent_id), Some(flags)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("Bad traceparent: {:?}", s));
+        };
+        let [version] = parse_hex::<1>(version)?;
+        if version == 0xff || (version == 0 && fields.next().is_some()) {
+            return Err(format!("Bad traceparent version: {:?}", s));
+        }
+        let trace_parent = TraceParent {
+            trace_id: parse_hex(trace_id)?,
+            parent_id: parse_hex(parent_id)?,
+            flags: parse_hex::<1>(flags)?[0],
+        };
+        if trace_parent.trace_id == [0; 16] || trace_parent.parent_id == [0; 8] {
+            return Err(format!("Bad traceparent: all-zero id in {:?}", s));
+        }
+        Ok(trace_parent)
+    }
+}
+
+impl std::fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("00-")?;
+        for byte in self.trace_id {
+            write!(f, "{:02x}", byte)?;
+        }
+        f.write_str("-")?;
+        for byte in self.parent_id {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "-{:02x}", self.flags)
+    }
+}
+
+/// Prefixes a frame with the caller's trace context: `TAG_TRACE_CONTEXT`,
+/// then the trace id, parent id and flags of a version 00 `traceparent` in
+/// binary. Goes inside the request id tag when both are present.
+pub fn trace_frame(trace_parent: &TraceParent, frame: &[u8]) -> Bytes {
+    let mut out = BytesMut::with_capacity(1 + TraceParent::ENCODED_LEN + frame.len());
+    out.put_u8(TAG_TRACE_CONTEXT);
+    out.extend_from_slice(&trace_parent.trace_id);
+    out.extend_from_slice(&trace_parent.parent_id);
+    out.put_u8(trace_parent.flags);
+    out.extend_from_slice(frame);
+    out.freeze()
+}
+
+pub fn untrace_frame(buf: &[u8]) -> Result<(Option<TraceParent>, &[u8]), String> {
+    match buf.split_first() {
+        Some((&TAG_TRACE_CONTEXT, rest)) => {
+            if rest.len() < TraceParent::ENCODED_LEN {
+                return Err("Bad trace context".into());
+            }
+            let (context, rest) = rest.split_at(TraceParent::ENCODED_LEN);
+            let trace_parent = TraceParent {
+                trace_id: context[..16].try_into().expect("16 bytes"),
+                parent_id: context[16..24].try_into().expect("8 bytes"),
+                flags: context[24],
+            };
+            Ok((Some(trace_parent), rest))
+        }
+        _ => Ok((None, buf)),
+    }
+}
+
+/// Stable 64-bit FNV-1a hash of a key, so logs and traces from clients and
+/// servers can be correlated without recording the key itself.
+pub fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Parses a memory size such as `"512MiB"`, `"2GB"` or `"1048576"`. Binary
+/// (`KiB`, `MiB`, ...) and bare (`K`, `M`, ...) suffixes are powers of 1024,
+/// `KB`, `MB`, ... are powers of 1000. Suffixes are case-insensitive.
+pub fn parse_byte_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Bad byte size: {:?}", input))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(format!("Bad byte size unit: {:?}", input)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Byte size overflows u64: {:?}", input))
+}
+
+/// Error for a frame that ends before a field it announces.
+pub const TRUNCATED: &str = "Truncated frame";
+
+fn take_bytes(buf: &mut &[u8], len: usize) -> Result<Bytes, String> {
+    if buf.remaining() < len {
+        return Err(TRUNCATED.to_string());
+    }
+    Ok(buf.copy_to_bytes(len))
+}
+
 pub fn decode_response(mut buf: &[u8]) -> Result<CacheResponce, String> {
-    let response = buf.get_u8();
+    let response = buf.try_get_u8().map_err(|_| TRUNCATED.to_string())?;
     match response {
         RES_OK => Ok(CacheResponce::Ok),
         RES_MISS => Ok(CacheResponce::Miss),
+        RES_LEASE => Ok(CacheResponce::Lease),
+        RES_TTL => match buf.try_get_u8().map_err(|_| TRUNCATED.to_string())? {
+            0 => Ok(CacheResponce::Ttl(None)),
+            _ => Ok(CacheResponce::Ttl(Some(
+                buf.try_get_u64_le().map_err(|_| TRUNCATED.to_string())?,
+            ))),
+        },
         RES_HIT => {
-            let len = buf.get_u32_le() as usize;
-            let value = buf.copy_to_bytes(len).to_vec();
+            let len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let value = take_bytes(&mut buf, len)?;
             Ok(CacheResponce::Hit(value))
         }
         RES_ERR => {
-            let len = buf.get_u32_le() as usize;
-            let msg =
-                String::from_utf8(buf.copy_to_bytes(len).to_vec()).map_err(|_| "Bad err utf-8")?;
+            let len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let msg = String::from_utf8(take_bytes(&mut buf, len)?.to_vec())
+                .map_err(|_| "Bad err utf-8")?;
             Ok(CacheResponce::Error(msg))
         }
+        RES_VALUES => {
+            let count = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let mut values = Vec::with_capacity(count.min(buf.remaining()));
+            for _ in 0..count {
+                match buf.try_get_u8().map_err(|_| TRUNCATED.to_string())? {
+                    RES_HIT => {
+                        let len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+                        values.push(Some(take_bytes(&mut buf, len)?));
+                    }
+                    RES_MISS => values.push(None),
+                    _ => return Err("Unknown value marker".into()),
+                }
+            }
+            Ok(CacheResponce::Values(values))
+        }
+        RES_HELLO => {
+            let version = buf.try_get_u16_le().map_err(|_| TRUNCATED.to_string())?;
+            let capabilities = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())?;
+            Ok(CacheResponce::Hello {
+                version,
+                capabilities,
+            })
+        }
+        RES_REJECTED => {
+            let code = buf.try_get_u16_le().map_err(|_| TRUNCATED.to_string())?;
+            let len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let message = String::from_utf8(take_bytes(&mut buf, len)?.to_vec())
+                .map_err(|_| "Bad err utf-8")?;
+            Ok(CacheResponce::Rejected { code, message })
+        }
+        RES_INFO => {
+            let len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let info = serde_json::from_slice(&take_bytes(&mut buf, len)?)
+                .map_err(|e| format!("Bad info document: {}", e))?;
+            Ok(CacheResponce::Info(Box::new(info)))
+        }
         _ => Err("Unknown result".into()),
     }
 }
@@ -60,25 +462,213 @@ pub enum CacheCommand {
     },
     Set {
         key: String,
-        value: Vec<u8>,
+        value: Bytes,
+        ttl_secs: Option<u64>,
+        mode: ExpirationMode,
+    },
+    Delete {
+        key: String,
+    },
+    MGet {
+        keys: Vec<String>,
+    },
+    MSet {
+        entries: Vec<(String, Bytes)>,
         ttl_secs: Option<u64>,
     },
+    Hello {
+        version: u16,
+        capabilities: u32,
+    },
+    GetOrLease {
+        key: String,
+        lease_ms: u64,
+    },
+    ReleaseLease {
+        key: String,
+    },
+    Ttl {
+        key: String,
+    },
+    Expire {
+        key: String,
+        ttl_secs: u64,
+    },
+    Persist {
+        key: String,
+    },
+    Touch {
+        key: String,
+    },
+    Info,
+}
+
+impl CacheCommand {
+    /// Lowercase opcode name, for logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheCommand::Get { .. } => "get",
+            CacheCommand::Set { .. } => "set",
+            CacheCommand::Delete { .. } => "del",
+            CacheCommand::MGet { .. } => "mget",
+            CacheCommand::MSet { .. } => "mset",
+            CacheCommand::Hello { .. } => "hello",
+            CacheCommand::GetOrLease { .. } => "get_or_lease",
+            CacheCommand::ReleaseLease { .. } => "release_lease",
+            CacheCommand::Ttl { .. } => "ttl",
+            CacheCommand::Expire { .. } => "expire",
+            CacheCommand::Persist { .. } => "persist",
+            CacheCommand::Touch { .. } => "touch",
+            CacheCommand::Info => "info",
+        }
+    }
+
+    /// The `CAP_*` bit a client must have negotiated to send this command, or
+    /// 0 for commands every protocol version understands.
+    pub fn capability(&self) -> u32 {
+        match self {
+            CacheCommand::MGet { .. } | CacheCommand::MSet { .. } => CAP_BATCH,
+            CacheCommand::GetOrLease { .. } | CacheCommand::ReleaseLease { .. } => CAP_LEASES,
+            CacheCommand::Set {
+                mode: ExpirationMode::Sliding,
+                ..
+            }
+            | CacheCommand::Ttl { .. }
+            | CacheCommand::Expire { .. }
+            | CacheCommand::Persist { .. }
+            | CacheCommand::Touch { .. } => CAP_TTL,
+            CacheCommand::Get { .. }
+            | CacheCommand::Set { .. }
+            | CacheCommand::Delete { .. }
+            | CacheCommand::Hello { .. }
+            | CacheCommand::Info => 0,
+        }
+    }
+
+    /// The key of single-key commands.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            CacheCommand::Get { key }
+            | CacheCommand::Set { key, .. }
+            | CacheCommand::Delete { key }
+            | CacheCommand::GetOrLease { key, .. }
+            | CacheCommand::ReleaseLease { key }
+            | CacheCommand::Ttl { key }
+            | CacheCommand::Expire { key, .. }
+            | CacheCommand::Persist { key }
+            | CacheCommand::Touch { key } => Some(key),
+            CacheCommand::MGet { .. }
+            | CacheCommand::MSet { .. }
+            | CacheCommand::Hello { .. }
+            | CacheCommand::Info => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum CacheResponce {
     Ok,
-    Hit(Vec<u8>),
+    Hit(Bytes),
     Miss,
     Error(String),
+    Values(Vec<Option<Bytes>>),
+    Hello {
+        version: u16,
+        capabilities: u32,
+    },
+    Rejected {
+        code: u16,
+        message: String,
+    },
+    Lease,
+    /// Remaining lifetime of an existing key in milliseconds, `None` when it
+    /// never expires. A missing key is reported as `Miss`.
+    Ttl(Option<u64>),
+    /// Sent as a length-prefixed JSON document.
+    Info(Box<ServerInfo>),
+}
+
+/// State of a running server, as answered to `OP_INFO`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub cache: CacheStats,
+    /// Open connections by protocol.
+    pub connections: BTreeMap<String, u64>,
+    pub redis: RedisInfo,
+    /// The configuration in effect, with secrets removed.
+    pub config: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RedisInfo {
+    pub enabled: bool,
+    /// The id this server stamps on the cache updates it publishes.
+    pub node_id: Option<String>,
+    /// Whether the pub/sub subscriber currently holds a subscription.
+    pub subscribed: bool,
+    /// When the last cache update arrived over pub/sub, in milliseconds since
+    /// the Unix epoch.
+    pub last_message_unix_ms: Option<u64>,
 }
 
 #[derive(Clone)]
 struct Entry {
-    value: Vec<u8>,
+    value: Bytes,
+    ttl: Option<Duration>,
+    mode: ExpirationMode,
     expires_at: Option<Instant>,
+    /// When the value was written, compared against `mark_all_stale`.
+    written_at: Instant,
+}
+
+impl Entry {
+    fn new(value: Bytes, ttl: Option<Duration>) -> Self {
+        Self::with_mode(value, ttl, ExpirationMode::Absolute)
+    }
+
+    fn with_mode(value: Bytes, ttl: Option<Duration>, mode: ExpirationMode) -> Self {
+        let expires_at = match mode {
+            ExpirationMode::Absolute => ttl.map(|ttl| Instant::now() + ttl),
+            ExpirationMode::Sliding => None,
+        };
+        Self {
+            value,
+            ttl,
+            mode,
+            expires_at,
+            written_at: Instant::now(),
+        }
+    }
+
+    /// The same value with its lifetime restarted under a new TTL.
+    fn renewed(self, ttl: Option<Duration>) -> Self {
+        Self {
+            written_at: self.written_at,
+            ..Self::with_mode(self.value, ttl, self.mode)
+        }
+    }
+
+    fn idle(&self) -> Option<Duration> {
+        match self.mode {
+            ExpirationMode::Absolute => None,
+            ExpirationMode::Sliding => self.ttl,
+        }
+    }
+
+    fn time_to_live(&self) -> Option<Duration> {
+        match self.idle() {
+            Some(idle) => Some(idle),
+            None => self
+                .expires_at
+                .map(|t| t.saturating_duration_since(Instant::now())),
+        }
+    }
 }
 
+type Leases = Arc<Mutex<HashMap<String, oneshot::Sender<Entry>>>>;
+
 struct EntryExpiry;
 
 impl<K> Expiry<K, Entry> for EntryExpiry {
@@ -88,75 +678,1555 @@ impl<K> Expiry<K, Entry> for EntryExpiry {
         value: &Entry,
         _created_at: Instant,
     ) -> Option<Duration> {
-        value
-            .expires_at
-            .map(|t| t.saturating_duration_since(Instant::now()))
+        value.time_to_live()
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &K,
+        value: &Entry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        value.time_to_live()
+    }
+
+    fn expire_after_read(
+        &self,
+        _key: &K,
+        value: &Entry,
+        _read_at: Instant,
+        duration_until_expiry: Option<Duration>,
+        _last_modified_at: Instant,
+    ) -> Option<Duration> {
+        value.idle().or(duration_until_expiry)
+    }
+}
+
+/// A write observed by `L1Cache::watch`.
+#[derive(Debug, Clone)]
+pub enum CacheEvent {
+    Set { key: String, value: Bytes },
+    Delete { key: String },
+}
+
+/// Events a slow watcher may fall behind by before it starts missing them.
+const WATCH_CAPACITY: usize = 1024;
+
+/// How an `L1Cache` is bounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capacity {
+    /// At most this many entries.
+    Entries(u64),
+    /// At most this many bytes of keys and values. Entries larger than 4 GiB
+    /// are weighed as 4 GiB.
+    Bytes(u64),
+}
+
+impl Capacity {
+    fn build(self, counters: &Arc<Counters>) -> Cache<String, Entry> {
+        let counters = counters.clone();
+        let builder = Cache::builder()
+            .expire_after(EntryExpiry)
+            .eviction_listener(move |_key, _entry, cause| {
+                let counter = match cause {
+                    RemovalCause::Size => &counters.evicted,
+                    RemovalCause::Expired => &counters.expired,
+                    RemovalCause::Explicit | RemovalCause::Replaced => return,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+        match self {
+            Capacity::Entries(max_entries) => builder.max_capacity(max_entries).build(),
+            Capacity::Bytes(max_bytes) => builder
+                .weigher(|key: &String, entry: &Entry| {
+                    (key.len() + entry.value.len())
+                        .try_into()
+                        .unwrap_or(u32::MAX)
+                })
+                .max_capacity(max_bytes)
+                .build(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evicted: AtomicU64,
+    expired: AtomicU64,
+}
+
+impl Counters {
+    fn lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters since an `L1Cache` was created, plus its current size.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups that found nothing, including ones a loader then filled.
+    pub misses: u64,
+    /// Entries dropped to stay within capacity.
+    pub evicted: u64,
+    /// Entries dropped because their TTL ran out.
+    pub expired: u64,
+    pub entry_count: u64,
+    pub weighted_size: u64,
+}
+
+impl CacheStats {
+    /// Every statistic by field name, in declaration order, for reports that
+    /// list them rather than pick fields out.
+    pub fn fields(&self) -> [(&'static str, u64); 6] {
+        [
+            ("hits", self.hits),
+            ("misses", self.misses),
+            ("evicted", self.evicted),
+            ("expired", self.expired),
+            ("entry_count", self.entry_count),
+            ("weighted_size", self.weighted_size),
+        ]
     }
 }
 
+struct Store {
+    cache: Cache<String, Entry>,
+    capacity: Capacity,
+    /// The cache a running `resize` is copying from. Deletes reach it too, so
+    /// the copy cannot bring a deleted key back.
+    draining: Option<Cache<String, Entry>>,
+}
+
 #[derive(Clone)]
 pub struct L1Cache {
-    inner: Cache<String, Entry>,
+    store: Arc<RwLock<Store>>,
+    resizing: Arc<tokio::sync::Mutex<()>>,
+    /// Held shared by writes and exclusively by `resize` while it swaps
+    /// stores, so no write lands in a store that is already being copied.
+    writes: Arc<tokio::sync::RwLock<()>>,
+    counters: Arc<Counters>,
+    leases: Leases,
+    events: broadcast::Sender<CacheEvent>,
+    /// Entries written at or before this are stale.
+    stale_before: Arc<RwLock<Option<Instant>>>,
+    /// Every entry is stale while set.
+    catching_up: Arc<AtomicBool>,
 }
 
 impl L1Cache {
+    /// Cache holding at most `max_capacity` entries.
     pub fn new(max_capacity: u64) -> Self {
-        let inner = Cache::builder()
-            .expire_after(EntryExpiry)
-            .max_capacity(max_capacity)
-            .build();
+        Self::with_capacity(Capacity::Entries(max_capacity))
+    }
 
-        Self { inner }
+    /// Cache bounded by the total size of its keys and values in bytes rather
+    /// than by entry count. Entries larger than 4 GiB are weighed as 4 GiB.
+    pub fn with_max_bytes(max_bytes: u64) -> Self {
+        Self::with_capacity(Capacity::Bytes(max_bytes))
     }
 
-    pub async fn get(&self, key: &str) -> CacheResponce {
-        match self.inner.get(key).await {
-            Some(entry) => CacheResponce::Hit(entry.value),
-            None => CacheResponce::Miss,
+    pub fn with_capacity(capacity: Capacity) -> Self {
+        let counters = Arc::<Counters>::default();
+        Self {
+            store: Arc::new(RwLock::new(Store {
+                cache: capacity.build(&counters),
+                capacity,
+                draining: None,
+            })),
+            resizing: Arc::default(),
+            writes: Arc::default(),
+            counters,
+            leases: Leases::default(),
+            events: broadcast::channel(WATCH_CAPACITY).0,
+            stale_before: Arc::default(),
+            catching_up: Arc::default(),
         }
     }
 
-    pub async fn set(&self, key: String, value: Vec<u8>, ttl_secs: Option<u64>) {
-        let ttl = ttl_secs.map(|s| Instant::now() + Duration::from_secs(s));
-        let entry = Entry {
-            value,
-            expires_at: ttl,
+    fn cache(&self) -> Cache<String, Entry> {
+        self.store
+            .read()
+            .expect("cache store lock poisoned")
+            .cache
+            .clone()
+    }
+
+    /// The cache to write `key` to. While a resize is draining the old cache,
+    /// `key` is moved over first, so the write sees its current entry.
+    async fn cache_for(&self, key: &str) -> Cache<String, Entry> {
+        let (cache, draining) = {
+            let store = self.store.read().expect("cache store lock poisoned");
+            (store.cache.clone(), store.draining.clone())
         };
-        self.inner.insert(key, entry).await;
+        if let Some(draining) = draining {
+            move_entry(&draining, &cache, key).await;
+        }
+        cache
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{CacheResponce, L1Cache};
-    use tokio::time::{Duration, sleep};
+    pub fn capacity(&self) -> Capacity {
+        self.store
+            .read()
+            .expect("cache store lock poisoned")
+            .capacity
+    }
 
-    #[tokio::test]
-    async fn cache_get_miss() {
-        let cache = L1Cache::new(10);
-        assert!(matches!(cache.get("missing").await, CacheResponce::Miss));
+    /// Rebounds the cache without dropping it: entries move to a cache built
+    /// for `capacity`, keeping their TTLs, until it is full. Writes wait for
+    /// the swap, then move their key over before applying, so they win over
+    /// the copies; reads of keys not moved yet are misses.
+    pub async fn resize(&self, capacity: Capacity) {
+        let _resizing = self.resizing.lock().await;
+        let (old, new) = {
+            let _swapping = self.writes.write().await;
+            let mut store = self.store.write().expect("cache store lock poisoned");
+            if store.capacity == capacity {
+                return;
+            }
+            let old = std::mem::replace(&mut store.cache, capacity.build(&self.counters));
+            store.capacity = capacity;
+            store.draining = Some(old.clone());
+            (old, store.cache.clone())
+        };
+
+        for (key, _) in old.iter() {
+            move_entry(&old, &new, &key).await;
+        }
+
+        self.store
+            .write()
+            .expect("cache store lock poisoned")
+            .draining = None;
+        old.invalidate_all();
     }
 
-    #[tokio::test]
-    async fn cache_set_get_hit() {
-        let cache = L1Cache::new(10);
-        cache
-            .set("alpha".to_string(), b"hello".to_vec(), None)
-            .await;
-        match cache.get("alpha").await {
-            CacheResponce::Hit(bytes) => assert_eq!(bytes, b"hello".to_vec()),
-            other => panic!("Expected Hit, got {:?}", other),
+    /// Subscribes to writes and deletes made through this cache, including
+    /// those applied on behalf of other nodes. Expiry and eviction are not
+    /// reported.
+    pub fn watch(&self) -> broadcast::Receiver<CacheEvent> {
+        self.events.subscribe()
+    }
+
+    fn notify(&self, event: impl FnOnce() -> CacheEvent) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event());
         }
     }
 
-    #[tokio::test]
-    async fn cache_ttl_expiry() {
-        let cache = L1Cache::new(10);
-        cache
-            .set("beta".to_string(), b"value".to_vec(), Some(1))
+    /// Current weight of the cache: bytes for `with_max_bytes` caches, entries
+    /// otherwise. Like `entry_count`, it trails recent writes slightly.
+    pub fn weighted_size(&self) -> u64 {
+        self.cache().weighted_size()
+    }
+
+    pub fn entry_count(&self) -> u64 {
+        self.cache().entry_count()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let cache = self.cache();
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evicted: self.counters.evicted.load(Ordering::Relaxed),
+            expired: self.counters.expired.load(Ordering::Relaxed),
+            entry_count: cache.entry_count(),
+            weighted_size: cache.weighted_size(),
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> CacheResponce {
+        let entry = self.lookup(&self.cache(), key).await;
+        self.counters.lookup(entry.is_some());
+        match entry {
+            Some(entry) => CacheResponce::Hit(entry.value),
+            None => CacheResponce::Miss,
+        }
+    }
+
+    pub async fn set(&self, key: String, value: Bytes, ttl_secs: Option<u64>) {
+        self.set_with_mode(key, value, ttl_secs, ExpirationMode::Absolute)
             .await;
-        sleep(Duration::from_millis(1100)).await;
-        assert!(matches!(cache.get("beta").await, CacheResponce::Miss));
+    }
+
+    pub async fn set_with_mode(
+        &self,
+        key: String,
+        value: Bytes,
+        ttl_secs: Option<u64>,
+        mode: ExpirationMode,
+    ) {
+        let entry = Entry::with_mode(value, ttl_secs.map(Duration::from_secs), mode);
+        self.fulfil_lease(&key, &entry);
+        self.notify(|| CacheEvent::Set {
+            key: key.clone(),
+            value: entry.value.clone(),
+        });
+        let _writing = self.writes.read().await;
+        self.cache_for(&key).await.insert(key, entry).await;
+    }
+
+    /// Writes `key` only if `condition` holds, checked atomically against the
+    /// current entry. Returns whether the value was stored.
+    pub async fn set_if(
+        &self,
+        key: String,
+        value: Bytes,
+        ttl_secs: Option<u64>,
+        condition: SetCondition,
+    ) -> bool {
+        let entry = Entry::new(value, ttl_secs.map(Duration::from_secs));
+        let mut stored = false;
+        let _writing = self.writes.read().await;
+        self.cache_for(&key)
+            .await
+            .entry_by_ref(&key)
+            .and_compute_with(|current| {
+                let current = current.filter(|current| !self.is_stale(current.value()));
+                let op = match (condition, current) {
+                    (SetCondition::IfAbsent, None) | (SetCondition::IfPresent, Some(_)) => {
+                        stored = true;
+                        Op::Put(entry.clone())
+                    }
+                    _ => Op::Nop,
+                };
+                std::future::ready(op)
+            })
+            .await;
+        if stored {
+            self.fulfil_lease(&key, &entry);
+            self.notify(|| CacheEvent::Set {
+                key,
+                value: entry.value,
+            });
+        }
+        stored
+    }
+
+    /// Whether `key` is cached and not stale. Unlike `get` this is not a read,
+    /// so it does not refresh sliding TTLs.
+    pub async fn contains(&self, key: &str) -> bool {
+        let cache = self.cache();
+        self.drop_stale(&cache, key).await;
+        cache.contains_key(key)
+    }
+
+    /// Ends the fill lease on `key` without a value, so its waiters get a miss
+    /// now rather than when the lease runs out. Returns whether a lease was
+    /// held.
+    pub fn release_lease(&self, key: &str) -> bool {
+        // Dropping the fill sender fails the lookup the waiters joined.
+        self.leases
+            .lock()
+            .expect("lease table lock poisoned")
+            .remove(key)
+            .is_some()
+    }
+
+    fn fulfil_lease(&self, key: &str, entry: &Entry) {
+        let waiter = self
+            .leases
+            .lock()
+            .expect("lease table lock poisoned")
+            .remove(key);
+        if let Some(waiter) = waiter {
+            let _ = waiter.send(entry.clone());
+        }
+    }
+
+    /// Single-flight lookup for callers that load values themselves. On a miss
+    /// the first caller gets `CacheResponce::Lease` and is expected to `set` the
+    /// key within `lease`, or `release_lease` it. Concurrent callers wait for
+    /// that fill and get the value, or `CacheResponce::Miss` if the lease is
+    /// released or runs out first.
+    pub async fn get_or_lease(&self, key: &str, lease: Duration) -> CacheResponce {
+        let (granted_tx, granted_rx) = oneshot::channel();
+        let leases = self.leases.clone();
+        let lease_key = key.to_string();
+        let fill = async move {
+            let (fill_tx, fill_rx) = oneshot::channel();
+            leases
+                .lock()
+                .expect("lease table lock poisoned")
+                .insert(lease_key.clone(), fill_tx);
+            let _ = granted_tx.send(());
+
+            match tokio::time::timeout(lease, fill_rx).await {
+                Ok(Ok(entry)) => Ok(entry),
+                _ => {
+                    leases
+                        .lock()
+                        .expect("lease table lock poisoned")
+                        .remove(&lease_key);
+                    Err(())
+                }
+            }
+        };
+
+        // The lookup outlives this call when we hand out the lease: it keeps
+        // waiting for the fill so that other callers can join it.
+        let inner = self.cache();
+        self.drop_stale(&inner, key).await;
+        let key = key.to_string();
+        let mut lookup = tokio::spawn(async move { inner.try_get_with(key, fill).await });
+
+        let response = tokio::select! {
+            biased;
+            Ok(()) = granted_rx => CacheResponce::Lease,
+            res = &mut lookup => match res {
+                Ok(Ok(entry)) => CacheResponce::Hit(entry.value),
+                _ => CacheResponce::Miss,
+            },
+        };
+        self.counters
+            .lookup(matches!(response, CacheResponce::Hit(_)));
+        response
+    }
+
+    /// Returns the cached value or runs `load` to produce it. Concurrent callers
+    /// for the same key share the first caller's `load`; an error is returned to
+    /// all of them and nothing is cached.
+    pub async fn get_or_load<F, E>(
+        &self,
+        key: &str,
+        ttl_secs: Option<u64>,
+        load: F,
+    ) -> Result<Bytes, Arc<E>>
+    where
+        F: Future<Output = Result<Bytes, E>>,
+        E: Send + Sync + 'static,
+    {
+        let mut loaded = false;
+        let init = async {
+            loaded = true;
+            load.await
+                .map(|value| Entry::new(value, ttl_secs.map(Duration::from_secs)))
+        };
+        let cache = self.cache_for(key).await;
+        self.drop_stale(&cache, key).await;
+        let entry = cache.try_get_with_by_ref(key, init).await;
+        self.counters.lookup(!loaded);
+        let value = entry.map(|entry| entry.value)?;
+        if loaded {
+            self.keep_fill(&cache, key).await;
+            self.notify_fill(key, &value);
+        }
+        Ok(value)
+    }
+
+    /// Returns the cached value or, on a miss, awaits `fetch` to load it together
+    /// with its remaining TTL. Concurrent misses for the same key share a single
+    /// `fetch`; a `None` result leaves the key absent. A stale entry counts as
+    /// a miss, so this is how stale entries get revalidated.
+    pub async fn get_or_fetch<F>(&self, key: &str, fetch: F) -> CacheResponce
+    where
+        F: Future<Output = Option<(Bytes, Option<Duration>)>>,
+    {
+        let mut fetched = false;
+        let init = async {
+            fetched = true;
+            fetch.await.map(|(value, ttl)| Entry::new(value, ttl))
+        };
+        let cache = self.cache_for(key).await;
+        self.drop_stale(&cache, key).await;
+        let entry = cache.optionally_get_with_by_ref(key, init).await;
+        self.counters.lookup(!fetched);
+        if fetched && let Some(entry) = &entry {
+            self.keep_fill(&cache, key).await;
+            self.notify_fill(key, &entry.value);
+        }
+        match entry {
+            Some(entry) => CacheResponce::Hit(entry.value),
+            None => CacheResponce::Miss,
+        }
+    }
+
+    /// Moves a value filled into `cache` over to the current store, in case a
+    /// resize swapped stores while it loaded. Loaders may take long and may
+    /// write to the cache themselves, so they run without holding off resizes.
+    async fn keep_fill(&self, cache: &Cache<String, Entry>, key: &str) {
+        move_entry(cache, &self.cache(), key).await;
+    }
+
+    /// Reports a value `get_or_load` or `get_or_fetch` stored to watchers, like
+    /// any other write. Only the caller whose loader ran reports it.
+    fn notify_fill(&self, key: &str, value: &Bytes) {
+        self.notify(|| CacheEvent::Set {
+            key: key.to_string(),
+            value: value.clone(),
+        });
+    }
+
+    pub async fn get_many(&self, keys: &[String]) -> CacheResponce {
+        let cache = self.cache();
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let entry = self.lookup(&cache, key).await;
+            self.counters.lookup(entry.is_some());
+            values.push(entry.map(|entry| entry.value));
+        }
+        CacheResponce::Values(values)
+    }
+
+    pub async fn set_many(&self, entries: Vec<(String, Bytes)>, ttl_secs: Option<u64>) {
+        for (key, value) in entries {
+            self.set(key, value, ttl_secs).await;
+        }
+    }
+
+    pub async fn invalidate(&self, key: &str) {
+        self.remove(key).await;
+    }
+
+    /// Drops `key` like `invalidate`. Returns whether it was cached.
+    pub async fn remove(&self, key: &str) -> bool {
+        let _writing = self.writes.read().await;
+        let removed = self.cache_for(key).await.remove(key).await.is_some();
+        self.notify(|| CacheEvent::Delete {
+            key: key.to_string(),
+        });
+        removed
+    }
+
+    /// Drops `key` unless it was written after `before`, the moment it was
+    /// found missing elsewhere. Returns whether it was dropped.
+    pub async fn invalidate_if_older(&self, key: &str, before: Instant) -> bool {
+        let mut removed = false;
+        let _writing = self.writes.read().await;
+        self.cache_for(key)
+            .await
+            .entry_by_ref(key)
+            .and_compute_with(|current| {
+                let op = match current {
+                    Some(current) if current.value().written_at <= before => {
+                        removed = true;
+                        Op::Remove
+                    }
+                    _ => Op::Nop,
+                };
+                std::future::ready(op)
+            })
+            .await;
+        if removed {
+            self.notify(|| CacheEvent::Delete {
+                key: key.to_string(),
+            });
+        }
+        removed
+    }
+
+    /// Drops every entry, for when writes made elsewhere may have been missed.
+    /// Watchers are not notified.
+    pub fn invalidate_all(&self) {
+        let store = self.store.read().expect("cache store lock poisoned");
+        if let Some(draining) = &store.draining {
+            draining.invalidate_all();
+        }
+        store.cache.invalidate_all();
+    }
+
+    /// Marks every entry stale, for when writes made elsewhere may have been
+    /// missed. Reads treat a stale entry as a miss and drop it; writes made
+    /// afterwards are fresh. Cheaper than `invalidate_all` when most entries
+    /// are never read again.
+    pub fn mark_all_stale(&self) {
+        *self
+            .stale_before
+            .write()
+            .expect("stale marker lock poisoned") = Some(Instant::now());
+    }
+
+    /// Treats every entry as stale until `finish_catch_up`, for while writes
+    /// made elsewhere are being replayed: reads miss (and drop what they
+    /// find) rather than serve a value a pending update would change.
+    pub fn start_catch_up(&self) {
+        self.catching_up.store(true, Ordering::Relaxed);
+    }
+
+    /// Ends `start_catch_up`; entries still cached are served again.
+    pub fn finish_catch_up(&self) {
+        self.catching_up.store(false, Ordering::Relaxed);
+    }
+
+    fn is_stale(&self, entry: &Entry) -> bool {
+        if self.catching_up.load(Ordering::Relaxed) {
+            return true;
+        }
+        let stale_before = *self
+            .stale_before
+            .read()
+            .expect("stale marker lock poisoned");
+        stale_before.is_some_and(|stale_before| entry.written_at <= stale_before)
+    }
+
+    /// Reads `key`, dropping it instead if it is stale.
+    async fn lookup(&self, cache: &Cache<String, Entry>, key: &str) -> Option<Entry> {
+        let entry = cache.get(key).await?;
+        if !self.is_stale(&entry) {
+            return Some(entry);
+        }
+        cache
+            .entry_by_ref(key)
+            .and_compute_with(|current| {
+                let op = match current {
+                    Some(current) if self.is_stale(current.value()) => Op::Remove,
+                    _ => Op::Nop,
+                };
+                std::future::ready(op)
+            })
+            .await;
+        None
+    }
+
+    /// Drops `key` if it is stale. Not a read, so it does not refresh sliding
+    /// TTLs.
+    async fn drop_stale(&self, cache: &Cache<String, Entry>, key: &str) {
+        let marked = self
+            .stale_before
+            .read()
+            .expect("stale marker lock poisoned")
+            .is_some();
+        if !marked && !self.catching_up.load(Ordering::Relaxed) {
+            return;
+        }
+        cache
+            .entry_by_ref(key)
+            .and_compute_with(|current| {
+                let op = match current {
+                    Some(current) if self.is_stale(current.value()) => Op::Remove,
+                    _ => Op::Nop,
+                };
+                std::future::ready(op)
+            })
+            .await;
+    }
+
+    /// A snapshot of the cached keys, in no particular order.
+    pub fn keys(&self) -> Vec<String> {
+        let store = self.store.read().expect("cache store lock poisoned");
+        let Some(draining) = &store.draining else {
+            return store.cache.iter().map(|(key, _)| (*key).clone()).collect();
+        };
+        // Keys still draining stay in the old cache until the resize is done,
+        // so reading it first misses none that move in between.
+        let mut keys = draining
+            .iter()
+            .map(|(key, _)| (*key).clone())
+            .collect::<HashSet<_>>();
+        keys.extend(store.cache.iter().map(|(key, _)| (*key).clone()));
+        keys.into_iter().collect()
+    }
+
+    /// Replaces the value of a cached key, keeping its TTL and expiration
+    /// mode, and makes it fresh. Returns `false` if the key is not cached.
+    pub async fn refresh(&self, key: &str, value: Bytes) -> bool {
+        self.refresh_if_older(key, value, Instant::now()).await
+    }
+
+    /// Like `refresh`, but keeps an entry written after `before`, the moment
+    /// `value` was read elsewhere, since that write is newer than `value`.
+    pub async fn refresh_if_older(&self, key: &str, value: Bytes, before: Instant) -> bool {
+        let mut refreshed = false;
+        let _writing = self.writes.read().await;
+        self.cache_for(key)
+            .await
+            .entry_by_ref(key)
+            .and_compute_with(|current| {
+                let op = match current {
+                    Some(current) if current.value().written_at <= before => {
+                        refreshed = true;
+                        Op::Put(Entry {
+                            value: value.clone(),
+                            written_at: Instant::now(),
+                            ..current.into_value()
+                        })
+                    }
+                    _ => Op::Nop,
+                };
+                std::future::ready(op)
+            })
+            .await;
+        if refreshed {
+            self.notify(|| CacheEvent::Set {
+                key: key.to_string(),
+                value,
+            });
+        }
+        refreshed
+    }
+
+    /// Remaining TTL of `key` as `CacheResponce::Ttl`, or `Miss` if it is not
+    /// cached. Looking up a sliding entry counts as a read, so it reports the
+    /// full idle timeout.
+    pub async fn ttl(&self, key: &str) -> CacheResponce {
+        match self.lookup(&self.cache(), key).await {
+            Some(entry) => {
+                CacheResponce::Ttl(entry.time_to_live().map(|ttl| ttl.as_millis() as u64))
+            }
+            None => CacheResponce::Miss,
+        }
+    }
+
+    /// Gives `key` a new TTL counted from now, keeping its expiration mode.
+    /// Returns `false` if the key is not cached.
+    pub async fn expire(&self, key: &str, ttl_secs: u64) -> bool {
+        self.renew(key, |_| Some(Duration::from_secs(ttl_secs)))
+            .await
+            .is_some()
+    }
+
+    /// Removes the TTL of `key` so it only leaves the cache through eviction or
+    /// deletion. Returns `false` if the key is not cached.
+    pub async fn persist(&self, key: &str) -> bool {
+        self.renew(key, |_| None).await.is_some()
+    }
+
+    /// Restarts the TTL of `key` from now with its current duration. Returns
+    /// that TTL (`Some(None)` for entries without one), or `None` if the key is
+    /// not cached.
+    pub async fn touch(&self, key: &str) -> Option<Option<Duration>> {
+        self.renew(key, |entry| entry.ttl).await
+    }
+
+    async fn renew<F>(&self, key: &str, ttl: F) -> Option<Option<Duration>>
+    where
+        F: FnOnce(&Entry) -> Option<Duration>,
+    {
+        let mut renewed = None;
+        let _writing = self.writes.read().await;
+        self.cache_for(key)
+            .await
+            .entry_by_ref(key)
+            .and_compute_with(|current| {
+                let op = match current {
+                    // A stale entry is as good as gone; renewing it would
+                    // keep it past the resync that marked it.
+                    Some(current) if self.is_stale(current.value()) => Op::Remove,
+                    Some(current) => {
+                        let entry = current.into_value();
+                        let ttl = ttl(&entry);
+                        renewed = Some(ttl);
+                        Op::Put(entry.renewed(ttl))
+                    }
+                    None => Op::Nop,
+                };
+                std::future::ready(op)
+            })
+            .await;
+        renewed
+    }
+}
+
+/// Copies `key` from `old` to `new`, unless `new` already has an entry for it
+/// or `old` lost it meanwhile.
+async fn move_entry(old: &Cache<String, Entry>, new: &Cache<String, Entry>, key: &str) {
+    let Some(entry) = old.get(key).await else {
+        return;
+    };
+    new.entry_by_ref(key)
+        .and_compute_with(|current| {
+            let op = match current {
+                None if old.contains_key(key) => Op::Put(entry),
+                _ => Op::Nop,
+            };
+            std::future::ready(op)
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CacheEvent, CacheResponce, CacheStats, Capacity, ExpirationMode, L1Cache, OP_GET,
+        RES_HELLO, RES_HIT, RES_INFO, RES_TTL, RedisInfo, ServerInfo, SetCondition,
+        TAG_TRACE_CONTEXT, TraceParent, decode_response, encode_get, key_hash, parse_byte_size,
+        tag_frame, trace_frame, untag_frame, untrace_frame,
+    };
+    use bytes::Bytes;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::{Duration, sleep};
+
+    #[test]
+    fn key_hash_is_fnv1a() {
+        assert_eq!(key_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(key_hash("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn tag_frame_roundtrip() {
+        let frame = encode_get("alpha");
+        let tagged = tag_frame(42, &frame);
+
+        let (request_id, body) = untag_frame(&tagged).expect("untag");
+        assert_eq!(request_id, Some(42));
+        assert_eq!(body, frame.as_ref());
+    }
+
+    #[test]
+    fn untag_frame_passes_through_untagged() {
+        let frame = encode_get("alpha");
+        let (request_id, body) = untag_frame(&frame).expect("untag");
+        assert_eq!(request_id, None);
+        assert_eq!(body[0], OP_GET);
+    }
+
+    #[test]
+    fn trace_parent_parses_and_formats() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let trace_parent: TraceParent = header.parse().expect("traceparent");
+        assert_eq!(trace_parent.trace_id[0], 0x4b);
+        assert_eq!(trace_parent.parent_id[7], 0xb7);
+        assert!(trace_parent.sampled());
+        assert_eq!(trace_parent.to_string(), header);
+
+        for bad in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e47zz-00f067aa0ba902b7-01",
+        ] {
+            assert!(bad.parse::<TraceParent>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn trace_frame_roundtrip_inside_request_id() {
+        let trace_parent: TraceParent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+            .parse()
+            .expect("traceparent");
+        let frame = encode_get("alpha");
+        let tagged = tag_frame(7, &trace_frame(&trace_parent, &frame));
+
+        let (request_id, body) = untag_frame(&tagged).expect("untag");
+        assert_eq!(request_id, Some(7));
+        let (traced, body) = untrace_frame(body).expect("untrace");
+        assert_eq!(traced, Some(trace_parent));
+        assert_eq!(body, frame.as_ref());
+
+        let (traced, body) = untrace_frame(&frame).expect("untrace");
+        assert_eq!(traced, None);
+        assert_eq!(body, frame.as_ref());
+
+        assert!(untrace_frame(&[TAG_TRACE_CONTEXT, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn decode_response_truncated_errors() {
+        let err = decode_response(&[]).expect_err("empty response should error");
+        assert_eq!(err, "Truncated frame");
+
+        let err = decode_response(&[RES_HIT, 10, 0, 0, 0, b'a']).expect_err("short hit");
+        assert_eq!(err, "Truncated frame");
+
+        let err = decode_response(&[RES_HELLO, 2]).expect_err("short hello");
+        assert_eq!(err, "Truncated frame");
+    }
+
+    #[test]
+    fn decode_response_hello() {
+        let response = decode_response(&[RES_HELLO, 2, 0, 3, 0, 0, 0]).expect("decode hello");
+        assert!(matches!(
+            response,
+            CacheResponce::Hello {
+                version: 2,
+                capabilities: 3
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn cache_get_miss() {
+        let cache = L1Cache::new(10);
+        assert!(matches!(cache.get("missing").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_set_get_hit() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("alpha".to_string(), Bytes::from_static(b"hello"), None)
+            .await;
+        match cache.get("alpha").await {
+            CacheResponce::Hit(bytes) => assert_eq!(bytes, Bytes::from_static(b"hello")),
+            other => panic!("Expected Hit, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_get_many_mixed() {
+        let cache = L1Cache::new(10);
+        cache
+            .set_many(
+                vec![
+                    ("k1".to_string(), Bytes::from_static(b"v1")),
+                    ("k2".to_string(), Bytes::from_static(b"v2")),
+                ],
+                None,
+            )
+            .await;
+        let keys = vec!["k1".to_string(), "nope".to_string(), "k2".to_string()];
+        match cache.get_many(&keys).await {
+            CacheResponce::Values(values) => assert_eq!(
+                values,
+                vec![
+                    Some(Bytes::from_static(b"v1")),
+                    None,
+                    Some(Bytes::from_static(b"v2"))
+                ]
+            ),
+            other => panic!("Expected Values, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_get_or_fetch_coalesces_misses() {
+        let cache = L1Cache::new(10);
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(50)).await;
+            Some((Bytes::from_static(b"remote"), None))
+        };
+
+        let (a, b, c) = tokio::join!(
+            cache.get_or_fetch("delta", fetch()),
+            cache.get_or_fetch("delta", fetch()),
+            cache.get_or_fetch("delta", fetch()),
+        );
+
+        for res in [a, b, c] {
+            assert!(matches!(res, CacheResponce::Hit(v) if v == b"remote"[..]));
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(matches!(cache.get("delta").await, CacheResponce::Hit(_)));
+    }
+
+    #[tokio::test]
+    async fn cache_get_or_fetch_none_stays_miss() {
+        let cache = L1Cache::new(10);
+        let res = cache.get_or_fetch("epsilon", async { None }).await;
+        assert!(matches!(res, CacheResponce::Miss));
+        assert!(matches!(cache.get("epsilon").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_get_or_fetch_applies_ttl() {
+        let cache = L1Cache::new(10);
+        let res = cache
+            .get_or_fetch("zeta", async {
+                Some((Bytes::from_static(b"v"), Some(Duration::from_millis(100))))
+            })
+            .await;
+        assert!(matches!(res, CacheResponce::Hit(_)));
+        sleep(Duration::from_millis(200)).await;
+        assert!(matches!(cache.get("zeta").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_get_or_lease_single_filler() {
+        let cache = L1Cache::new(10);
+        let lease = Duration::from_secs(5);
+
+        let first = cache.get_or_lease("eta", lease).await;
+        assert!(matches!(first, CacheResponce::Lease));
+
+        let waiter = {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.get_or_lease("eta", lease).await })
+        };
+        sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        cache
+            .set("eta".to_string(), Bytes::from_static(b"filled"), None)
+            .await;
+        let res = waiter.await.unwrap();
+        assert!(matches!(res, CacheResponce::Hit(v) if v == b"filled"[..]));
+    }
+
+    #[tokio::test]
+    async fn cache_get_or_lease_expired_lease_is_miss() {
+        let cache = L1Cache::new(10);
+        let lease = Duration::from_millis(100);
+
+        assert!(matches!(
+            cache.get_or_lease("theta", lease).await,
+            CacheResponce::Lease
+        ));
+        assert!(matches!(
+            cache.get_or_lease("theta", lease).await,
+            CacheResponce::Miss
+        ));
+        assert!(matches!(
+            cache.get_or_lease("theta", lease).await,
+            CacheResponce::Lease
+        ));
+    }
+
+    #[tokio::test]
+    async fn cache_released_lease_is_miss_for_waiters() {
+        let cache = L1Cache::new(10);
+        let lease = Duration::from_secs(5);
+
+        assert!(matches!(
+            cache.get_or_lease("iota", lease).await,
+            CacheResponce::Lease
+        ));
+        let waiter = {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.get_or_lease("iota", lease).await })
+        };
+        sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        assert!(cache.release_lease("iota"));
+        let res = tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiter still blocked on a released lease")
+            .unwrap();
+        assert!(matches!(res, CacheResponce::Miss));
+        assert!(!cache.release_lease("iota"));
+        assert!(matches!(
+            cache.get_or_lease("iota", lease).await,
+            CacheResponce::Lease
+        ));
+    }
+
+    #[tokio::test]
+    async fn cache_get_or_load_runs_loader_once() {
+        let cache = L1Cache::new(10);
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(50)).await;
+            Ok::<_, ()>(Bytes::from_static(b"loaded"))
+        };
+
+        let (a, b) = tokio::join!(
+            cache.get_or_load("iota", None, load()),
+            cache.get_or_load("iota", None, load()),
+        );
+
+        assert_eq!(a.unwrap(), &b"loaded"[..]);
+        assert_eq!(b.unwrap(), &b"loaded"[..]);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_invalidate() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("gamma".to_string(), Bytes::from_static(b"value"), None)
+            .await;
+        cache.invalidate("gamma").await;
+        assert!(matches!(cache.get("gamma").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_ttl_expiry() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("beta".to_string(), Bytes::from_static(b"value"), Some(1))
+            .await;
+        sleep(Duration::from_millis(1100)).await;
+        assert!(matches!(cache.get("beta").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_ttl_overwrite_without_ttl_persists() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("beta".to_string(), Bytes::from_static(b"value"), Some(1))
+            .await;
+        cache
+            .set("beta".to_string(), Bytes::from_static(b"forever"), None)
+            .await;
+        sleep(Duration::from_millis(1100)).await;
+        assert!(matches!(cache.get("beta").await, CacheResponce::Hit(v) if v == b"forever"[..]));
+    }
+
+    #[tokio::test]
+    async fn cache_ttl_overwrite_with_shorter_ttl() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("beta".to_string(), Bytes::from_static(b"value"), Some(30))
+            .await;
+        cache
+            .set("beta".to_string(), Bytes::from_static(b"short"), Some(1))
+            .await;
+        sleep(Duration::from_millis(1100)).await;
+        assert!(matches!(cache.get("beta").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_sliding_ttl_extends_on_read() {
+        let cache = L1Cache::new(10);
+        cache
+            .set_with_mode(
+                "kappa".to_string(),
+                Bytes::from_static(b"value"),
+                Some(1),
+                ExpirationMode::Sliding,
+            )
+            .await;
+        for _ in 0..3 {
+            sleep(Duration::from_millis(600)).await;
+            assert!(matches!(cache.get("kappa").await, CacheResponce::Hit(_)));
+        }
+        sleep(Duration::from_millis(1100)).await;
+        assert!(matches!(cache.get("kappa").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_ttl_reports_remaining() {
+        let cache = L1Cache::new(10);
+        assert!(matches!(cache.ttl("lambda").await, CacheResponce::Miss));
+
+        cache
+            .set("lambda".to_string(), Bytes::from_static(b"value"), None)
+            .await;
+        assert!(matches!(
+            cache.ttl("lambda").await,
+            CacheResponce::Ttl(None)
+        ));
+
+        cache
+            .set("lambda".to_string(), Bytes::from_static(b"value"), Some(10))
+            .await;
+        match cache.ttl("lambda").await {
+            CacheResponce::Ttl(Some(ms)) => assert!(ms > 9_000 && ms <= 10_000),
+            other => panic!("Expected Ttl, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_expire_and_persist() {
+        let cache = L1Cache::new(10);
+        assert!(!cache.expire("mu", 1).await);
+        assert!(!cache.persist("mu").await);
+
+        cache
+            .set("mu".to_string(), Bytes::from_static(b"value"), None)
+            .await;
+        assert!(cache.expire("mu", 1).await);
+        assert!(cache.persist("mu").await);
+        sleep(Duration::from_millis(1200)).await;
+        assert!(matches!(cache.get("mu").await, CacheResponce::Hit(_)));
+
+        assert!(cache.expire("mu", 1).await);
+        sleep(Duration::from_millis(1200)).await;
+        assert!(matches!(cache.get("mu").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_touch_restarts_ttl() {
+        let cache = L1Cache::new(10);
+        assert_eq!(cache.touch("nu").await, None);
+
+        cache
+            .set("nu".to_string(), Bytes::from_static(b"value"), Some(1))
+            .await;
+        sleep(Duration::from_millis(600)).await;
+        assert_eq!(cache.touch("nu").await, Some(Some(Duration::from_secs(1))));
+        sleep(Duration::from_millis(600)).await;
+        assert!(matches!(cache.get("nu").await, CacheResponce::Hit(_)));
+        sleep(Duration::from_millis(600)).await;
+        assert!(matches!(cache.get("nu").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_set_if_checks_presence() {
+        let cache = L1Cache::new(10);
+        let key = "xi".to_string();
+
+        assert!(
+            !cache
+                .set_if(
+                    key.clone(),
+                    Bytes::from_static(b"1"),
+                    None,
+                    SetCondition::IfPresent
+                )
+                .await
+        );
+        assert!(!cache.contains("xi").await);
+        assert!(
+            cache
+                .set_if(
+                    key.clone(),
+                    Bytes::from_static(b"1"),
+                    None,
+                    SetCondition::IfAbsent
+                )
+                .await
+        );
+        assert!(
+            !cache
+                .set_if(
+                    key.clone(),
+                    Bytes::from_static(b"2"),
+                    None,
+                    SetCondition::IfAbsent
+                )
+                .await
+        );
+        assert!(
+            cache
+                .set_if(
+                    key.clone(),
+                    Bytes::from_static(b"3"),
+                    Some(30),
+                    SetCondition::IfPresent
+                )
+                .await
+        );
+        assert!(cache.contains("xi").await);
+        assert!(matches!(cache.get("xi").await, CacheResponce::Hit(v) if v == b"3"[..]));
+        assert!(matches!(cache.ttl("xi").await, CacheResponce::Ttl(Some(_))));
+    }
+
+    #[tokio::test]
+    async fn cache_stats_count_lookups_and_evictions() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("rho".to_string(), Bytes::from_static(b"v1"), Some(1))
+            .await;
+        let _ = cache.get("rho").await;
+        let _ = cache.get("sigma").await;
+        let _ = cache
+            .get_many(&["rho".to_string(), "tau".to_string()])
+            .await;
+        let _ = cache
+            .get_or_fetch("tau", async { Some((Bytes::from_static(b"v2"), None)) })
+            .await;
+
+        sleep(Duration::from_millis(1100)).await;
+        let _ = cache.get("rho").await;
+        cache.cache().run_pending_tasks().await;
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.expired, 1);
+        assert_eq!(stats.evicted, 0);
+        assert_eq!(stats.entry_count, 1);
+    }
+
+    #[tokio::test]
+    async fn cache_resize_keeps_entries() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("omicron".to_string(), Bytes::from_static(b"v1"), Some(30))
+            .await;
+        cache
+            .set("pi".to_string(), Bytes::from_static(b"v2"), None)
+            .await;
+
+        cache.resize(Capacity::Bytes(1024)).await;
+        assert_eq!(cache.capacity(), Capacity::Bytes(1024));
+        assert!(matches!(cache.get("omicron").await, CacheResponce::Hit(v) if v == b"v1"[..]));
+        assert!(matches!(cache.ttl("omicron").await, CacheResponce::Ttl(Some(ms)) if ms <= 30_000));
+        assert!(matches!(cache.get("pi").await, CacheResponce::Hit(v) if v == b"v2"[..]));
+
+        cache.invalidate("pi").await;
+        cache.resize(Capacity::Entries(10)).await;
+        assert!(matches!(cache.get("pi").await, CacheResponce::Miss));
+        assert!(matches!(cache.get("omicron").await, CacheResponce::Hit(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cache_writes_during_resize_are_kept() {
+        let cache = L1Cache::new(10_000);
+        for i in 0..2_000 {
+            cache.set(format!("k{}", i), "old".into(), None).await;
+        }
+
+        let resize = {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.resize(Capacity::Entries(5_000)).await })
+        };
+        for i in 0..2_000 {
+            let key = format!("k{}", i);
+            if i % 2 == 0 {
+                cache.set(key, "new".into(), None).await;
+            } else {
+                assert!(cache.refresh(&key, "refreshed".into()).await, "{}", key);
+            }
+            if i % 100 == 0 {
+                assert_eq!(cache.keys().len(), 2_000);
+            }
+        }
+        resize.await.unwrap();
+
+        assert_eq!(cache.keys().len(), 2_000);
+        for i in 0..2_000 {
+            let expected = if i % 2 == 0 { "new" } else { "refreshed" };
+            assert!(
+                matches!(cache.get(&format!("k{}", i)).await, CacheResponce::Hit(v) if v == expected),
+                "k{}",
+                i
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_refresh_keeps_writes_made_after_the_fetch() {
+        let cache = L1Cache::new(10);
+        cache.set("alpha".to_string(), "v1".into(), None).await;
+        cache.set("beta".to_string(), "v2".into(), None).await;
+        cache.set("gamma".to_string(), "v3".into(), None).await;
+
+        // Redis is read here; then a client writes before the refresh lands.
+        let fetched = std::time::Instant::now();
+        sleep(Duration::from_millis(2)).await;
+        cache.set("alpha".to_string(), "v4".into(), None).await;
+        cache.set("beta".to_string(), "v5".into(), None).await;
+
+        assert!(
+            !cache
+                .refresh_if_older("alpha", "v1-redis".into(), fetched)
+                .await
+        );
+        assert!(!cache.invalidate_if_older("beta", fetched).await);
+        assert!(
+            cache
+                .refresh_if_older("gamma", "v3-redis".into(), fetched)
+                .await
+        );
+        assert!(matches!(cache.get("alpha").await, CacheResponce::Hit(v) if v == "v4"));
+        assert!(matches!(cache.get("beta").await, CacheResponce::Hit(v) if v == "v5"));
+        assert!(matches!(cache.get("gamma").await, CacheResponce::Hit(v) if v == "v3-redis"));
+
+        assert!(
+            cache
+                .invalidate_if_older("gamma", std::time::Instant::now())
+                .await
+        );
+        assert!(matches!(cache.get("gamma").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn cache_invalidate_all_drops_every_entry() {
+        let cache = L1Cache::new(10);
+        cache.set("alpha".to_string(), "v1".into(), None).await;
+        cache.set("beta".to_string(), "v2".into(), Some(60)).await;
+
+        cache.invalidate_all();
+        assert!(matches!(cache.get("alpha").await, CacheResponce::Miss));
+        assert!(matches!(cache.get("beta").await, CacheResponce::Miss));
+
+        cache.set("alpha".to_string(), "v3".into(), None).await;
+        assert!(matches!(cache.get("alpha").await, CacheResponce::Hit(v) if v == "v3"));
+    }
+
+    #[tokio::test]
+    async fn cache_stale_entries_are_revalidated() {
+        let cache = L1Cache::new(10);
+        cache.set("alpha".to_string(), "v1".into(), None).await;
+        cache.set("beta".to_string(), "v2".into(), None).await;
+        cache.set("gamma".to_string(), "v3".into(), Some(60)).await;
+
+        cache.mark_all_stale();
+        cache.set("delta".to_string(), "v4".into(), None).await;
+        assert!(matches!(cache.get("delta").await, CacheResponce::Hit(v) if v == "v4"));
+
+        assert!(matches!(cache.get("alpha").await, CacheResponce::Miss));
+        assert!(!cache.contains("alpha").await);
+
+        let fetch = async { Some((Bytes::from("fetched"), None)) };
+        let response = cache.get_or_fetch("beta", fetch).await;
+        assert!(matches!(response, CacheResponce::Hit(v) if v == "fetched"));
+
+        assert!(cache.refresh("gamma", "v5".into()).await);
+        assert!(!cache.refresh("alpha", "v6".into()).await);
+        assert!(matches!(cache.get("gamma").await, CacheResponce::Hit(v) if v == "v5"));
+        assert!(matches!(cache.ttl("gamma").await, CacheResponce::Ttl(Some(ms)) if ms > 59_000));
+
+        let mut keys = cache.keys();
+        keys.sort();
+        assert_eq!(keys, ["beta", "delta", "gamma"]);
+    }
+
+    #[tokio::test]
+    async fn cache_catch_up_serves_nothing_until_finished() {
+        let cache = L1Cache::new(10);
+        cache.set("alpha".to_string(), "v1".into(), None).await;
+        cache.set("beta".to_string(), "v2".into(), None).await;
+
+        cache.start_catch_up();
+        cache.set("gamma".to_string(), "v3".into(), None).await;
+        assert!(matches!(cache.get("alpha").await, CacheResponce::Miss));
+        assert!(matches!(cache.get("gamma").await, CacheResponce::Miss));
+        let fetch = async { Some((Bytes::from("fetched"), None)) };
+        let response = cache.get_or_fetch("beta", fetch).await;
+        assert!(matches!(response, CacheResponce::Hit(v) if v == "fetched"));
+
+        cache.finish_catch_up();
+        assert!(matches!(cache.get("alpha").await, CacheResponce::Miss));
+        assert!(matches!(cache.get("beta").await, CacheResponce::Hit(v) if v == "fetched"));
+        cache.set("alpha".to_string(), "v4".into(), None).await;
+        assert!(matches!(cache.get("alpha").await, CacheResponce::Hit(v) if v == "v4"));
+    }
+
+    #[tokio::test]
+    async fn cache_stale_entries_count_as_absent() {
+        let cache = L1Cache::new(10);
+        for key in ["alpha", "beta", "gamma", "delta"] {
+            cache.set(key.to_string(), "old".into(), Some(60)).await;
+        }
+        cache.mark_all_stale();
+
+        assert!(!cache.contains("alpha").await);
+        assert!(
+            !cache
+                .set_if(
+                    "beta".to_string(),
+                    "new".into(),
+                    None,
+                    SetCondition::IfPresent
+                )
+                .await
+        );
+        assert!(
+            cache
+                .set_if(
+                    "gamma".to_string(),
+                    "new".into(),
+                    None,
+                    SetCondition::IfAbsent
+                )
+                .await
+        );
+        assert!(matches!(cache.get("gamma").await, CacheResponce::Hit(v) if v == "new"));
+        assert!(!cache.expire("delta", 600).await);
+        assert!(!cache.persist("delta").await);
+        assert_eq!(cache.touch("delta").await, None);
+        assert!(matches!(cache.ttl("delta").await, CacheResponce::Miss));
+    }
+
+    #[test]
+    fn decode_response_ttl() {
+        let response = decode_response(&[RES_TTL, 0]).expect("decode ttl");
+        assert!(matches!(response, CacheResponce::Ttl(None)));
+
+        let response =
+            decode_response(&[RES_TTL, 1, 232, 3, 0, 0, 0, 0, 0, 0]).expect("decode ttl");
+        assert!(matches!(response, CacheResponce::Ttl(Some(1000))));
+    }
+
+    #[test]
+    fn decode_response_info() {
+        let info = ServerInfo {
+            version: "0.1.0".to_string(),
+            uptime_secs: 42,
+            cache: CacheStats {
+                hits: 3,
+                entry_count: 1,
+                ..CacheStats::default()
+            },
+            connections: BTreeMap::from([("frame".to_string(), 2)]),
+            redis: RedisInfo::default(),
+            config: serde_json::json!({ "cache": { "max_capacity": 10 } }),
+        };
+        let document = serde_json::to_vec(&info).unwrap();
+        let mut frame = vec![RES_INFO];
+        frame.extend_from_slice(&(document.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&document);
+
+        let response = decode_response(&frame).expect("decode info");
+        assert!(matches!(response, CacheResponce::Info(decoded) if *decoded == info));
+
+        let err = decode_response(&frame[..frame.len() - 1]).expect_err("short document");
+        assert_eq!(err, "Truncated frame");
+    }
+
+    #[test]
+    fn parse_byte_size_units() {
+        assert_eq!(parse_byte_size("1048576"), Ok(1 << 20));
+        assert_eq!(parse_byte_size("512MiB"), Ok(512 << 20));
+        assert_eq!(parse_byte_size("2 gb"), Ok(2_000_000_000));
+        assert_eq!(parse_byte_size("64k"), Ok(64 << 10));
+        assert!(parse_byte_size("MiB").is_err());
+        assert!(parse_byte_size("12 parsecs").is_err());
+        assert!(parse_byte_size("99999999999TiB").is_err());
+    }
+
+    #[tokio::test]
+    async fn cache_max_bytes_evicts_by_size() {
+        let cache = L1Cache::with_max_bytes(1_000);
+        for i in 0..10 {
+            cache
+                .set(format!("key_{i}"), Bytes::from(vec![0; 195]), None)
+                .await;
+        }
+
+        cache.cache().run_pending_tasks().await;
+        assert!(
+            cache.weighted_size() <= 1_000,
+            "weighted_size={}",
+            cache.weighted_size()
+        );
+        assert!(cache.entry_count() < 10);
+    }
+
+    #[tokio::test]
+    async fn cache_watch_reports_writes() {
+        let cache = L1Cache::new(10);
+        let mut events = cache.watch();
+
+        cache
+            .set("xi".to_string(), Bytes::from_static(b"v"), None)
+            .await;
+        cache.invalidate("xi").await;
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            CacheEvent::Set { key, value } if key == "xi" && value == b"v"[..]
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            CacheEvent::Delete { key } if key == "xi"
+        ));
+    }
+
+    #[tokio::test]
+    async fn cache_watch_reports_loader_fills() {
+        let cache = L1Cache::new(10);
+        let mut events = cache.watch();
+
+        let loaded = cache
+            .get_or_load("omicron", None, async {
+                Ok::<_, ()>(Bytes::from_static(b"loaded"))
+            })
+            .await;
+        assert_eq!(loaded.unwrap(), b"loaded"[..]);
+        let fetched = cache
+            .get_or_fetch("pi", async { Some((Bytes::from_static(b"fetched"), None)) })
+            .await;
+        assert!(matches!(fetched, CacheResponce::Hit(_)));
+        // Hits load nothing, so they report nothing either.
+        let _ = cache.get_or_load("omicron", None, async { Err(()) }).await;
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            CacheEvent::Set { key, value } if key == "omicron" && value == b"loaded"[..]
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            CacheEvent::Set { key, value } if key == "pi" && value == b"fetched"[..]
+        ));
+        assert!(events.try_recv().is_err());
     }
 
     #[tokio::test]
@@ -164,20 +2234,36 @@ mod tests {
         let ttl_secs = Some(30);
         let cache = L1Cache::new(3);
         cache
-            .set("key_1".to_string(), b"value 1".to_vec(), ttl_secs)
+            .set(
+                "key_1".to_string(),
+                Bytes::from_static(b"value 1"),
+                ttl_secs,
+            )
             .await;
         cache
-            .set("key_2".to_string(), b"value 2".to_vec(), ttl_secs)
+            .set(
+                "key_2".to_string(),
+                Bytes::from_static(b"value 2"),
+                ttl_secs,
+            )
             .await;
         cache
-            .set("key_3".to_string(), b"value 3".to_vec(), ttl_secs)
+            .set(
+                "key_3".to_string(),
+                Bytes::from_static(b"value 3"),
+                ttl_secs,
+            )
             .await;
         cache
-            .set("key_4".to_string(), b"value 4".to_vec(), ttl_secs)
+            .set(
+                "key_4".to_string(),
+                Bytes::from_static(b"value 4"),
+                ttl_secs,
+            )
             .await;
 
-        cache.inner.run_pending_tasks().await;
-        let count = cache.inner.entry_count();
+        cache.cache().run_pending_tasks().await;
+        let count = cache.cache().entry_count();
         assert!(
             count == 3,
             "capacity=3 should not retain all keys: count={}",
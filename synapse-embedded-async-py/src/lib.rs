@@ -1,7 +1,12 @@
-use pyo3::{exceptions::PyRuntimeError, prelude::*};
-use pyo3_async_runtimes::tokio::future_into_py;
-use std::{sync::Arc};
-use synapse_core::{CacheResponce, L1Cache};
+use bytes::Bytes;
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyDict,
+};
+use pyo3_async_runtimes::tokio::{future_into_py, into_future};
+use std::sync::Arc;
+use synapse_core::{CacheResponce, ExpirationMode, L1Cache, parse_byte_size};
 
 #[pyclass]
 struct SynapseEmbedded {
@@ -10,13 +15,42 @@ struct SynapseEmbedded {
 
 #[pymethods]
 impl SynapseEmbedded {
+    /// Bounded by `max_capacity` entries, or by `max_memory` bytes of keys and
+    /// values (e.g. `"512MiB"`) when that is given instead.
     #[new]
-    fn new(max_capacity: u64) -> PyResult<Self> {
+    #[pyo3(signature = (max_capacity=None, max_memory=None))]
+    fn new(max_capacity: Option<u64>, max_memory: Option<String>) -> PyResult<Self> {
+        let cache = match (max_capacity, max_memory) {
+            (Some(max_capacity), None) => L1Cache::new(max_capacity),
+            (None, Some(max_memory)) => parse_byte_size(&max_memory)
+                .map(L1Cache::with_max_bytes)
+                .map_err(PyValueError::new_err)?,
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Pass exactly one of max_capacity or max_memory",
+                ));
+            }
+        };
         Ok(Self {
-            cache: Arc::new(L1Cache::new(max_capacity)),
+            cache: Arc::new(cache),
         })
     }
 
+    /// Bytes held when bounded by `max_memory`, entries otherwise.
+    fn weighted_size(&self) -> u64 {
+        self.cache.weighted_size()
+    }
+
+    /// Hit, miss, eviction and expiration counts since the cache was created,
+    /// plus its current size, as a dict.
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        for (name, value) in self.cache.stats().fields() {
+            dict.set_item(name, value)?;
+        }
+        Ok(dict)
+    }
+
     fn get<'py>(&self, py: Python<'py>, key: String) -> PyResult<Bound<'py, PyAny>> {
         let cache = self.cache.clone();
         future_into_py(py, async move {
@@ -24,18 +58,124 @@ impl SynapseEmbedded {
                 CacheResponce::Hit(value) => Ok(Some(value)),
                 CacheResponce::Miss => Ok(None),
                 CacheResponce::Error(err) => Err(PyRuntimeError::new_err(err.to_string())),
-                CacheResponce::Ok => Ok(None),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// With `sliding=True` the TTL is an idle timeout refreshed by every read.
+    #[pyo3(signature = (key, value, ttl_secs, sliding=false))]
+    fn set<'py>(
+        &self,
+        py: Python<'py>,
+        key: String,
+        value: Bytes,
+        ttl_secs: Option<u64>,
+        sliding: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let cache = self.cache.clone();
+        let mode = if sliding {
+            ExpirationMode::Sliding
+        } else {
+            ExpirationMode::Absolute
+        };
+        future_into_py(py, async move {
+            cache.set_with_mode(key, value, ttl_secs, mode).await;
+            Ok(true)
+        })
+    }
+
+    /// Awaits the cached value or awaits `loader()` to produce and cache it.
+    /// Concurrent callers for the same key share a single `loader()` call.
+    #[pyo3(signature = (key, loader, ttl_secs=None))]
+    fn get_or_load<'py>(
+        &self,
+        py: Python<'py>,
+        key: String,
+        loader: Py<PyAny>,
+        ttl_secs: Option<u64>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let cache = self.cache.clone();
+        future_into_py(py, async move {
+            let load = async {
+                let awaitable = Python::attach(|py| into_future(loader.call0(py)?.into_bound(py)))?;
+                let value = awaitable.await?;
+                Python::attach(|py| Ok::<_, PyErr>(value.extract::<Bytes>(py)?))
+            };
+            cache
+                .get_or_load(&key, ttl_secs, load)
+                .await
+                .map_err(|err| Python::attach(|py| err.clone_ref(py)))
+        })
+    }
+
+    fn get_many<'py>(&self, py: Python<'py>, keys: Vec<String>) -> PyResult<Bound<'py, PyAny>> {
+        let cache = self.cache.clone();
+        future_into_py(py, async move {
+            match cache.get_many(&keys).await {
+                CacheResponce::Values(values) => Ok(values),
+                CacheResponce::Error(err) => Err(PyRuntimeError::new_err(err.to_string())),
+                _ => Ok(vec![None; keys.len()]),
             }
         })
     }
 
-    fn set<'py>(&self, py: Python<'py>, key: String, value: Vec<u8>, ttl_secs: Option<u64>) -> PyResult<Bound<'py, PyAny>> {
+    fn set_many<'py>(
+        &self,
+        py: Python<'py>,
+        entries: Vec<(String, Bytes)>,
+        ttl_secs: Option<u64>,
+    ) -> PyResult<Bound<'py, PyAny>> {
         let cache = self.cache.clone();
         future_into_py(py, async move {
-            cache.set(key, value, ttl_secs).await;
+            cache.set_many(entries, ttl_secs).await;
             Ok(true)
         })
     }
+
+    fn delete<'py>(&self, py: Python<'py>, key: String) -> PyResult<Bound<'py, PyAny>> {
+        let cache = self.cache.clone();
+        future_into_py(py, async move {
+            cache.invalidate(&key).await;
+            Ok(true)
+        })
+    }
+
+    /// Remaining TTL of `key` in milliseconds, following Redis `PTTL`: -1 if the
+    /// key never expires and -2 if it is not cached.
+    fn ttl<'py>(&self, py: Python<'py>, key: String) -> PyResult<Bound<'py, PyAny>> {
+        let cache = self.cache.clone();
+        future_into_py(py, async move {
+            match cache.ttl(&key).await {
+                CacheResponce::Ttl(Some(ms)) => Ok(ms as i64),
+                CacheResponce::Ttl(None) => Ok(-1),
+                _ => Ok(-2),
+            }
+        })
+    }
+
+    /// Sets a new TTL on an existing key. Resolves to `False` if it is not cached.
+    fn expire<'py>(
+        &self,
+        py: Python<'py>,
+        key: String,
+        ttl_secs: u64,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let cache = self.cache.clone();
+        future_into_py(py, async move { Ok(cache.expire(&key, ttl_secs).await) })
+    }
+
+    /// Removes the TTL of an existing key. Resolves to `False` if it is not cached.
+    fn persist<'py>(&self, py: Python<'py>, key: String) -> PyResult<Bound<'py, PyAny>> {
+        let cache = self.cache.clone();
+        future_into_py(py, async move { Ok(cache.persist(&key).await) })
+    }
+
+    /// Restarts the TTL of an existing key. Resolves to `False` if it is not cached.
+    fn touch<'py>(&self, py: Python<'py>, key: String) -> PyResult<Bound<'py, PyAny>> {
+        let cache = self.cache.clone();
+        future_into_py(py, async move { Ok(cache.touch(&key).await.is_some()) })
+    }
 }
 
 #[pymodule]
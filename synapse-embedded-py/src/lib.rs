@@ -1,5 +1,10 @@
-use pyo3::{exceptions::PyRuntimeError, prelude::*};
-use synapse_core::{CacheResponce, L1Cache};
+use bytes::Bytes;
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyDict,
+};
+use synapse_core::{CacheResponce, ExpirationMode, L1Cache, parse_byte_size};
 use tokio::runtime::{Builder, Runtime};
 
 #[pyclass]
@@ -10,35 +15,149 @@ struct SynapseEmbedded {
 
 #[pymethods]
 impl SynapseEmbedded {
+    /// Bounded by `max_capacity` entries, or by `max_memory` bytes of keys and
+    /// values (e.g. `"512MiB"`) when that is given instead.
     #[new]
-    fn new(max_capacity: u64) -> PyResult<Self> {
+    #[pyo3(signature = (max_capacity=None, max_memory=None))]
+    fn new(max_capacity: Option<u64>, max_memory: Option<String>) -> PyResult<Self> {
         let runtime = Builder::new_current_thread()
             .enable_time()
             .build()
             .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
 
-        let cache = L1Cache::new(max_capacity);
+        let cache = match (max_capacity, max_memory) {
+            (Some(max_capacity), None) => L1Cache::new(max_capacity),
+            (None, Some(max_memory)) => parse_byte_size(&max_memory)
+                .map(L1Cache::with_max_bytes)
+                .map_err(PyValueError::new_err)?,
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Pass exactly one of max_capacity or max_memory",
+                ));
+            }
+        };
 
         Ok(Self { cache, runtime })
     }
 
-    fn get(&self, key: String) -> PyResult<Option<Vec<u8>>> {
+    /// Bytes held when bounded by `max_memory`, entries otherwise.
+    fn weighted_size(&self) -> u64 {
+        self.cache.weighted_size()
+    }
+
+    /// Hit, miss, eviction and expiration counts since the cache was created,
+    /// plus its current size, as a dict.
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        for (name, value) in self.cache.stats().fields() {
+            dict.set_item(name, value)?;
+        }
+        Ok(dict)
+    }
+
+    fn get(&self, key: String) -> PyResult<Option<Bytes>> {
         self.runtime.block_on(async {
             match self.cache.get(&key).await {
                 CacheResponce::Hit(value) => Ok(Some(value)),
                 CacheResponce::Miss => Ok(None),
                 CacheResponce::Error(err) => Err(PyRuntimeError::new_err(err.to_string())),
-                CacheResponce::Ok => Ok(None),
+                _ => Ok(None),
             }
         })
     }
 
-    fn set(&self, key: String, value: Vec<u8>, ttl_secs: Option<u64>) -> PyResult<bool> {
+    /// With `sliding=True` the TTL is an idle timeout refreshed by every read.
+    #[pyo3(signature = (key, value, ttl_secs, sliding=false))]
+    fn set(
+        &self,
+        key: String,
+        value: Bytes,
+        ttl_secs: Option<u64>,
+        sliding: bool,
+    ) -> PyResult<bool> {
+        let mode = if sliding {
+            ExpirationMode::Sliding
+        } else {
+            ExpirationMode::Absolute
+        };
         self.runtime.block_on(async {
-            self.cache.set(key, value, ttl_secs).await;
+            self.cache.set_with_mode(key, value, ttl_secs, mode).await;
             Ok(true)
         })
     }
+
+    /// Returns the cached value or calls `loader()` to produce and cache it.
+    /// Concurrent callers for the same key share a single `loader()` call.
+    #[pyo3(signature = (key, loader, ttl_secs=None))]
+    fn get_or_load(
+        &self,
+        py: Python<'_>,
+        key: String,
+        loader: Py<PyAny>,
+        ttl_secs: Option<u64>,
+    ) -> PyResult<Bytes> {
+        py.detach(|| {
+            self.runtime
+                .block_on(self.cache.get_or_load(&key, ttl_secs, async {
+                    Python::attach(|py| Ok::<_, PyErr>(loader.call0(py)?.extract::<Bytes>(py)?))
+                }))
+        })
+        .map_err(|err| err.clone_ref(py))
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> PyResult<Vec<Option<Bytes>>> {
+        self.runtime.block_on(async {
+            match self.cache.get_many(&keys).await {
+                CacheResponce::Values(values) => Ok(values),
+                CacheResponce::Error(err) => Err(PyRuntimeError::new_err(err.to_string())),
+                _ => Ok(vec![None; keys.len()]),
+            }
+        })
+    }
+
+    fn set_many(&self, entries: Vec<(String, Bytes)>, ttl_secs: Option<u64>) -> PyResult<bool> {
+        self.runtime.block_on(async {
+            self.cache.set_many(entries, ttl_secs).await;
+            Ok(true)
+        })
+    }
+
+    fn delete(&self, key: String) -> PyResult<bool> {
+        self.runtime.block_on(async {
+            self.cache.invalidate(&key).await;
+            Ok(true)
+        })
+    }
+
+    /// Remaining TTL of `key` in milliseconds, following Redis `PTTL`: -1 if the
+    /// key never expires and -2 if it is not cached.
+    fn ttl(&self, key: String) -> PyResult<i64> {
+        self.runtime.block_on(async {
+            match self.cache.ttl(&key).await {
+                CacheResponce::Ttl(Some(ms)) => Ok(ms as i64),
+                CacheResponce::Ttl(None) => Ok(-1),
+                _ => Ok(-2),
+            }
+        })
+    }
+
+    /// Sets a new TTL on an existing key. Returns `False` if it is not cached.
+    fn expire(&self, key: String, ttl_secs: u64) -> PyResult<bool> {
+        self.runtime
+            .block_on(async { Ok(self.cache.expire(&key, ttl_secs).await) })
+    }
+
+    /// Removes the TTL of an existing key. Returns `False` if it is not cached.
+    fn persist(&self, key: String) -> PyResult<bool> {
+        self.runtime
+            .block_on(async { Ok(self.cache.persist(&key).await) })
+    }
+
+    /// Restarts the TTL of an existing key. Returns `False` if it is not cached.
+    fn touch(&self, key: String) -> PyResult<bool> {
+        self.runtime
+            .block_on(async { Ok(self.cache.touch(&key).await.is_some()) })
+    }
 }
 
 #[pymodule]
@@ -1,7 +1,15 @@
+use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
 use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
-use synapse_core::{CacheResponce, MAX_FRAME_LENGTH, decode_response, encode_get, encode_set};
+use pyo3::types::PyDict;
+use synapse_core::{
+    CAP_BATCH, CAP_LEASES, CAP_TRACE_CONTEXT, CAP_TTL, CacheResponce, DEFAULT_LEASE_MS,
+    ExpirationMode, MAX_FRAME_LENGTH, PROTOCOL_VERSION, TraceParent, decode_response, encode_del,
+    encode_expire, encode_get, encode_get_or_lease, encode_hello, encode_info, encode_mget,
+    encode_mset, encode_persist, encode_release_lease, encode_set_with_mode, encode_touch,
+    encode_ttl, trace_frame,
+};
 use tokio::{
     net::UnixStream,
     runtime::{Builder, Runtime},
@@ -9,10 +17,20 @@ use tokio::{
 };
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
+const CLIENT_CAPABILITIES: u32 = CAP_BATCH | CAP_LEASES | CAP_TTL | CAP_TRACE_CONTEXT;
+
+/// When the `opentelemetry` package is installed, each request carries the
+/// current span's `traceparent` so the server's spans join the trace.
 #[pyclass]
 struct SynapseClient {
     runtime: Runtime,
     framed: Mutex<Framed<UnixStream, LengthDelimitedCodec>>,
+    /// `opentelemetry.propagate.inject`, if available.
+    inject: Option<Py<PyAny>>,
+    #[pyo3(get)]
+    protocol_version: u16,
+    #[pyo3(get)]
+    capabilities: u32,
 }
 
 #[pymethods]
@@ -24,29 +42,63 @@ impl SynapseClient {
             .build()
             .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
 
-        let framed = rt.block_on(async {
+        let (framed, protocol_version, capabilities) = rt.block_on(async {
             let stream = UnixStream::connect(&socket_path)
                 .await
                 .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
-            Ok::<_, PyErr>(Framed::new(
+            let mut framed = Framed::new(
                 stream,
                 LengthDelimitedCodec::builder()
                     .max_frame_length(MAX_FRAME_LENGTH)
                     .new_codec(),
-            ))
+            );
+
+            framed
+                .send(encode_hello(PROTOCOL_VERSION, CLIENT_CAPABILITIES))
+                .await
+                .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+
+            let Some(Ok(packet)) = framed.next().await else {
+                return Err(PyRuntimeError::new_err("Connection closed"));
+            };
+            match decode_response(&packet).map_err(|e| PyRuntimeError::new_err(e.to_string()))? {
+                CacheResponce::Hello {
+                    version,
+                    capabilities,
+                } => Ok((framed, version, capabilities)),
+                CacheResponce::Rejected { code, message } => Err(PyRuntimeError::new_err(format!(
+                    "Handshake rejected ({}): {}",
+                    code, message
+                ))),
+                CacheResponce::Error(e) => Err(PyRuntimeError::new_err(format!(
+                    "Server does not support the protocol handshake: {}",
+                    e
+                ))),
+                _ => Err(PyRuntimeError::new_err("Unexpected response")),
+            }
         })?;
 
+        let inject = Python::attach(|py| {
+            py.import("opentelemetry.propagate")
+                .and_then(|propagate| propagate.getattr("inject"))
+                .map(Bound::unbind)
+                .ok()
+        });
+
         Ok(SynapseClient {
             runtime: rt,
             framed: Mutex::new(framed),
+            inject,
+            protocol_version,
+            capabilities,
         })
     }
 
-    fn get(&self, key: String) -> PyResult<Option<Vec<u8>>> {
+    fn get(&self, key: String) -> PyResult<Option<Bytes>> {
         self.runtime.block_on(async {
             let mut framed = self.framed.lock().await;
 
-            let bytes = encode_get(key.as_str());
+            let bytes = self.traced(encode_get(key.as_str()));
 
             framed
                 .send(bytes)
@@ -68,26 +120,214 @@ impl SynapseClient {
         })
     }
 
-    fn set(&self, key: String, value: Vec<u8>, ttl_secs: Option<u64>) -> PyResult<bool> {
+    /// With `sliding=True` the TTL is an idle timeout refreshed by every read.
+    #[pyo3(signature = (key, value, ttl_secs, sliding=false))]
+    fn set(
+        &self,
+        py: Python<'_>,
+        key: String,
+        value: Bytes,
+        ttl_secs: Option<u64>,
+        sliding: bool,
+    ) -> PyResult<bool> {
+        let mode = if sliding {
+            ExpirationMode::Sliding
+        } else {
+            ExpirationMode::Absolute
+        };
+        let frame = encode_set_with_mode(key.as_str(), &value, ttl_secs, mode);
+        py.detach(|| self.stored(frame))
+    }
+
+    /// Returns the cached value or calls `loader()` to produce it and stores it.
+    /// Only the caller granted the fill lease runs its loader; concurrent callers
+    /// for the same key wait for that value instead, or miss at once if the
+    /// loader raises.
+    #[pyo3(signature = (key, loader, ttl_secs=None))]
+    fn get_or_load(
+        &self,
+        py: Python<'_>,
+        key: String,
+        loader: Py<PyAny>,
+        ttl_secs: Option<u64>,
+    ) -> PyResult<Bytes> {
+        let frame = if self.capabilities & CAP_LEASES != 0 {
+            encode_get_or_lease(key.as_str(), DEFAULT_LEASE_MS)
+        } else {
+            encode_get(key.as_str())
+        };
+
+        let leased = match py.detach(|| self.request(frame))? {
+            CacheResponce::Hit(val) => return Ok(val),
+            CacheResponce::Lease => true,
+            CacheResponce::Miss => false,
+            CacheResponce::Error(e) => return Err(PyRuntimeError::new_err(e)),
+            _ => return Err(PyRuntimeError::new_err("Unexpected response")),
+        };
+
+        let value: Bytes = match loader.call0(py).and_then(|value| Ok(value.extract(py)?)) {
+            Ok(value) => value,
+            Err(err) => {
+                if leased {
+                    // The loader's exception matters more than a failed release.
+                    let _ = py.detach(|| self.request(encode_release_lease(key.as_str())));
+                }
+                return Err(err);
+            }
+        };
+        self.set(py, key, value.clone(), ttl_secs, false)?;
+        Ok(value)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> PyResult<Vec<Option<Bytes>>> {
         self.runtime.block_on(async {
             let mut framed = self.framed.lock().await;
 
-            let bytes = encode_set(key.as_str(), &value, ttl_secs);
+            let bytes = self.traced(encode_mget(&keys));
 
             framed
                 .send(bytes)
                 .await
                 .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
 
+            if let Some(Ok(packet)) = framed.next().await {
+                match decode_response(&packet)
+                    .map_err(|e| PyRuntimeError::new_err(e.to_string()))?
+                {
+                    CacheResponce::Values(values) => Ok(values),
+                    CacheResponce::Error(e) => Err(PyRuntimeError::new_err(e)),
+                    _ => Err(PyRuntimeError::new_err("Unexpected response")),
+                }
+            } else {
+                Err(PyRuntimeError::new_err("Connection closed"))
+            }
+        })
+    }
+
+    fn set_many(
+        &self,
+        py: Python<'_>,
+        entries: Vec<(String, Bytes)>,
+        ttl_secs: Option<u64>,
+    ) -> PyResult<bool> {
+        py.detach(|| self.stored(encode_mset(&entries, ttl_secs)))
+    }
+
+    fn delete(&self, py: Python<'_>, key: String) -> PyResult<bool> {
+        py.detach(|| self.stored(encode_del(key.as_str())))
+    }
+
+    /// Remaining TTL of `key` in milliseconds, following Redis `PTTL`: -1 if the
+    /// key never expires and -2 if it is not cached.
+    fn ttl(&self, py: Python<'_>, key: String) -> PyResult<i64> {
+        match py.detach(|| self.request(encode_ttl(key.as_str())))? {
+            CacheResponce::Ttl(Some(ms)) => Ok(ms as i64),
+            CacheResponce::Ttl(None) => Ok(-1),
+            CacheResponce::Miss => Ok(-2),
+            CacheResponce::Error(e) => Err(PyRuntimeError::new_err(e)),
+            _ => Err(PyRuntimeError::new_err("Unexpected response")),
+        }
+    }
+
+    /// Sets a new TTL on an existing key. Returns `False` if it is not cached.
+    fn expire(&self, py: Python<'_>, key: String, ttl_secs: u64) -> PyResult<bool> {
+        py.detach(|| self.key_exists(encode_expire(key.as_str(), ttl_secs)))
+    }
+
+    /// Removes the TTL of an existing key. Returns `False` if it is not cached.
+    fn persist(&self, py: Python<'_>, key: String) -> PyResult<bool> {
+        py.detach(|| self.key_exists(encode_persist(key.as_str())))
+    }
+
+    /// Restarts the TTL of an existing key. Returns `False` if it is not cached.
+    fn touch(&self, py: Python<'_>, key: String) -> PyResult<bool> {
+        py.detach(|| self.key_exists(encode_touch(key.as_str())))
+    }
+
+    /// The server's version, uptime, configuration and cache, connection and
+    /// Redis state, as a dict.
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let info = match py.detach(|| self.request(encode_info()))? {
+            CacheResponce::Info(info) => info,
+            CacheResponce::Error(e) => return Err(PyRuntimeError::new_err(e)),
+            _ => return Err(PyRuntimeError::new_err("Unexpected response")),
+        };
+        let document =
+            serde_json::to_string(&info).map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+        py.import("json")?.call_method1("loads", (document,))
+    }
+}
+
+impl SynapseClient {
+    fn trace_parent(&self) -> Option<TraceParent> {
+        if self.capabilities & CAP_TRACE_CONTEXT == 0 {
+            return None;
+        }
+        let inject = self.inject.as_ref()?;
+        Python::attach(|py| {
+            let carrier = PyDict::new(py);
+            inject.call1(py, (&carrier,)).ok()?;
+            let header: String = carrier.get_item("traceparent").ok()??.extract().ok()?;
+            header.parse().ok()
+        })
+    }
+
+    fn traced(&self, frame: Bytes) -> Bytes {
+        match self.trace_parent() {
+            Some(trace_parent) => trace_frame(&trace_parent, &frame),
+            None => frame,
+        }
+    }
+
+    fn key_exists(&self, frame: Bytes) -> PyResult<bool> {
+        match self.request(frame)? {
+            CacheResponce::Ok => Ok(true),
+            CacheResponce::Miss => Ok(false),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sends a write whose only successful reply is `Ok`.
+    fn stored(&self, frame: Bytes) -> PyResult<bool> {
+        match self.request(frame)? {
+            CacheResponce::Ok => Ok(true),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn request(&self, frame: Bytes) -> PyResult<CacheResponce> {
+        let frame = self.traced(frame);
+        self.runtime.block_on(async {
+            let mut framed = self.framed.lock().await;
+
+            framed
+                .send(frame)
+                .await
+                .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+
             match framed.next().await {
-                Some(Ok(_)) => Ok(true),
+                Some(Ok(packet)) => {
+                    decode_response(&packet).map_err(|e| PyRuntimeError::new_err(e.to_string()))
+                }
                 Some(Err(e)) => Err(PyRuntimeError::new_err(e.to_string())),
-                None => Ok(false),
+                None => Err(PyRuntimeError::new_err("Connection closed")),
             }
         })
     }
 }
 
+/// The error for a reply the command does not expect, carrying the server's
+/// message when it failed or refused the command.
+fn unexpected(response: CacheResponce) -> PyErr {
+    match response {
+        CacheResponce::Error(e) => PyRuntimeError::new_err(e),
+        CacheResponce::Rejected { code, message } => {
+            PyRuntimeError::new_err(format!("Rejected ({}): {}", code, message))
+        }
+        _ => PyRuntimeError::new_err("Unexpected response"),
+    }
+}
+
 #[pymodule]
 fn synapse_py(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<SynapseClient>()?;
@@ -1,68 +1,615 @@
 use std::{
+    collections::HashMap,
     error::Error,
+    future::Future,
     io::{self, ErrorKind},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
+use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
-use synapse_core::{CacheResponce, MAX_FRAME_LENGTH, decode_response, encode_get, encode_set};
-use tokio::{net::UnixStream, sync::Mutex};
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use opentelemetry::{Context, trace::TraceContextExt};
+use synapse_core::{
+    CAP_BATCH, CAP_LEASES, CAP_REQUEST_IDS, CAP_TRACE_CONTEXT, CAP_TTL, CacheResponce,
+    DEFAULT_LEASE_MS, MAX_FRAME_LENGTH, PROTOCOL_VERSION, TraceParent, decode_response, encode_del,
+    encode_expire, encode_get, encode_get_or_lease, encode_hello, encode_info, encode_mget,
+    encode_mset, encode_persist, encode_release_lease, encode_set, encode_set_with_mode,
+    encode_touch, encode_ttl, key_hash, tag_frame, trace_frame, untag_frame,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    net::{TcpStream, ToSocketAddrs, UnixStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tracing::{Span, debug, field, instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub use synapse_core::{CacheStats, ExpirationMode, RedisInfo, ServerInfo};
+
+const REQUEST_QUEUE: usize = 1024;
+const CLIENT_CAPABILITIES: u32 =
+    CAP_BATCH | CAP_REQUEST_IDS | CAP_LEASES | CAP_TTL | CAP_TRACE_CONTEXT;
+
+type Pending = Arc<Mutex<PendingRequests>>;
+
+/// Callers waiting for a response, by request id.
+#[derive(Default)]
+struct PendingRequests {
+    waiters: HashMap<u32, oneshot::Sender<Result<CacheResponce, String>>>,
+    /// Set once the response reader has stopped, so nothing would answer a
+    /// new waiter.
+    closed: bool,
+}
 
+/// Forgets a request's waiter if its caller gives up before the response.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    request_id: u32,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .expect("pending requests lock poisoned")
+            .waiters
+            .remove(&self.request_id);
+    }
+}
+
+/// Client for a Synapse server. Requests are tagged with ids so many callers can
+/// share one connection without waiting for each other's responses.
+///
+/// Every request runs in a `debug` span named after its opcode, nested under
+/// the caller's current span, with the key's FNV-1a hash (`key_hash`, the
+/// same value the server logs) and the value size where there is one. The
+/// OpenTelemetry context of that span, taken from `tracing-opentelemetry` or
+/// else the current `opentelemetry` context, is sent along so the server's
+/// spans join the caller's trace.
 pub struct SynapseClient {
-    framed: Mutex<Framed<UnixStream, LengthDelimitedCodec>>,
+    requests: mpsc::Sender<Bytes>,
+    pending: Pending,
+    next_id: AtomicU32,
+    reader: JoinHandle<()>,
+    version: u16,
+    capabilities: u32,
 }
 
 impl SynapseClient {
     pub async fn new(socket_path: String) -> Result<Self, Box<dyn Error>> {
-        let stream = UnixStream::connect(&socket_path).await?;
-        let framed = Framed::new(
-            stream,
+        Self::from_stream(UnixStream::connect(&socket_path).await?).await
+    }
+
+    /// Connects over plain TCP to a server listening on `addr`, e.g.
+    /// `"127.0.0.1:7420"`.
+    pub async fn connect_tcp(addr: impl ToSocketAddrs) -> Result<Self, Box<dyn Error>> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Self::from_stream(stream).await
+    }
+
+    /// Runs the client over an already connected stream, e.g. a TLS session
+    /// wrapping a `TcpStream`.
+    pub async fn from_stream<S>(stream: S) -> Result<Self, Box<dyn Error>>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let codec = || {
             LengthDelimitedCodec::builder()
                 .max_frame_length(MAX_FRAME_LENGTH)
-                .new_codec(),
-        );
+                .new_codec()
+        };
+
+        let mut framed_read = FramedRead::new(read_half, codec());
+        let mut framed_write = FramedWrite::new(write_half, codec());
+        let (version, capabilities) = handshake(&mut framed_read, &mut framed_write).await?;
+
+        let (requests, requests_rx) = mpsc::channel(REQUEST_QUEUE);
+        let pending = Pending::default();
+
+        tokio::spawn(write_requests(framed_write, requests_rx));
+        let reader = tokio::spawn(read_responses(framed_read, pending.clone()));
 
         Ok(Self {
-            framed: Mutex::new(framed),
+            requests,
+            pending,
+            next_id: AtomicU32::new(0),
+            reader,
+            version,
+            capabilities,
+        })
+    }
+
+    /// Protocol version agreed with the server during the handshake.
+    pub fn protocol_version(&self) -> u16 {
+        self.version
+    }
+
+    /// Capability bits (`CAP_*`) agreed with the server during the handshake.
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// The span to parent the server's work under, if there is one and the
+    /// server accepts trace contexts.
+    fn trace_parent(&self) -> Option<TraceParent> {
+        if self.capabilities & CAP_TRACE_CONTEXT == 0 {
+            return None;
+        }
+        let from_span = Span::current().context();
+        let context = if from_span.has_active_span() {
+            from_span
+        } else {
+            Context::current()
+        };
+        let span = context.span();
+        let span_context = span.span_context();
+        span_context.is_valid().then(|| TraceParent {
+            trace_id: span_context.trace_id().to_bytes(),
+            parent_id: span_context.span_id().to_bytes(),
+            flags: span_context.trace_flags().to_u8(),
         })
     }
 
+    async fn call(&self, frame: Bytes) -> Result<CacheResponce, Box<dyn Error>> {
+        let started = Instant::now();
+        let frame = match self.trace_parent() {
+            Some(trace_parent) => trace_frame(&trace_parent, &frame),
+            None => frame,
+        };
+        let request_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().expect("pending requests lock poisoned");
+            if pending.closed {
+                return Err(io::Error::new(ErrorKind::BrokenPipe, "connection closed").into());
+            }
+            pending.waiters.insert(request_id, tx);
+        }
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            request_id,
+        };
+
+        if self
+            .requests
+            .send(tag_frame(request_id, &frame))
+            .await
+            .is_err()
+        {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "connection closed").into());
+        }
+
+        let response = rx.await;
+        let latency_us = started.elapsed().as_micros() as u64;
+        debug!(request_id, latency_us, "response received");
+        match response {
+            Ok(Ok(CacheResponce::Error(err))) => Err(io::Error::other(err).into()),
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(err)) => Err(io::Error::new(ErrorKind::InvalidData, err).into()),
+            Err(_) => Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed").into()),
+        }
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "synapse.get",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key)),
+            value_size = field::Empty
+        )
+    )]
     pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        let mut framed = self.framed.lock().await;
-        let bytes = encode_get(key);
-
-        framed.send(bytes).await?;
-
-        match framed.next().await {
-            Some(Ok(packet)) => {
-                let response = decode_response(&packet)
-                    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
-                match response {
-                    CacheResponce::Hit(value) => Ok(Some(value)),
-                    CacheResponce::Miss => Ok(None),
-                    CacheResponce::Error(err) => Err(io::Error::new(ErrorKind::Other, err).into()),
-                    _ => Err(io::Error::new(ErrorKind::Unsupported, "unexpected response").into()),
-                }
+        match self.call(encode_get(key)).await? {
+            CacheResponce::Hit(value) => {
+                Span::current().record("value_size", value.len());
+                Ok(Some(value.into()))
             }
-            Some(Err(err)) => Err(err.into()),
-            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed").into()),
+            CacheResponce::Miss => Ok(None),
+            _ => Err(io::Error::new(ErrorKind::Unsupported, "unexpected response").into()),
         }
     }
 
+    #[instrument(
+        level = "debug",
+        name = "synapse.set",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key)),
+            value_size = field::Empty
+        )
+    )]
     pub async fn set(
         &self,
         key: &str,
         value: Vec<u8>,
         ttl_secs: Option<u64>,
     ) -> Result<bool, Box<dyn Error>> {
-        let mut framed = self.framed.lock().await;
-        let bytes = encode_set(key, &value, ttl_secs);
-
-        framed.send(bytes).await?;
-        match framed.next().await {
-            Some(Ok(_)) => Ok(true),
-            Some(Err(e)) => Err(io::Error::new(ErrorKind::UnexpectedEof, e.to_string()).into()),
-            None => Ok(false),
+        Span::current().record("value_size", value.len());
+        self.call(encode_set(key, &value, ttl_secs)).await?;
+        Ok(true)
+    }
+
+    /// Like `set`, choosing whether `ttl_secs` is absolute or an idle timeout
+    /// refreshed by every read.
+    #[instrument(
+        level = "debug",
+        name = "synapse.set",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key)),
+            value_size = field::Empty
+        )
+    )]
+    pub async fn set_with_mode(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: Option<u64>,
+        mode: ExpirationMode,
+    ) -> Result<bool, Box<dyn Error>> {
+        Span::current().record("value_size", value.len());
+        self.call(encode_set_with_mode(key, &value, ttl_secs, mode))
+            .await?;
+        Ok(true)
+    }
+
+    /// Returns the cached value or produces it with `loader` and stores it. When
+    /// several callers miss the same key at once, only the one granted the fill
+    /// lease runs its loader; the others wait on the server for that value.
+    /// If the loader fails the lease is released, so the others miss at once
+    /// instead of waiting it out. Callers that run their loader get a child
+    /// `synapse.set` span for the store.
+    #[instrument(
+        level = "debug",
+        name = "synapse.get_or_load",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key)),
+            value_size = field::Empty
+        )
+    )]
+    pub async fn get_or_load<F, Fut>(
+        &self,
+        key: &str,
+        ttl_secs: Option<u64>,
+        loader: F,
+    ) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<u8>, Box<dyn Error>>>,
+    {
+        let frame = if self.capabilities & CAP_LEASES != 0 {
+            encode_get_or_lease(key, DEFAULT_LEASE_MS)
+        } else {
+            encode_get(key)
+        };
+
+        let leased = match self.call(frame).await? {
+            CacheResponce::Hit(value) => {
+                Span::current().record("value_size", value.len());
+                return Ok(value.into());
+            }
+            CacheResponce::Lease => true,
+            CacheResponce::Miss => false,
+            _ => return Err(io::Error::new(ErrorKind::Unsupported, "unexpected response").into()),
+        };
+
+        let value = match loader().await {
+            Ok(value) => value,
+            Err(err) => {
+                if leased && let Err(release_err) = self.call(encode_release_lease(key)).await {
+                    debug!(error = %release_err, "cannot release the fill lease");
+                }
+                return Err(err);
+            }
+        };
+        Span::current().record("value_size", value.len());
+        self.set(key, value.clone(), ttl_secs).await?;
+        Ok(value)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "synapse.get_many",
+        skip_all,
+        fields(
+            value_size = field::Empty
+        )
+    )]
+    pub async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>, Box<dyn Error>> {
+        match self.call(encode_mget(keys)).await? {
+            CacheResponce::Values(values) => {
+                let value_size: usize = values.iter().flatten().map(Bytes::len).sum();
+                Span::current().record("value_size", value_size);
+                Ok(values
+                    .into_iter()
+                    .map(|value| value.map(Vec::from))
+                    .collect())
+            }
+            _ => Err(io::Error::new(ErrorKind::Unsupported, "unexpected response").into()),
+        }
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "synapse.set_many",
+        skip_all,
+        fields(
+            value_size = field::Empty
+        )
+    )]
+    pub async fn set_many(
+        &self,
+        entries: &[(&str, &[u8])],
+        ttl_secs: Option<u64>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let value_size: usize = entries.iter().map(|(_, value)| value.len()).sum();
+        Span::current().record("value_size", value_size);
+        self.call(encode_mset(entries, ttl_secs)).await?;
+        Ok(true)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "synapse.delete",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key))
+        )
+    )]
+    pub async fn delete(&self, key: &str) -> Result<bool, Box<dyn Error>> {
+        self.call(encode_del(key)).await?;
+        Ok(true)
+    }
+
+    /// Remaining TTL of `key`: `None` if it is not cached, `Some(None)` if it
+    /// never expires.
+    #[instrument(
+        level = "debug",
+        name = "synapse.ttl",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key))
+        )
+    )]
+    pub async fn ttl(&self, key: &str) -> Result<Option<Option<Duration>>, Box<dyn Error>> {
+        match self.call(encode_ttl(key)).await? {
+            CacheResponce::Ttl(ttl_ms) => Ok(Some(ttl_ms.map(Duration::from_millis))),
+            CacheResponce::Miss => Ok(None),
+            _ => Err(io::Error::new(ErrorKind::Unsupported, "unexpected response").into()),
+        }
+    }
+
+    /// Sets a new TTL on an existing key. Returns `false` if it is not cached.
+    #[instrument(
+        level = "debug",
+        name = "synapse.expire",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key))
+        )
+    )]
+    pub async fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, Box<dyn Error>> {
+        self.key_exists(encode_expire(key, ttl_secs)).await
+    }
+
+    /// Removes the TTL of an existing key. Returns `false` if it is not cached.
+    #[instrument(
+        level = "debug",
+        name = "synapse.persist",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key))
+        )
+    )]
+    pub async fn persist(&self, key: &str) -> Result<bool, Box<dyn Error>> {
+        self.key_exists(encode_persist(key)).await
+    }
+
+    /// Restarts the TTL of an existing key. Returns `false` if it is not cached.
+    #[instrument(
+        level = "debug",
+        name = "synapse.touch",
+        skip_all,
+        fields(
+            key_hash = %format_args!("{:016x}", key_hash(key))
+        )
+    )]
+    pub async fn touch(&self, key: &str) -> Result<bool, Box<dyn Error>> {
+        self.key_exists(encode_touch(key)).await
+    }
+
+    /// The server's version, uptime, configuration and cache, connection and
+    /// Redis state.
+    #[instrument(level = "debug", name = "synapse.stats", skip_all)]
+    pub async fn stats(&self) -> Result<ServerInfo, Box<dyn Error>> {
+        match self.call(encode_info()).await? {
+            CacheResponce::Info(info) => Ok(*info),
+            _ => Err(io::Error::new(ErrorKind::Unsupported, "unexpected response").into()),
+        }
+    }
+
+    async fn key_exists(&self, frame: Bytes) -> Result<bool, Box<dyn Error>> {
+        match self.call(frame).await? {
+            CacheResponce::Ok => Ok(true),
+            CacheResponce::Miss => Ok(false),
+            _ => Err(io::Error::new(ErrorKind::Unsupported, "unexpected response").into()),
         }
     }
 }
+
+impl Drop for SynapseClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn handshake<S: AsyncRead + AsyncWrite>(
+    framed_read: &mut FramedRead<ReadHalf<S>, LengthDelimitedCodec>,
+    framed_write: &mut FramedWrite<WriteHalf<S>, LengthDelimitedCodec>,
+) -> Result<(u16, u32), Box<dyn Error>> {
+    framed_write
+        .send(encode_hello(PROTOCOL_VERSION, CLIENT_CAPABILITIES))
+        .await?;
+
+    let packet = match framed_read.next().await {
+        Some(Ok(packet)) => packet,
+        Some(Err(err)) => return Err(err.into()),
+        None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed").into()),
+    };
+
+    match decode_response(&packet).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))? {
+        CacheResponce::Hello {
+            version,
+            capabilities,
+        } => {
+            if capabilities & CAP_REQUEST_IDS == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "server does not support request ids",
+                )
+                .into());
+            }
+            Ok((version, capabilities))
+        }
+        CacheResponce::Rejected { code, message } => Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("handshake rejected ({}): {}", code, message),
+        )
+        .into()),
+        CacheResponce::Error(err) => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("server does not support the protocol handshake: {}", err),
+        )
+        .into()),
+        _ => Err(io::Error::new(ErrorKind::Unsupported, "unexpected response").into()),
+    }
+}
+
+async fn write_requests<S: AsyncWrite>(
+    mut framed: FramedWrite<WriteHalf<S>, LengthDelimitedCodec>,
+    mut requests: mpsc::Receiver<Bytes>,
+) {
+    while let Some(frame) = requests.recv().await {
+        if framed.send(frame).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn read_responses<S: AsyncRead>(
+    mut framed: FramedRead<ReadHalf<S>, LengthDelimitedCodec>,
+    pending: Pending,
+) {
+    while let Some(Ok(packet)) = framed.next().await {
+        let Ok((Some(request_id), body)) = untag_frame(&packet) else {
+            continue;
+        };
+        let waiter = pending
+            .lock()
+            .expect("pending requests lock poisoned")
+            .waiters
+            .remove(&request_id);
+        if let Some(waiter) = waiter {
+            let _ = waiter.send(decode_response(body));
+        }
+    }
+
+    // Dropping the senders wakes every waiter with a closed-connection error.
+    let mut pending = pending.lock().expect("pending requests lock poisoned");
+    pending.closed = true;
+    pending.waiters.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SynapseClient;
+    use bytes::{BufMut, BytesMut};
+    use futures::{SinkExt, StreamExt};
+    use std::time::Duration;
+    use synapse_core::{
+        CAP_LEASES, CAP_REQUEST_IDS, OP_GET_OR_LEASE, OP_RELEASE_LEASE, PROTOCOL_VERSION,
+        RES_HELLO, RES_LEASE, RES_OK, tag_frame, untag_frame,
+    };
+    use tokio::io::DuplexStream;
+    use tokio::time::timeout;
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    /// Answers the client's HELLO with `capabilities`.
+    async fn accept_hello(
+        server_io: DuplexStream,
+        capabilities: u32,
+    ) -> Framed<DuplexStream, LengthDelimitedCodec> {
+        let mut framed = Framed::new(server_io, LengthDelimitedCodec::new());
+        framed.next().await.expect("hello").unwrap();
+        let mut hello = BytesMut::new();
+        hello.put_u8(RES_HELLO);
+        hello.put_u16_le(PROTOCOL_VERSION);
+        hello.put_u32_le(capabilities);
+        framed.send(hello.freeze()).await.unwrap();
+        framed
+    }
+
+    #[tokio::test]
+    async fn calls_fail_once_the_server_goes_away() {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut framed = accept_hello(server_io, CAP_REQUEST_IDS).await;
+            // Read both requests, then die without answering them.
+            framed.next().await.expect("request").unwrap();
+            framed.next().await.expect("request").unwrap();
+        });
+        let client = SynapseClient::from_stream(client_io).await.unwrap();
+
+        let abandoned = timeout(Duration::from_millis(20), client.get("beta")).await;
+        assert!(abandoned.is_err());
+        assert!(client.pending.lock().unwrap().waiters.is_empty());
+
+        let in_flight = timeout(Duration::from_secs(5), client.get("alpha"))
+            .await
+            .expect("in-flight call hung");
+        assert!(in_flight.is_err());
+        server.await.unwrap();
+
+        let after_close = timeout(Duration::from_secs(5), client.get("alpha"))
+            .await
+            .expect("call after close hung");
+        assert!(after_close.is_err());
+        assert!(client.pending.lock().unwrap().waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_loader_releases_its_lease() {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut framed = accept_hello(server_io, CAP_REQUEST_IDS | CAP_LEASES).await;
+            let mut ops = Vec::new();
+            for response in [RES_LEASE, RES_OK] {
+                let frame = framed.next().await.expect("request").unwrap();
+                let (request_id, body) = untag_frame(&frame).unwrap();
+                ops.push(body[0]);
+                framed
+                    .send(tag_frame(request_id.unwrap(), &[response]))
+                    .await
+                    .unwrap();
+            }
+            ops
+        });
+        let client = SynapseClient::from_stream(client_io).await.unwrap();
+
+        let res = client
+            .get_or_load("kappa", None, || async { Err("loader failed".into()) })
+            .await;
+        assert_eq!(res.unwrap_err().to_string(), "loader failed");
+        assert_eq!(server.await.unwrap(), [OP_GET_OR_LEASE, OP_RELEASE_LEASE]);
+    }
+}
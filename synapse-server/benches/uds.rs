@@ -1,18 +1,19 @@
-use bytes::{BufMut, BytesMut};
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use bytes::{BufMut, Bytes, BytesMut};
+use criterion::{Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
 use synapse_core::{CacheResponce, OP_GET, OP_SET};
-use synapse_server::server::uds::{decode_command, encode_response};
+use synapse_server::server::uds::{decode_command, encode_response, encode_response_parts};
 
-fn build_get_frame() -> BytesMut {
+fn build_get_frame() -> Bytes {
     let key = "alpha";
     let mut buf = BytesMut::new();
     buf.put_u8(OP_GET);
     buf.put_u32_le(key.len() as u32);
     buf.extend_from_slice(key.as_bytes());
-    buf
+    buf.freeze()
 }
 
-fn build_set_frame(value: &[u8]) -> BytesMut {
+fn build_set_frame(value: &[u8]) -> Bytes {
     let key = "alpha";
     let mut buf = BytesMut::new();
     buf.put_u8(OP_SET);
@@ -21,25 +22,25 @@ fn build_set_frame(value: &[u8]) -> BytesMut {
     buf.put_u64_le(0);
     buf.extend_from_slice(key.as_bytes());
     buf.extend_from_slice(value);
-    buf
+    buf.freeze()
 }
 
 fn uds_benches(c: &mut Criterion) {
     let get_frame = build_get_frame();
     c.bench_function("uds_decode_get", |b| {
         b.iter(|| {
-            let _ = decode_command(black_box(get_frame.as_ref())).unwrap();
+            let _ = decode_command(black_box(get_frame.clone())).unwrap();
         });
     });
 
     let set_frame = build_set_frame(b"payload");
     c.bench_function("uds_decode_set", |b| {
         b.iter(|| {
-            let _ = decode_command(black_box(set_frame.as_ref())).unwrap();
+            let _ = decode_command(black_box(set_frame.clone())).unwrap();
         });
     });
 
-    let hit = CacheResponce::Hit(b"payload".to_vec());
+    let hit = CacheResponce::Hit(Bytes::from_static(b"payload"));
     c.bench_function("uds_encode_hit", |b| {
         b.iter(|| {
             let _ = encode_response(black_box(hit.clone()));
@@ -53,12 +54,20 @@ fn uds_benches(c: &mut Criterion) {
         });
     });
 
-    for size in [1024_usize, 4 * 1024, 16 * 1024, 64 * 1024, 256 * 1024] {
-        let value = vec![0xAB; size];
+    for size in [
+        1024_usize,
+        4 * 1024,
+        16 * 1024,
+        64 * 1024,
+        256 * 1024,
+        1024 * 1024,
+        5 * 1024 * 1024,
+    ] {
+        let value = Bytes::from(vec![0xAB; size]);
         let set_frame = build_set_frame(&value);
         c.bench_function(&format!("uds_decode_set_{}b", size), |b| {
             b.iter(|| {
-                let _ = decode_command(black_box(set_frame.as_ref())).unwrap();
+                let _ = decode_command(black_box(set_frame.clone())).unwrap();
             });
         });
 
@@ -68,6 +77,13 @@ fn uds_benches(c: &mut Criterion) {
                 let _ = encode_response(black_box(hit.clone()));
             });
         });
+
+        // What the server writes for a hit: a small header plus the shared value.
+        c.bench_function(&format!("uds_encode_hit_parts_{}b", size), |b| {
+            b.iter(|| {
+                let _ = encode_response_parts(black_box(hit.clone()));
+            });
+        });
     }
 }
 
@@ -0,0 +1,14 @@
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    println!("cargo:rerun-if-changed=proto/synapse.proto");
+
+    // protox parses the proto in pure Rust, so building doesn't need protoc.
+    let file_descriptors = protox::compile(["proto/synapse.proto"], ["proto"])?;
+    tonic_prost_build::configure()
+        .bytes(".")
+        .build_client(false)
+        .compile_fds(file_descriptors)?;
+
+    Ok(())
+}
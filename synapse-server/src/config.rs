@@ -0,0 +1,1109 @@
+use std::{error::Error, fmt::Display, fs, net::SocketAddr, path::Path, str::FromStr};
+
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use synapse_core::{Capacity, parse_byte_size};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_MAX_CAPACITY: u64 = 10_000;
+const DEFAULT_SOCKET_PATH: &str = "/tmp/synapse.sock";
+const DEFAULT_REDIS_PREFIX: &str = "synapse:cache:";
+const DEFAULT_REDIS_CHANNEL: &str = "synapse:cache_updates";
+const DEFAULT_INLINE_MAX_BYTES: u64 = 4096;
+const DEFAULT_STREAM_MAXLEN: u64 = 100_000;
+const DEFAULT_SERVICE_NAME: &str = "synapse-server";
+
+/// Command line flags. Each one overrides the configuration file and is in
+/// turn overridden by the matching `SYNAPSE_*` environment variable.
+#[derive(Parser, Debug, Default)]
+#[command(
+    name = "synapse-server",
+    version,
+    about = "Local L1 cache sidecar with Redis sync"
+)]
+pub struct Cli {
+    /// TOML configuration file.
+    #[arg(long, short = 'c', env = "SYNAPSE_CONFIG")]
+    pub config: Option<String>,
+    /// Print the effective configuration as TOML and exit.
+    #[arg(long)]
+    pub print_config: bool,
+    /// Print the state of the server on the configured Unix socket as JSON
+    /// and exit.
+    #[arg(long, conflicts_with = "print_config")]
+    pub info: bool,
+
+    /// Maximum number of cached entries.
+    #[arg(long)]
+    pub max_capacity: Option<u64>,
+    /// Bound the cache by key and value bytes instead, e.g. `512MiB`.
+    #[arg(long)]
+    pub max_memory: Option<String>,
+
+    /// Unix socket for the binary protocol.
+    #[arg(long)]
+    pub socket_path: Option<String>,
+    /// Octal permissions for the Unix socket, e.g. `660`.
+    #[arg(long)]
+    pub socket_mode: Option<String>,
+    /// Don't listen on the Unix socket.
+    #[arg(long)]
+    pub no_uds: bool,
+
+    /// Serve the binary protocol on `host:port`.
+    #[arg(long)]
+    pub tcp_addr: Option<String>,
+    /// PEM certificate chain; enables TLS on the TCP listener.
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+    /// PEM private key for `--tls-cert`.
+    #[arg(long)]
+    pub tls_key: Option<String>,
+    /// PEM CA bundle that TCP client certificates must chain to.
+    #[arg(long)]
+    pub tls_client_ca: Option<String>,
+    /// Serve the Redis protocol on `host:port` or `unix:/path`.
+    #[arg(long)]
+    pub resp_addr: Option<String>,
+    /// Serve the memcached protocol on `host:port` or `unix:/path`.
+    #[arg(long)]
+    pub memcache_addr: Option<String>,
+    /// Serve gRPC on `host:port` or `unix:/path`.
+    #[arg(long)]
+    pub grpc_addr: Option<String>,
+    /// Serve Prometheus metrics at `http://host:port/metrics`.
+    #[arg(long)]
+    pub metrics_addr: Option<String>,
+
+    /// Redis URL; Redis sync is off without one.
+    #[arg(long)]
+    pub redis_url: Option<String>,
+    /// Prefix for keys written to Redis.
+    #[arg(long)]
+    pub redis_prefix: Option<String>,
+    /// Pub/sub channel for cache updates.
+    #[arg(long)]
+    pub redis_channel: Option<String>,
+    /// Load `GET` misses from Redis.
+    #[arg(long)]
+    pub read_through: bool,
+    /// How writes reach the other servers' L1. Per-prefix overrides are only
+    /// read from the configuration file.
+    #[arg(long, value_enum)]
+    pub redis_mode: Option<SyncMode>,
+    /// How cache updates are sent to the other servers.
+    #[arg(long, value_enum)]
+    pub redis_transport: Option<SyncTransport>,
+    /// Approximate number of updates a stream keeps for servers catching up.
+    #[arg(long)]
+    pub redis_stream_maxlen: Option<u64>,
+    /// What to do with L1 after reconnecting to Redis, since updates sent
+    /// meanwhile may have been missed.
+    #[arg(long, value_enum)]
+    pub redis_on_reconnect: Option<ReconnectPolicy>,
+    /// Identifies this server in the cache updates it publishes; generated
+    /// at startup when unset.
+    #[arg(long)]
+    pub node_id: Option<String>,
+    /// Values up to this many bytes travel in the pub/sub message itself;
+    /// 0 makes peers always read them from Redis.
+    #[arg(long)]
+    pub redis_inline_max_bytes: Option<u64>,
+
+    /// Timeout for opening a Redis connection, in milliseconds.
+    #[arg(long)]
+    pub redis_connect_timeout_ms: Option<u64>,
+    /// Timeout for a Redis response, in milliseconds.
+    #[arg(long)]
+    pub redis_response_timeout_ms: Option<u64>,
+    /// Longest wait between pub/sub reconnect attempts, in milliseconds.
+    #[arg(long)]
+    pub redis_reconnect_max_ms: Option<u64>,
+
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`, optionally
+    /// followed by per-target directives such as `synapse_server::redis=debug`.
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Log output format.
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// OTLP/gRPC collector for trace export, e.g. `http://127.0.0.1:4317`.
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` reported with exported spans.
+    #[arg(long)]
+    pub otlp_service_name: Option<String>,
+    /// Fraction of requests without a sampled trace context to export.
+    #[arg(long)]
+    pub otlp_sample_ratio: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub max_capacity: u64,
+    /// Takes precedence over `max_capacity` when set.
+    pub max_memory: Option<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_capacity: DEFAULT_MAX_CAPACITY,
+            max_memory: None,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn capacity(&self) -> Result<Capacity, String> {
+        match &self.max_memory {
+            Some(max_memory) => parse_byte_size(max_memory).map(Capacity::Bytes),
+            None => Ok(Capacity::Entries(self.max_capacity)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdsConfig {
+    pub enabled: bool,
+    pub socket_path: String,
+    /// Octal permissions applied after binding, e.g. `"660"`.
+    pub socket_mode: Option<String>,
+}
+
+impl Default for UdsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket_path: DEFAULT_SOCKET_PATH.to_string(),
+            socket_mode: None,
+        }
+    }
+}
+
+impl UdsConfig {
+    pub fn socket_mode(&self) -> Result<Option<u32>, String> {
+        self.socket_mode
+            .as_deref()
+            .map(|mode| {
+                u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|&mode| mode <= 0o777)
+                    .ok_or_else(|| format!("uds.socket_mode: {:?} is not an octal mode", mode))
+            })
+            .transpose()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    pub addr: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_client_ca: Option<String>,
+}
+
+/// A listener that is off unless it has an address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// `host:port` or `unix:/path/to.sock`.
+    pub addr: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    pub url: Option<String>,
+    pub prefix: String,
+    /// The pub/sub channel, or the stream key with `transport = "stream"`.
+    pub channel: String,
+    pub read_through: bool,
+    /// Must be unique among the servers sharing `channel`.
+    pub node_id: Option<String>,
+    /// Largest value, before compression, sent inline with its update.
+    pub inline_max_bytes: u64,
+    pub mode: SyncMode,
+    pub transport: SyncTransport,
+    /// `MAXLEN ~` applied on every `XADD`.
+    pub stream_maxlen: u64,
+    pub on_reconnect: ReconnectPolicy,
+    /// Per-prefix `mode` and `read_through`; the longest matching prefix wins.
+    pub overrides: Vec<SyncOverride>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncTransport {
+    /// `PUBLISH`: updates sent while a server is disconnected are lost to it.
+    #[default]
+    Pubsub,
+    /// `XADD` to a capped stream that servers read from where they left off.
+    Stream,
+}
+
+impl FromStr for SyncTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// Peers store the written value in their L1.
+    #[default]
+    Replicate,
+    /// Peers only drop the key from their L1, so they never hold a stale copy.
+    Invalidate,
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+/// How L1 catches up on the updates missed while the subscriber was
+/// disconnected. A stream that still holds them is replayed instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReconnectPolicy {
+    /// Keep L1 as it is. A stream that lost updates is flushed regardless.
+    #[default]
+    Resume,
+    /// Drop every L1 entry.
+    Flush,
+    /// Keep the entries but treat each as a miss the next time it is read,
+    /// which loads it from Redis again with `read_through`.
+    Stale,
+    /// Read every key held in L1 back from Redis with pipelined `MGET`s.
+    Refetch,
+}
+
+impl FromStr for ReconnectPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncOverride {
+    pub prefix: String,
+    pub mode: SyncMode,
+    /// Falls back to `redis.read_through` when unset.
+    pub read_through: Option<bool>,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            prefix: DEFAULT_REDIS_PREFIX.to_string(),
+            channel: DEFAULT_REDIS_CHANNEL.to_string(),
+            read_through: false,
+            node_id: None,
+            inline_max_bytes: DEFAULT_INLINE_MAX_BYTES,
+            mode: SyncMode::Replicate,
+            transport: SyncTransport::Pubsub,
+            stream_maxlen: DEFAULT_STREAM_MAXLEN,
+            on_reconnect: ReconnectPolicy::Resume,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub redis_connect_ms: u64,
+    pub redis_response_ms: u64,
+    pub redis_reconnect_max_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            redis_connect_ms: 1_000,
+            redis_response_ms: 500,
+            redis_reconnect_max_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per event.
+    #[default]
+    Text,
+    /// Multi-line, human-oriented output.
+    Pretty,
+    /// One JSON object per event, with span fields included.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// A level, optionally followed by `target=level` directives, e.g.
+    /// `"info,synapse_server::redis=debug"`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn filter(&self) -> Result<EnvFilter, String> {
+        // `EnvFilter` reads a bare word as a target to enable, which would
+        // turn a misspelt level into a filter that logs nothing.
+        for directive in self.level.split(',') {
+            if !directive.contains('=') && directive.trim().parse::<LevelFilter>().is_err() {
+                return Err(format!("logging.level: unknown level {:?}", directive));
+            }
+        }
+        EnvFilter::try_new(&self.level).map_err(|err| format!("logging.level: {}", err))
+    }
+}
+
+/// Span export. Requests carrying a trace context follow the caller's
+/// sampling decision; `sample_ratio` applies to the rest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// OTLP/gRPC endpoint; nothing is exported without one.
+    pub endpoint: Option<String>,
+    pub service_name: String,
+    pub sample_ratio: f64,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: DEFAULT_SERVICE_NAME.to_string(),
+            sample_ratio: 0.0,
+        }
+    }
+}
+
+/// Settings for `synapse-server`, merged from defaults, a TOML file, command
+/// line flags and `SYNAPSE_*` environment variables, in increasing precedence.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub cache: CacheConfig,
+    pub uds: UdsConfig,
+    pub tcp: TcpConfig,
+    pub resp: ListenerConfig,
+    pub memcache: ListenerConfig,
+    pub grpc: ListenerConfig,
+    pub metrics: ListenerConfig,
+    pub redis: RedisConfig,
+    pub timeouts: TimeoutConfig,
+    pub logging: LoggingConfig,
+    pub otlp: OtlpConfig,
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str, errors: &mut Vec<String>) -> Option<T>
+where
+    T::Err: Display,
+{
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(err) => {
+            errors.push(format!("{}: {:?} is invalid: {}", name, value, err));
+            None
+        }
+    }
+}
+
+fn parse_env_bool(name: &str, value: &str, errors: &mut Vec<String>) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" => Some(false),
+        _ => {
+            errors.push(format!("{}: {:?} is not a boolean", name, value));
+            None
+        }
+    }
+}
+
+fn check_addr(name: &str, addr: &Option<String>, errors: &mut Vec<String>) {
+    match addr
+        .as_deref()
+        .map(|addr| (addr, addr.strip_prefix("unix:")))
+    {
+        Some((_, Some(""))) => errors.push(format!("{}: unix socket path is empty", name)),
+        Some((addr, None)) if addr.parse::<SocketAddr>().is_err() => errors.push(format!(
+            "{}: {:?} is neither host:port nor unix:/path",
+            name, addr
+        )),
+        _ => {}
+    }
+}
+
+fn check_file(name: &str, path: &Option<String>, errors: &mut Vec<String>) {
+    if let Some(path) = path
+        && !Path::new(path).is_file()
+    {
+        errors.push(format!("{}: {} does not exist", name, path));
+    }
+}
+
+impl Config {
+    /// Reads `--config` (if any), then applies flags and the process
+    /// environment. Call [`Config::validate`] before using the result.
+    pub fn load(cli: &Cli) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let file = match &cli.config {
+            Some(path) => Some(
+                fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path, err))?,
+            ),
+            None => None,
+        };
+        Ok(Self::resolve(cli, file.as_deref(), |name| {
+            std::env::var(name).ok()
+        })?)
+    }
+
+    /// Merges the layers without validating, so `--print-config` can show what
+    /// an invalid setup resolved to. `env` looks up environment variables.
+    pub fn resolve(
+        cli: &Cli,
+        file: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let mut config: Config = match file {
+            Some(file) => {
+                toml::from_str(file).map_err(|err| format!("Bad config file: {}", err))?
+            }
+            None => Config::default(),
+        };
+        config.apply_cli(cli);
+        config
+            .apply_env(env)
+            .map_err(|errors| format!("Bad environment:\n  {}", errors.join("\n  ")))?;
+        Ok(config)
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        let set = |target: &mut Option<String>, value: &Option<String>| {
+            if value.is_some() {
+                target.clone_from(value);
+            }
+        };
+
+        if let Some(max_capacity) = cli.max_capacity {
+            self.cache.max_capacity = max_capacity;
+        }
+        set(&mut self.cache.max_memory, &cli.max_memory);
+        if let Some(socket_path) = &cli.socket_path {
+            self.uds.socket_path.clone_from(socket_path);
+        }
+        set(&mut self.uds.socket_mode, &cli.socket_mode);
+        if cli.no_uds {
+            self.uds.enabled = false;
+        }
+        set(&mut self.tcp.addr, &cli.tcp_addr);
+        set(&mut self.tcp.tls_cert, &cli.tls_cert);
+        set(&mut self.tcp.tls_key, &cli.tls_key);
+        set(&mut self.tcp.tls_client_ca, &cli.tls_client_ca);
+        set(&mut self.resp.addr, &cli.resp_addr);
+        set(&mut self.memcache.addr, &cli.memcache_addr);
+        set(&mut self.grpc.addr, &cli.grpc_addr);
+        set(&mut self.metrics.addr, &cli.metrics_addr);
+        set(&mut self.redis.url, &cli.redis_url);
+        if let Some(prefix) = &cli.redis_prefix {
+            self.redis.prefix.clone_from(prefix);
+        }
+        if let Some(channel) = &cli.redis_channel {
+            self.redis.channel.clone_from(channel);
+        }
+        if cli.read_through {
+            self.redis.read_through = true;
+        }
+        set(&mut self.redis.node_id, &cli.node_id);
+        if let Some(bytes) = cli.redis_inline_max_bytes {
+            self.redis.inline_max_bytes = bytes;
+        }
+        if let Some(mode) = cli.redis_mode {
+            self.redis.mode = mode;
+        }
+        if let Some(transport) = cli.redis_transport {
+            self.redis.transport = transport;
+        }
+        if let Some(maxlen) = cli.redis_stream_maxlen {
+            self.redis.stream_maxlen = maxlen;
+        }
+        if let Some(policy) = cli.redis_on_reconnect {
+            self.redis.on_reconnect = policy;
+        }
+        if let Some(ms) = cli.redis_connect_timeout_ms {
+            self.timeouts.redis_connect_ms = ms;
+        }
+        if let Some(ms) = cli.redis_response_timeout_ms {
+            self.timeouts.redis_response_ms = ms;
+        }
+        if let Some(ms) = cli.redis_reconnect_max_ms {
+            self.timeouts.redis_reconnect_max_ms = ms;
+        }
+        if let Some(level) = &cli.log_level {
+            self.logging.level.clone_from(level);
+        }
+        if let Some(format) = cli.log_format {
+            self.logging.format = format;
+        }
+        set(&mut self.otlp.endpoint, &cli.otlp_endpoint);
+        if let Some(service_name) = &cli.otlp_service_name {
+            self.otlp.service_name.clone_from(service_name);
+        }
+        if let Some(ratio) = cli.otlp_sample_ratio {
+            self.otlp.sample_ratio = ratio;
+        }
+    }
+
+    fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        let string = |name: &str, target: &mut String| {
+            if let Some(value) = env(name) {
+                *target = value;
+            }
+        };
+        string("SYNAPSE_SOCKET_PATH", &mut self.uds.socket_path);
+        string("SYNAPSE_REDIS_PREFIX", &mut self.redis.prefix);
+        string("SYNAPSE_REDIS_CHANNEL", &mut self.redis.channel);
+        string("SYNAPSE_LOG_LEVEL", &mut self.logging.level);
+        string("SYNAPSE_OTLP_SERVICE_NAME", &mut self.otlp.service_name);
+
+        let optional = [
+            ("SYNAPSE_MAX_MEMORY", &mut self.cache.max_memory),
+            ("SYNAPSE_SOCKET_MODE", &mut self.uds.socket_mode),
+            ("SYNAPSE_TCP_ADDR", &mut self.tcp.addr),
+            ("SYNAPSE_TLS_CERT", &mut self.tcp.tls_cert),
+            ("SYNAPSE_TLS_KEY", &mut self.tcp.tls_key),
+            ("SYNAPSE_TLS_CLIENT_CA", &mut self.tcp.tls_client_ca),
+            ("SYNAPSE_RESP_ADDR", &mut self.resp.addr),
+            ("SYNAPSE_MEMCACHE_ADDR", &mut self.memcache.addr),
+            ("SYNAPSE_GRPC_ADDR", &mut self.grpc.addr),
+            ("SYNAPSE_METRICS_ADDR", &mut self.metrics.addr),
+            ("SYNAPSE_REDIS_URL", &mut self.redis.url),
+            ("SYNAPSE_NODE_ID", &mut self.redis.node_id),
+            ("SYNAPSE_OTLP_ENDPOINT", &mut self.otlp.endpoint),
+        ];
+        for (name, target) in optional {
+            if let Some(value) = env(name) {
+                *target = Some(value);
+            }
+        }
+
+        let numbers = [
+            ("SYNAPSE_MAX_CAPACITY", &mut self.cache.max_capacity),
+            (
+                "SYNAPSE_REDIS_INLINE_MAX_BYTES",
+                &mut self.redis.inline_max_bytes,
+            ),
+            ("SYNAPSE_REDIS_STREAM_MAXLEN", &mut self.redis.stream_maxlen),
+            (
+                "SYNAPSE_REDIS_CONNECT_TIMEOUT_MS",
+                &mut self.timeouts.redis_connect_ms,
+            ),
+            (
+                "SYNAPSE_REDIS_RESPONSE_TIMEOUT_MS",
+                &mut self.timeouts.redis_response_ms,
+            ),
+            (
+                "SYNAPSE_REDIS_RECONNECT_MAX_MS",
+                &mut self.timeouts.redis_reconnect_max_ms,
+            ),
+        ];
+        for (name, target) in numbers {
+            if let Some(value) = env(name).and_then(|value| parse_env(name, &value, &mut errors)) {
+                *target = value;
+            }
+        }
+
+        if let Some(format) = env("SYNAPSE_LOG_FORMAT")
+            .and_then(|value| parse_env("SYNAPSE_LOG_FORMAT", &value, &mut errors))
+        {
+            self.logging.format = format;
+        }
+        if let Some(mode) = env("SYNAPSE_REDIS_MODE")
+            .and_then(|value| parse_env("SYNAPSE_REDIS_MODE", &value, &mut errors))
+        {
+            self.redis.mode = mode;
+        }
+        if let Some(transport) = env("SYNAPSE_REDIS_TRANSPORT")
+            .and_then(|value| parse_env("SYNAPSE_REDIS_TRANSPORT", &value, &mut errors))
+        {
+            self.redis.transport = transport;
+        }
+        if let Some(policy) = env("SYNAPSE_REDIS_ON_RECONNECT")
+            .and_then(|value| parse_env("SYNAPSE_REDIS_ON_RECONNECT", &value, &mut errors))
+        {
+            self.redis.on_reconnect = policy;
+        }
+        if let Some(ratio) = env("SYNAPSE_OTLP_SAMPLE_RATIO")
+            .and_then(|value| parse_env("SYNAPSE_OTLP_SAMPLE_RATIO", &value, &mut errors))
+        {
+            self.otlp.sample_ratio = ratio;
+        }
+
+        let flags = [
+            ("SYNAPSE_UDS_ENABLED", &mut self.uds.enabled),
+            ("SYNAPSE_READ_THROUGH", &mut self.redis.read_through),
+        ];
+        for (name, target) in flags {
+            if let Some(value) =
+                env(name).and_then(|value| parse_env_bool(name, &value, &mut errors))
+            {
+                *target = value;
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Reports every problem at once rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if self.cache.max_capacity == 0 {
+            errors.push("cache.max_capacity must be greater than 0".to_string());
+        }
+        if let Some(max_memory) = &self.cache.max_memory {
+            match parse_byte_size(max_memory) {
+                Ok(0) => errors.push("cache.max_memory must be greater than 0".to_string()),
+                Ok(_) => {}
+                Err(err) => errors.push(format!("cache.max_memory: {}", err)),
+            }
+        }
+
+        if self.uds.enabled && self.uds.socket_path.is_empty() {
+            errors.push("uds.socket_path must not be empty".to_string());
+        }
+        if let Err(err) = self.uds.socket_mode() {
+            errors.push(err);
+        }
+
+        if let Some(addr) = &self.tcp.addr
+            && addr.parse::<SocketAddr>().is_err()
+        {
+            errors.push(format!("tcp.addr: {:?} is not host:port", addr));
+        }
+        match (&self.tcp.tls_cert, &self.tcp.tls_key) {
+            (Some(_), None) | (None, Some(_)) => {
+                errors.push("tcp.tls_cert and tcp.tls_key must be set together".to_string())
+            }
+            (None, None) if self.tcp.tls_client_ca.is_some() => {
+                errors.push("tcp.tls_client_ca requires tcp.tls_cert and tcp.tls_key".to_string())
+            }
+            _ => {}
+        }
+        check_file("tcp.tls_cert", &self.tcp.tls_cert, &mut errors);
+        check_file("tcp.tls_key", &self.tcp.tls_key, &mut errors);
+        check_file("tcp.tls_client_ca", &self.tcp.tls_client_ca, &mut errors);
+        check_addr("resp.addr", &self.resp.addr, &mut errors);
+        check_addr("memcache.addr", &self.memcache.addr, &mut errors);
+        check_addr("grpc.addr", &self.grpc.addr, &mut errors);
+        if let Some(addr) = &self.metrics.addr
+            && addr.parse::<SocketAddr>().is_err()
+        {
+            errors.push(format!("metrics.addr: {:?} is not host:port", addr));
+        }
+
+        let listeners = [
+            self.tcp.addr.is_some(),
+            self.resp.addr.is_some(),
+            self.memcache.addr.is_some(),
+            self.grpc.addr.is_some(),
+        ];
+        if !self.uds.enabled && !listeners.contains(&true) {
+            errors.push("no listener is enabled".to_string());
+        }
+
+        if let Some(url) = &self.redis.url
+            && let Err(err) = redis::Client::open(url.as_str())
+        {
+            errors.push(format!("redis.url: {}", err));
+        }
+        if self.redis.channel.is_empty() {
+            errors.push("redis.channel must not be empty".to_string());
+        }
+        if self.redis.node_id.as_deref() == Some("") {
+            errors.push("redis.node_id must not be empty".to_string());
+        }
+        if self.redis.stream_maxlen == 0 {
+            errors.push("redis.stream_maxlen must be at least 1".to_string());
+        }
+        for (i, rule) in self.redis.overrides.iter().enumerate() {
+            if rule.prefix.is_empty() {
+                errors.push(format!("redis.overrides[{}].prefix must not be empty", i));
+            } else if self.redis.overrides[..i]
+                .iter()
+                .any(|other| other.prefix == rule.prefix)
+            {
+                errors.push(format!(
+                    "redis.overrides[{}]: prefix {:?} is listed twice",
+                    i, rule.prefix
+                ));
+            }
+        }
+
+        let timeouts = [
+            ("timeouts.redis_connect_ms", self.timeouts.redis_connect_ms),
+            (
+                "timeouts.redis_response_ms",
+                self.timeouts.redis_response_ms,
+            ),
+            (
+                "timeouts.redis_reconnect_max_ms",
+                self.timeouts.redis_reconnect_max_ms,
+            ),
+        ];
+        for (name, ms) in timeouts {
+            if ms == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+
+        if let Err(err) = self.logging.filter() {
+            errors.push(err);
+        }
+
+        if let Some(endpoint) = &self.otlp.endpoint
+            && !endpoint.starts_with("http://")
+            && !endpoint.starts_with("https://")
+        {
+            errors.push(format!(
+                "otlp.endpoint: {:?} is not an http(s) URL",
+                endpoint
+            ));
+        }
+        if self.otlp.service_name.is_empty() {
+            errors.push("otlp.service_name must not be empty".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.otlp.sample_ratio) {
+            errors.push("otlp.sample_ratio must be between 0 and 1".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+
+    /// Sections of `new` that differ from `self` but only take effect on
+    /// restart. Everything else can be applied to a running server.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut sections = Vec::new();
+        if self.uds != new.uds {
+            sections.push("uds");
+        }
+        if self.tcp != new.tcp {
+            sections.push("tcp");
+        }
+        if self.resp != new.resp {
+            sections.push("resp");
+        }
+        if self.memcache != new.memcache {
+            sections.push("memcache");
+        }
+        if self.grpc != new.grpc {
+            sections.push("grpc");
+        }
+        if self.metrics != new.metrics {
+            sections.push("metrics");
+        }
+        if self.logging.format != new.logging.format {
+            sections.push("logging.format");
+        }
+        if self.otlp != new.otlp {
+            sections.push("otlp");
+        }
+        sections
+    }
+
+    pub fn to_toml(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cli, Config, LogFormat, ReconnectPolicy, SyncMode, SyncOverride, SyncTransport};
+    use clap::Parser;
+    use std::collections::HashMap;
+    use synapse_core::Capacity;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn config_defaults_are_valid() {
+        let config = Config::resolve(&Cli::default(), None, env(&[])).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.cache.max_capacity, 10_000);
+        assert_eq!(config.uds.socket_path, "/tmp/synapse.sock");
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn example_config_matches_defaults() {
+        let example = include_str!("../synapse.example.toml");
+        let config = Config::resolve(&Cli::default(), Some(example), env(&[])).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn config_parses_sync_mode_and_overrides() {
+        let file = r#"
+            [redis]
+            url = "redis://file:6379"
+
+            [[redis.overrides]]
+            prefix = "report:"
+            mode = "invalidate"
+            read_through = true
+
+            [[redis.overrides]]
+            prefix = "report:"
+            mode = "replicate"
+        "#;
+        let config = Config::resolve(
+            &Cli::default(),
+            Some(file),
+            env(&[("SYNAPSE_REDIS_MODE", "Invalidate")]),
+        )
+        .unwrap();
+
+        assert_eq!(config.redis.mode, SyncMode::Invalidate);
+        assert_eq!(
+            config.redis.overrides[0],
+            SyncOverride {
+                prefix: "report:".to_string(),
+                mode: SyncMode::Invalidate,
+                read_through: Some(true),
+            }
+        );
+        assert_eq!(config.redis.overrides[1].read_through, None);
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("redis.overrides[1]"), "{}", err);
+
+        let cli = Cli::parse_from([
+            "synapse-server",
+            "--redis-transport",
+            "stream",
+            "--redis-stream-maxlen",
+            "0",
+            "--redis-on-reconnect",
+            "flush",
+        ]);
+        let config = Config::resolve(
+            &cli,
+            None,
+            env(&[("SYNAPSE_REDIS_ON_RECONNECT", "refetch")]),
+        )
+        .unwrap();
+        assert_eq!(config.redis.transport, SyncTransport::Stream);
+        assert_eq!(config.redis.on_reconnect, ReconnectPolicy::Refetch);
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("redis.stream_maxlen"), "{}", err);
+
+        let cli = Cli::parse_from(["synapse-server", "--redis-mode", "replicate"]);
+        let err =
+            Config::resolve(&cli, Some(file), env(&[("SYNAPSE_REDIS_MODE", "bogus")])).unwrap_err();
+        assert!(err.contains("SYNAPSE_REDIS_MODE"), "{}", err);
+    }
+
+    #[test]
+    fn config_file_then_cli_then_env() {
+        let file = r#"
+            [cache]
+            max_capacity = 100
+
+            [uds]
+            socket_path = "/run/file.sock"
+
+            [redis]
+            url = "redis://file:6379"
+            channel = "file"
+        "#;
+        let cli = Cli::parse_from([
+            "synapse-server",
+            "--socket-path",
+            "/run/cli.sock",
+            "--redis-channel",
+            "cli",
+        ]);
+        let config =
+            Config::resolve(&cli, Some(file), env(&[("SYNAPSE_REDIS_CHANNEL", "env")])).unwrap();
+
+        assert_eq!(config.cache.max_capacity, 100);
+        assert_eq!(config.uds.socket_path, "/run/cli.sock");
+        assert_eq!(config.redis.url.as_deref(), Some("redis://file:6379"));
+        assert_eq!(config.redis.channel, "env");
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn config_rejects_unknown_fields_and_bad_env() {
+        let err = Config::resolve(
+            &Cli::default(),
+            Some("[cache]\nmax_entries = 1\n"),
+            env(&[]),
+        )
+        .unwrap_err();
+        assert!(err.contains("max_entries"), "{}", err);
+
+        let err = Config::resolve(
+            &Cli::default(),
+            None,
+            env(&[
+                ("SYNAPSE_MAX_CAPACITY", "lots"),
+                ("SYNAPSE_READ_THROUGH", "maybe"),
+            ]),
+        )
+        .unwrap_err();
+        assert!(err.contains("SYNAPSE_MAX_CAPACITY"), "{}", err);
+        assert!(err.contains("SYNAPSE_READ_THROUGH"), "{}", err);
+    }
+
+    #[test]
+    fn config_validation_reports_every_error() {
+        let cli = Cli::parse_from([
+            "synapse-server",
+            "--max-memory",
+            "lots",
+            "--socket-mode",
+            "999",
+            "--tls-cert",
+            "/nonexistent/cert.pem",
+            "--resp-addr",
+            "localhost",
+            "--log-level",
+            "loud",
+            "--otlp-endpoint",
+            "127.0.0.1:4317",
+            "--otlp-sample-ratio",
+            "1.5",
+            "--node-id",
+            "",
+        ]);
+        let config = Config::resolve(&cli, None, env(&[])).unwrap();
+        let err = config.validate().unwrap_err();
+
+        for field in [
+            "cache.max_memory",
+            "uds.socket_mode",
+            "tcp.tls_cert and tcp.tls_key",
+            "tcp.tls_cert: /nonexistent/cert.pem",
+            "resp.addr",
+            "logging.level",
+            "otlp.endpoint",
+            "otlp.sample_ratio",
+            "redis.node_id",
+        ] {
+            assert!(err.contains(field), "{} missing from {}", field, err);
+        }
+    }
+
+    #[test]
+    fn config_accepts_log_directives_and_formats() {
+        let cli = Cli::parse_from([
+            "synapse-server",
+            "--log-level",
+            "warn,synapse_server::redis=debug",
+            "--log-format",
+            "json",
+        ]);
+        let config = Config::resolve(&cli, None, env(&[])).unwrap();
+        assert_eq!(config.logging.format, LogFormat::Json);
+        config.validate().unwrap();
+
+        let config = Config::resolve(&cli, None, env(&[("SYNAPSE_LOG_FORMAT", "Pretty")])).unwrap();
+        assert_eq!(config.logging.format, LogFormat::Pretty);
+        assert_eq!(
+            Config::default().restart_required(&config),
+            vec!["logging.format"]
+        );
+    }
+
+    #[test]
+    fn config_reports_listener_changes_as_restart_required() {
+        let current = Config::default();
+        let cli = Cli::parse_from([
+            "synapse-server",
+            "--max-capacity",
+            "50",
+            "--redis-url",
+            "redis://127.0.0.1:6379",
+            "--log-level",
+            "debug",
+            "--resp-addr",
+            "127.0.0.1:6380",
+            "--socket-mode",
+            "600",
+        ]);
+        let new = Config::resolve(&cli, None, env(&[])).unwrap();
+
+        assert_eq!(current.restart_required(&new), vec!["uds", "resp"]);
+        assert_eq!(new.cache.capacity(), Ok(Capacity::Entries(50)));
+    }
+
+    #[test]
+    fn config_roundtrips_through_toml() {
+        let cli = Cli::parse_from([
+            "synapse-server",
+            "--max-memory",
+            "64MiB",
+            "--grpc-addr",
+            "unix:/tmp/synapse-grpc.sock",
+            "--socket-mode",
+            "660",
+        ]);
+        let config = Config::resolve(&cli, None, env(&[])).unwrap();
+        assert_eq!(config.uds.socket_mode(), Ok(Some(0o660)));
+
+        let printed = config.to_toml().unwrap();
+        let reparsed = Config::resolve(&Cli::default(), Some(&printed), env(&[])).unwrap();
+        assert_eq!(reparsed, config);
+    }
+}
@@ -1,2 +1,6 @@
+pub mod config;
+pub mod info;
+pub mod metrics;
 pub mod redis;
 pub mod server;
+pub mod telemetry;
@@ -1,38 +1,272 @@
 use crate::{
-    redis::{client::RedisSync, subscriber::spawn_redis_subscriber},
-    server::{grpc::run_grpc, uds::run_uds},
+    config::{Cli, Config, LogFormat, LoggingConfig},
+    metrics::run_metrics,
+    redis::{
+        client::{RedisSync, SharedRedisSync},
+        subscriber::spawn_redis_subscriber,
+    },
+    server::{grpc::run_grpc, memcache::run_memcache, resp::run_resp, tcp::run_tcp, uds::run_uds},
 };
+
+use clap::Parser;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::{fmt::Display, process};
 use synapse_core::L1Cache;
+use tokio::signal::unix::{SignalKind, signal};
 use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
 
+pub mod config;
+pub mod info;
+pub mod metrics;
 pub mod redis;
 pub mod server;
+pub mod telemetry;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let shutdown = CancellationToken::new();
-    let l1_cache = L1Cache::new(10_000);
-    let redis_sync = RedisSync::from_env()?;
-    if let Some(redis_sync) = redis_sync.clone() {
-        spawn_redis_subscriber(l1_cache.clone(), shutdown.clone(), redis_sync);
+/// Configuration errors span several lines, which `main` returning `Err`
+/// would print quoted and escaped.
+fn exit_with(err: impl Display) -> ! {
+    eprintln!("{}", err);
+    process::exit(2)
+}
+
+/// Installs the global subscriber. The returned handle swaps the log filter
+/// on reload; the output format and span export are fixed for the life of
+/// the process.
+fn init_logging(
+    logging: &LoggingConfig,
+    tracer_provider: Option<&SdkTracerProvider>,
+) -> Result<reload::Handle<EnvFilter, Registry>, Box<dyn std::error::Error + Send + Sync>> {
+    let (filter, handle) = reload::Layer::new(logging.filter()?);
+    let output = match logging.format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(tracer_provider.map(telemetry::layer))
+        .init();
+    Ok(handle)
+}
+
+/// What SIGHUP can change on a running server.
+struct Reloader {
+    cli: Cli,
+    config: Config,
+    l1_cache: L1Cache,
+    redis_sync: SharedRedisSync,
+    subscriber: Option<CancellationToken>,
+    log_filter: reload::Handle<EnvFilter, Registry>,
+    shutdown: CancellationToken,
+}
+
+impl Reloader {
+    fn start_subscriber(&mut self) {
+        if let Some(subscriber) = self.subscriber.take() {
+            subscriber.cancel();
+        }
+        if let Some(redis_sync) = self.redis_sync.load() {
+            let subscriber = self.shutdown.child_token();
+            spawn_redis_subscriber(
+                self.l1_cache.clone(),
+                subscriber.clone(),
+                RedisSync::clone(&redis_sync),
+            );
+            self.subscriber = Some(subscriber);
+        }
     }
 
-    let uds_handle = run_uds(l1_cache.clone(), shutdown.clone(), redis_sync);
-    let grpc_handle = run_grpc(l1_cache.clone(), shutdown.clone());
+    /// Re-reads the configuration and applies it. Listener settings are only
+    /// reported; they keep their current values until a restart.
+    async fn reload(&mut self) {
+        let config = match Config::load(&self.cli) {
+            Ok(config) => config,
+            Err(err) => {
+                error!(error = %err, "Reload failed, keeping the current configuration");
+                return;
+            }
+        };
+        if let Err(err) = config.validate() {
+            error!(error = %err, "Reload failed, keeping the current configuration");
+            return;
+        }
 
-    let servers = async { tokio::try_join!(uds_handle, grpc_handle) };
-    tokio::pin!(servers);
+        if config.logging.level != self.config.logging.level {
+            let reloaded = config
+                .logging
+                .filter()
+                .is_ok_and(|filter| self.log_filter.reload(filter).is_ok());
+            if reloaded {
+                info!(level = %config.logging.level, "Log level changed");
+            } else {
+                error!(level = %config.logging.level, "Cannot change the log level");
+            }
+        }
+
+        if config.cache != self.config.cache
+            && let Ok(capacity) = config.cache.capacity()
+            && capacity != self.l1_cache.capacity()
+        {
+            self.l1_cache.resize(capacity).await;
+            info!(?capacity, "L1 cache resized");
+        }
 
-    tokio::select! {
-        res = &mut servers => {
-            res?;
+        if config.redis != self.config.redis || config.timeouts != self.config.timeouts {
+            match RedisSync::from_config(&config.redis, &config.timeouts) {
+                Ok(redis_sync) => {
+                    let enabled = redis_sync.is_some();
+                    self.redis_sync.store(redis_sync);
+                    self.start_subscriber();
+                    if enabled {
+                        info!(channel = %config.redis.channel, "Redis sync reconfigured");
+                    } else {
+                        info!("Redis sync disabled");
+                    }
+                }
+                Err(err) => error!(error = %err, "Cannot reconnect Redis sync"),
+            }
         }
-        _ = tokio::signal::ctrl_c() => {
-            shutdown.cancel();
+
+        for section in self.config.restart_required(&config) {
+            warn!(
+                section,
+                "Setting changed; restart synapse-server to apply it"
+            );
         }
+
+        self.config = Config {
+            uds: self.config.uds.clone(),
+            tcp: self.config.tcp.clone(),
+            resp: self.config.resp.clone(),
+            memcache: self.config.memcache.clone(),
+            grpc: self.config.grpc.clone(),
+            metrics: self.config.metrics.clone(),
+            ..config
+        };
+        info::publish_config(&self.config);
+        info!("Configuration reloaded");
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Cli::parse();
+    let config = Config::load(&cli).unwrap_or_else(|err| exit_with(err));
+    if cli.info {
+        let info = info::query_info(&config.uds.socket_path)
+            .await
+            .unwrap_or_else(|err| exit_with(err));
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    if cli.print_config {
+        print!("{}", config.to_toml()?);
+    }
+    if let Err(err) = config.validate() {
+        exit_with(err);
+    }
+    if cli.print_config {
+        return Ok(());
+    }
+
+    info::publish_config(&config);
+    let tracer_provider = telemetry::tracer_provider(&config.otlp)?;
+    let log_filter = init_logging(&config.logging, tracer_provider.as_ref())?;
+
+    let shutdown = CancellationToken::new();
+    let l1_cache = L1Cache::with_capacity(config.cache.capacity()?);
+    let redis_sync = SharedRedisSync::new(RedisSync::from_config(&config.redis, &config.timeouts)?);
+
+    let uds_handle = run_uds(
+        config.uds.clone(),
+        l1_cache.clone(),
+        shutdown.clone(),
+        redis_sync.clone(),
+    );
+    let tcp_handle = run_tcp(
+        config.tcp.clone(),
+        l1_cache.clone(),
+        shutdown.clone(),
+        redis_sync.clone(),
+    );
+    let resp_handle = run_resp(
+        config.resp.clone(),
+        l1_cache.clone(),
+        shutdown.clone(),
+        redis_sync.clone(),
+    );
+    let memcache_handle = run_memcache(
+        config.memcache.clone(),
+        l1_cache.clone(),
+        shutdown.clone(),
+        redis_sync.clone(),
+    );
+    let grpc_handle = run_grpc(
+        config.grpc.clone(),
+        l1_cache.clone(),
+        shutdown.clone(),
+        redis_sync.clone(),
+    );
+
+    let metrics_handle = run_metrics(config.metrics.clone(), l1_cache.clone(), shutdown.clone());
+
+    let mut reloader = Reloader {
+        cli,
+        config,
+        l1_cache,
+        redis_sync,
+        subscriber: None,
+        log_filter,
+        shutdown: shutdown.clone(),
     };
+    reloader.start_subscriber();
+
+    // Listeners run on their own task so a slow reload doesn't hold up accepts.
+    let mut servers = tokio::spawn(async {
+        tokio::try_join!(
+            uds_handle,
+            tcp_handle,
+            resp_handle,
+            memcache_handle,
+            grpc_handle,
+            metrics_handle
+        )
+        .map(|_| ())
+    });
 
-    servers.await?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            res = &mut servers => return res?,
+            _ = tokio::signal::ctrl_c() => {
+                shutdown.cancel();
+                break;
+            }
+            _ = hangup.recv() => {
+                info!("SIGHUP received, reloading configuration");
+                // A resize can take a while on a large cache; shutdown
+                // abandons it rather than wait.
+                tokio::select! {
+                    _ = reloader.reload() => {}
+                    res = &mut servers => return res?,
+                    _ = tokio::signal::ctrl_c() => {
+                        shutdown.cancel();
+                        break;
+                    }
+                }
+            }
+        };
+    }
 
-    Ok(())
+    let res = servers.await?;
+    if let Some(tracer_provider) = tracer_provider
+        && let Err(err) = tracer_provider.shutdown()
+    {
+        warn!(error = %err, "Cannot flush exported spans");
+    }
+    res
 }
@@ -0,0 +1,321 @@
+use std::{error::Error, net::SocketAddr, sync::LazyLock, time::Instant};
+
+use axum::{Router, extract::State, http::header::CONTENT_TYPE, routing::get};
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder, exponential_buckets, proto::MetricFamily,
+};
+use synapse_core::L1Cache;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{config::ListenerConfig, redis::subscriber};
+
+/// Process-wide metrics. L1 figures are read from the cache on each scrape
+/// instead of being tracked here.
+pub struct Metrics {
+    registry: Registry,
+    pub sets: IntCounter,
+    pub request_duration: HistogramVec,
+    pub bytes_received: IntCounterVec,
+    pub bytes_sent: IntCounterVec,
+    pub active_connections: IntGaugeVec,
+    pub redis_write_failures: IntCounterVec,
+    pub redis_read_failures: IntCounter,
+    pub pubsub_received: IntCounter,
+    pub pubsub_decoded: IntCounter,
+    pub pubsub_failed: IntCounter,
+    pub pubsub_reconnects: IntCounter,
+    pub pubsub_disconnected: Histogram,
+    pub pubsub_disconnected_for: Gauge,
+    pub pubsub_echoes_suppressed: IntCounter,
+    pub pubsub_inline_values: IntCounter,
+    pub stream_full_invalidations: IntCounter,
+    pub pubsub_subscribed: IntGauge,
+    pub pubsub_last_message: Gauge,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: M) -> M {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| {
+            register(
+                &registry,
+                IntCounter::new(name, help).expect("valid metric"),
+            )
+        };
+        let counter_vec = |name: &str, help: &str, label: &str| {
+            register(
+                &registry,
+                IntCounterVec::new(Opts::new(name, help), &[label]).expect("valid metric"),
+            )
+        };
+
+        Self {
+            sets: counter("synapse_sets_total", "Values written by clients."),
+            request_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "synapse_request_duration_seconds",
+                        "Time to execute a command, by opcode.",
+                    )
+                    // 10µs to ~2.6s.
+                    .buckets(exponential_buckets(0.000_01, 4.0, 10).expect("valid buckets")),
+                    &["op"],
+                )
+                .expect("valid metric"),
+            ),
+            bytes_received: counter_vec(
+                "synapse_bytes_received_total",
+                "Bytes read from clients, including framing.",
+                "protocol",
+            ),
+            bytes_sent: counter_vec(
+                "synapse_bytes_sent_total",
+                "Bytes written to clients, including framing.",
+                "protocol",
+            ),
+            active_connections: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("synapse_active_connections", "Open client connections."),
+                    &["protocol"],
+                )
+                .expect("valid metric"),
+            ),
+            redis_write_failures: counter_vec(
+                "synapse_redis_write_failures_total",
+                "Writes applied to L1 that could not be mirrored to Redis.",
+                "op",
+            ),
+            redis_read_failures: counter(
+                "synapse_redis_read_failures_total",
+                "Read-through fetches from Redis that failed.",
+            ),
+            pubsub_received: counter(
+                "synapse_pubsub_messages_received_total",
+                "Cache update messages received from other servers.",
+            ),
+            pubsub_decoded: counter(
+                "synapse_pubsub_messages_decoded_total",
+                "Cache update messages decoded successfully.",
+            ),
+            pubsub_failed: counter(
+                "synapse_pubsub_messages_failed_total",
+                "Cache update messages that could not be decoded.",
+            ),
+            pubsub_reconnects: counter(
+                "synapse_pubsub_reconnects_total",
+                "Times the update subscriber reconnected after an error.",
+            ),
+            pubsub_disconnected: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "synapse_pubsub_disconnected_seconds",
+                        "How long the update subscriber was disconnected, observed on reconnect.",
+                    )
+                    // 100ms to ~1.8h.
+                    .buckets(exponential_buckets(0.1, 4.0, 9).expect("valid buckets")),
+                )
+                .expect("valid metric"),
+            ),
+            pubsub_disconnected_for: register(
+                &registry,
+                Gauge::new(
+                    "synapse_pubsub_disconnected_for_seconds",
+                    "How long the update subscriber has been disconnected so far; 0 while connected.",
+                )
+                .expect("valid metric"),
+            ),
+            pubsub_echoes_suppressed: counter(
+                "synapse_pubsub_echoes_suppressed_total",
+                "Cache updates ignored because this server published them.",
+            ),
+            pubsub_inline_values: counter(
+                "synapse_pubsub_inline_values_total",
+                "Cache updates applied from the value they carried, without a Redis GET.",
+            ),
+            stream_full_invalidations: counter(
+                "synapse_stream_full_invalidations_total",
+                "Times updates were trimmed from the stream before this server read them.",
+            ),
+            pubsub_subscribed: register(
+                &registry,
+                IntGauge::new(
+                    "synapse_pubsub_subscribed",
+                    "1 while the update subscriber is connected.",
+                )
+                .expect("valid metric"),
+            ),
+            pubsub_last_message: register(
+                &registry,
+                Gauge::new(
+                    "synapse_pubsub_last_message_timestamp_seconds",
+                    "Unix time of the last cache update received.",
+                )
+                .expect("valid metric"),
+            ),
+            registry,
+        }
+    }
+
+    pub fn observe(&self, op: &str, started: Instant) {
+        self.request_duration
+            .with_label_values(&[op])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Counts a connection as active until the guard is dropped.
+    pub fn connection(&self, protocol: &str) -> ConnectionGuard {
+        let gauge = self.active_connections.with_label_values(&[protocol]);
+        gauge.inc();
+        ConnectionGuard(gauge)
+    }
+}
+
+pub struct ConnectionGuard(IntGauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// L1 figures from `CacheStats::fields`. A field without a metric of its own
+/// below is still exported, as a `synapse_l1_<field>` gauge.
+fn cache_families(l1_cache: &L1Cache) -> Result<Vec<MetricFamily>, prometheus::Error> {
+    let registry = Registry::new();
+    let counter = |name: &str, help: &str, value: u64| -> Result<(), prometheus::Error> {
+        let counter = IntCounter::new(name, help)?;
+        counter.inc_by(value);
+        registry.register(Box::new(counter))
+    };
+    let gauge = |name: &str, help: &str, value: u64| -> Result<(), prometheus::Error> {
+        let gauge = IntGauge::new(name, help)?;
+        gauge.set(value as i64);
+        registry.register(Box::new(gauge))
+    };
+    let evictions = IntCounterVec::new(
+        Opts::new(
+            "synapse_l1_evictions_total",
+            "Entries removed from L1, by cause.",
+        ),
+        &["cause"],
+    )?;
+
+    for (field, value) in l1_cache.stats().fields() {
+        match field {
+            "hits" => counter("synapse_l1_hits_total", "Lookups answered from L1.", value)?,
+            "misses" => counter("synapse_l1_misses_total", "Lookups not found in L1.", value)?,
+            "evicted" => evictions.with_label_values(&["size"]).inc_by(value),
+            "expired" => evictions.with_label_values(&["expired"]).inc_by(value),
+            "entry_count" => gauge("synapse_l1_entries", "Entries in L1.", value)?,
+            "weighted_size" => gauge(
+                "synapse_l1_weighted_size",
+                "Weighted size of L1: bytes when bounded by memory, entries otherwise.",
+                value,
+            )?,
+            _ => gauge(
+                &format!("synapse_l1_{}", field),
+                "L1 cache statistic.",
+                value,
+            )?,
+        }
+    }
+    registry.register(Box::new(evictions))?;
+    Ok(registry.gather())
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn render(l1_cache: &L1Cache) -> Result<String, prometheus::Error> {
+    METRICS
+        .pubsub_disconnected_for
+        .set(subscriber::disconnected_for().as_secs_f64());
+    let mut families = METRICS.registry.gather();
+    families.extend(cache_families(l1_cache)?);
+    let mut out = Vec::new();
+    TextEncoder::new().encode(&families, &mut out)?;
+    Ok(String::from_utf8(out).expect("text format is UTF-8"))
+}
+
+async fn metrics(State(l1_cache): State<L1Cache>) -> ([(&'static str, &'static str); 1], String) {
+    let body = render(&l1_cache).unwrap_or_else(|err| format!("# {}\n", err));
+    ([(CONTENT_TYPE.as_str(), "text/plain; version=0.0.4")], body)
+}
+
+/// Serves `GET /metrics` on `metrics.addr`. Without an address the endpoint
+/// stays off.
+pub async fn run_metrics(
+    config: ListenerConfig,
+    l1_cache: L1Cache,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(addr) = config.addr else {
+        shutdown.cancelled().await;
+        return Ok(());
+    };
+    let addr: SocketAddr = addr.parse()?;
+
+    let listener = TcpListener::bind(addr).await?;
+    let router = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(l1_cache);
+
+    info!("Synapse metrics served on http://{}/metrics", addr);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    info!("Metrics server shutdown requested");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{METRICS, render};
+    use bytes::Bytes;
+    use synapse_core::L1Cache;
+
+    #[tokio::test]
+    async fn render_includes_cache_and_process_metrics() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("upsilon".to_string(), Bytes::from_static(b"v1"), None)
+            .await;
+        let _ = cache.get("upsilon").await;
+        let _ = cache.get("phi").await;
+        METRICS.sets.inc();
+        let _connection = METRICS.connection("test");
+
+        let text = render(&cache).unwrap();
+        assert!(text.contains("synapse_l1_hits_total 1"), "{}", text);
+        assert!(text.contains("synapse_l1_misses_total 1"), "{}", text);
+        assert!(
+            text.contains("synapse_l1_evictions_total{cause=\"size\"} 0"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("synapse_active_connections{protocol=\"test\"} 1"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("# TYPE synapse_sets_total counter"),
+            "{}",
+            text
+        );
+    }
+}
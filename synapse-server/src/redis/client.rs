@@ -1,69 +1,527 @@
-use std::{env, error::Error, sync::Arc};
+use std::{
+    error::Error,
+    hash::{BuildHasher, RandomState},
+    process,
+    sync::{
+        Arc, LazyLock, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
 use bincode::{Decode, Encode};
-use redis::{Client, pipe};
+use bytes::Bytes;
+use redis::{
+    AsyncCommands, AsyncConnectionConfig, Client, Pipeline,
+    aio::{ConnectionManager, ConnectionManagerConfig, MultiplexedConnection},
+    pipe,
+    streams::StreamMaxlen,
+};
 use serde::{Deserialize, Serialize};
+use synapse_core::{ExpirationMode, MAX_FRAME_LENGTH};
+use tokio::sync::OnceCell;
+use tracing::{Instrument, debug_span};
 
-const DEFAULT_REDIS_CHANNEL: &str = "synapse:cache_updates";
-const DEFAULT_REDIS_PREFIX: &str = "synapse:cache:";
+use crate::config::{
+    ReconnectPolicy, RedisConfig, SyncMode, SyncOverride, SyncTransport, TimeoutConfig,
+};
+
+/// Node id used when `redis.node_id` is unset. Kept for the life of the
+/// process so it survives reloads.
+static GENERATED_NODE_ID: LazyLock<String> =
+    LazyLock::new(|| format!("{:016x}", RandomState::new().hash_one(process::id())));
+
+/// Stream entry field holding an encoded `CacheUpdate`.
+pub(super) const STREAM_FIELD: &str = "u";
+
+/// Favours speed: updates are published on the write path.
+const ZSTD_LEVEL: i32 = 1;
+
+/// Keys per `MGET` when refetching L1 after a reconnect.
+const REFETCH_BATCH: usize = 256;
+
+/// Sequence number of the next published update. Process-wide, so it keeps
+/// increasing when a reload replaces the `RedisSync`.
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Clone)]
 pub struct RedisSync {
     pub client: Arc<Client>,
+    pub connection_config: AsyncConnectionConfig,
+    /// The connection writes and read-through fetches share, opened on first
+    /// use and reopened by the manager after it fails.
+    shared_connection: Arc<OnceCell<ConnectionManager>>,
+    shared_connection_config: ConnectionManagerConfig,
     pub key_prefix: String,
     pub channel: String,
+    pub read_through: bool,
+    pub mode: SyncMode,
+    pub overrides: Vec<SyncOverride>,
+    pub transport: SyncTransport,
+    pub stream_maxlen: u64,
+    pub on_reconnect: ReconnectPolicy,
+    /// Longest wait between pub/sub reconnect attempts.
+    pub reconnect_max: Duration,
+    /// Stamped on every published update so the subscriber can skip its own.
+    pub node_id: String,
+    /// Largest value sent with its update; 0 never sends values.
+    pub inline_max_bytes: u64,
 }
 
-#[derive(Serialize, Deserialize, Encode, Decode)]
+/// The `RedisSync` in use, swapped out when the configuration is reloaded.
+/// Listeners hold a clone and look up the current one for each request.
+#[derive(Clone, Default)]
+pub struct SharedRedisSync(Arc<RwLock<Option<Arc<RedisSync>>>>);
+
+impl SharedRedisSync {
+    pub fn new(redis_sync: Option<RedisSync>) -> Self {
+        Self(Arc::new(RwLock::new(redis_sync.map(Arc::new))))
+    }
+
+    pub fn load(&self) -> Option<Arc<RedisSync>> {
+        self.0.read().expect("redis sync lock poisoned").clone()
+    }
+
+    pub fn store(&self, redis_sync: Option<RedisSync>) {
+        *self.0.write().expect("redis sync lock poisoned") = redis_sync.map(Arc::new);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Encode, Decode)]
+pub(super) enum UpdateOp {
+    Set,
+    Delete,
+    Expire,
+    Persist,
+    Touch,
+}
+
+/// Follows the `key` and `ttl_secs` header of an update; the fields after it
+/// are this version's layout.
+const UPDATE_VERSION: u8 = 1;
+
+/// Encoded as the `key` and `ttl_secs` that every version starts with, then
+/// `UPDATE_VERSION` and the remaining fields. Servers that predate the
+/// version read just the header as a `Set`, and such a header on its own
+/// decodes as one here.
+#[derive(Serialize, Deserialize)]
 pub(super) struct CacheUpdate {
+    pub op: UpdateOp,
     pub key: String,
     pub ttl_secs: Option<u64>,
+    pub sliding: bool,
+    /// The publishing server's `node_id`.
+    pub node_id: String,
+    /// Increases with every update a node publishes.
+    pub seq: u64,
+    /// The value of a small `Set`, so peers don't read it back from Redis.
+    pub value: Option<InlineValue>,
+}
+
+#[derive(Serialize, Deserialize, Encode, Decode)]
+pub(super) enum InlineValue {
+    Plain(Vec<u8>),
+    Zstd(Vec<u8>),
+}
+
+impl CacheUpdate {
+    pub(super) fn encode(&self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let config = bincode::config::standard();
+        let mut payload = bincode::encode_to_vec((&self.key, self.ttl_secs), config)?;
+        payload.push(UPDATE_VERSION);
+        payload.extend(bincode::encode_to_vec(
+            (&self.op, self.sliding, &self.node_id, self.seq, &self.value),
+            config,
+        )?);
+        Ok(payload)
+    }
+
+    pub(super) fn decode(payload: &[u8]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let config = bincode::config::standard();
+        let ((key, ttl_secs), read): ((String, Option<u64>), _) =
+            bincode::decode_from_slice(payload, config)?;
+        let (op, sliding, node_id, seq, value) = match &payload[read..] {
+            [] => (UpdateOp::Set, false, String::new(), 0, None),
+            [UPDATE_VERSION, body @ ..] => bincode::decode_from_slice(body, config)?.0,
+            [version, ..] => return Err(format!("unknown update version {}", version).into()),
+        };
+        Ok(Self {
+            op,
+            key,
+            ttl_secs,
+            sliding,
+            node_id,
+            seq,
+            value,
+        })
+    }
+}
+
+impl InlineValue {
+    /// Compresses `value` unless that doesn't make it smaller.
+    fn new(value: &[u8]) -> Self {
+        match zstd::bulk::compress(value, ZSTD_LEVEL) {
+            Ok(compressed) if compressed.len() < value.len() => Self::Zstd(compressed),
+            _ => Self::Plain(value.to_vec()),
+        }
+    }
+
+    pub(super) fn into_bytes(self) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+        match self {
+            Self::Plain(value) => Ok(value.into()),
+            Self::Zstd(compressed) => {
+                Ok(zstd::bulk::decompress(&compressed, MAX_FRAME_LENGTH)?.into())
+            }
+        }
+    }
 }
 
 impl RedisSync {
-    pub fn from_env() -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
-        let url = match env::var("SYNAPSE_REDIS_URL") {
-            Ok(url) => url,
-            Err(_) => return Ok(None),
+    /// Builds the sync handle, or `None` when no Redis URL is configured.
+    pub fn from_config(
+        config: &RedisConfig,
+        timeouts: &TimeoutConfig,
+    ) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let Some(url) = &config.url else {
+            return Ok(None);
         };
-        let key_prefix =
-            env::var("SYNAPSE_REDIS_PREFIX").unwrap_or_else(|_| DEFAULT_REDIS_PREFIX.to_string());
-        let channel =
-            env::var("SYNAPSE_REDIS_CHANNEL").unwrap_or_else(|_| DEFAULT_REDIS_CHANNEL.to_string());
-        let client = Client::open(url)?;
+        let client = Client::open(url.as_str())?;
+        let connect_timeout = Some(Duration::from_millis(timeouts.redis_connect_ms));
+        let response_timeout = Some(Duration::from_millis(timeouts.redis_response_ms));
+        let connection_config = AsyncConnectionConfig::new()
+            .set_connection_timeout(connect_timeout)
+            .set_response_timeout(response_timeout);
+        // Requests wait for at most one connection attempt; the next request
+        // tries again.
+        let shared_connection_config = ConnectionManagerConfig::new()
+            .set_connection_timeout(connect_timeout)
+            .set_response_timeout(response_timeout)
+            .set_number_of_retries(0);
 
         Ok(Some(Self {
             client: Arc::new(client),
-            key_prefix,
-            channel,
+            connection_config,
+            shared_connection: Arc::default(),
+            shared_connection_config,
+            key_prefix: config.prefix.clone(),
+            channel: config.channel.clone(),
+            read_through: config.read_through,
+            mode: config.mode,
+            overrides: config.overrides.clone(),
+            transport: config.transport,
+            stream_maxlen: config.stream_maxlen,
+            on_reconnect: config.on_reconnect,
+            reconnect_max: Duration::from_millis(timeouts.redis_reconnect_max_ms),
+            node_id: config
+                .node_id
+                .clone()
+                .unwrap_or_else(|| GENERATED_NODE_ID.clone()),
+            inline_max_bytes: config.inline_max_bytes,
         }))
     }
 
+    /// A connection of its own, for the subscriber's long-running and
+    /// blocking commands.
+    pub(super) async fn dedicated_connection(
+        &self,
+    ) -> Result<MultiplexedConnection, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .client
+            .get_multiplexed_async_connection_with_config(&self.connection_config)
+            .await?)
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, Box<dyn Error + Send + Sync>> {
+        let connection = self
+            .shared_connection
+            .get_or_try_init(|| {
+                self.client
+                    .get_connection_manager_with_config(self.shared_connection_config.clone())
+            })
+            .await?;
+        Ok(connection.clone())
+    }
+
+    /// Redis has no sliding expiry, so a sliding TTL is stored as an absolute one
+    /// there; peers still apply it as sliding to their L1.
     pub async fn set(
         &self,
         key: &str,
         value: &[u8],
         ttl_secs: Option<u64>,
+        mode: ExpirationMode,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let mut conn = self.connection().await?;
         let redis_key = self.prefixed_key(key);
 
         let mut p = pipe();
         match ttl_secs {
-            Some(ttl) => p.atomic().set_ex(&redis_key, value, ttl),
-            None => p.atomic().set(&redis_key, value),
+            Some(ttl) => p.atomic().set_ex(&redis_key, value, ttl).ignore(),
+            None => p.atomic().set(&redis_key, value).ignore(),
         };
 
+        let payload = self.encode_write(key, value, ttl_secs, mode == ExpirationMode::Sliding)?;
+        self.push_update(&mut p, payload);
+
+        let () = p
+            .query_async(&mut conn)
+            .instrument(debug_span!("redis_pipeline", op = "set"))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Reads a value and its remaining TTL from Redis in one round-trip.
+    pub async fn fetch(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Bytes, Option<Duration>)>, Box<dyn Error + Send + Sync>> {
+        let mut conn = self.connection().await?;
+        let redis_key = self.prefixed_key(key);
+
+        let (value, pttl): (Option<Vec<u8>>, i64) = pipe()
+            .get(&redis_key)
+            .pttl(&redis_key)
+            .query_async(&mut conn)
+            .await?;
+
+        // PTTL is -1 for keys without expiry and -2 once the key is gone.
+        Ok(match (value, pttl) {
+            (Some(_), -2) | (None, _) => None,
+            (Some(value), pttl) if pttl > 0 => {
+                Some((value.into(), Some(Duration::from_millis(pttl as u64))))
+            }
+            (Some(value), _) => Some((value.into(), None)),
+        })
+    }
+
+    pub async fn set_many(
+        &self,
+        entries: &[(String, Bytes)],
+        ttl_secs: Option<u64>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut conn = self.connection().await?;
+
+        let mut p = pipe();
+        p.atomic();
+        for (key, value) in entries {
+            let redis_key = self.prefixed_key(key);
+            match ttl_secs {
+                Some(ttl) => p.set_ex(&redis_key, value.as_ref(), ttl).ignore(),
+                None => p.set(&redis_key, value.as_ref()).ignore(),
+            };
+
+            let payload = self.encode_write(key, value, ttl_secs, false)?;
+            self.push_update(&mut p, payload);
+        }
+
+        let () = p
+            .query_async(&mut conn)
+            .instrument(debug_span!("redis_pipeline", op = "mset"))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deletes `key` in Redis and on peers. Returns whether Redis had it.
+    pub async fn delete(&self, key: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut conn = self.connection().await?;
+        let redis_key = self.prefixed_key(key);
+
+        let mut p = pipe();
+        p.atomic().del(&redis_key);
+
+        let payload = self.encode_update(UpdateOp::Delete, key, None, false, None)?;
+        self.push_update(&mut p, payload);
+
+        let (deleted,): (u64,) = p.query_async(&mut conn).await?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Remaining TTL of `key` in Redis in milliseconds (`Some(None)` without
+    /// one), or `None` if Redis does not have it.
+    pub async fn ttl(
+        &self,
+        key: &str,
+    ) -> Result<Option<Option<u64>>, Box<dyn Error + Send + Sync>> {
+        let mut conn = self.connection().await?;
+        let pttl: i64 = conn.pttl(self.prefixed_key(key)).await?;
+
+        // PTTL is -1 for keys without expiry and -2 for missing ones.
+        Ok(match pttl {
+            -2 => None,
+            pttl if pttl < 0 => Some(None),
+            pttl => Some(Some(pttl as u64)),
+        })
+    }
+
+    /// Gives `key` a new TTL in Redis and on peers. Returns whether Redis
+    /// has the key.
+    pub async fn expire(
+        &self,
+        key: &str,
+        ttl_secs: u64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut conn = self.connection().await?;
+
+        let mut p = pipe();
+        p.atomic().expire(self.prefixed_key(key), ttl_secs as i64);
+        self.publish_update(&mut p, UpdateOp::Expire, key, Some(ttl_secs))?;
+
+        let (exists,): (bool,) = p.query_async(&mut conn).await?;
+
+        Ok(exists)
+    }
+
+    /// Removes the TTL of `key` in Redis and on peers. Returns whether Redis
+    /// has the key.
+    pub async fn persist(&self, key: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut conn = self.connection().await?;
+        let redis_key = self.prefixed_key(key);
+
+        // PERSIST answers 0 for keys without a TTL too, so ask separately.
+        let mut p = pipe();
+        p.atomic().exists(&redis_key).persist(&redis_key).ignore();
+        self.publish_update(&mut p, UpdateOp::Persist, key, None)?;
+
+        let (exists,): (bool,) = p.query_async(&mut conn).await?;
+
+        Ok(exists)
+    }
+
+    /// Restarts the Redis TTL of `key` with `ttl_secs`, the duration the caller's
+    /// L1 entry was renewed with, and asks peers to restart their own. Without
+    /// a local entry the duration is unknown, so only peers restart theirs.
+    /// Returns whether Redis has the key.
+    pub async fn touch(
+        &self,
+        key: &str,
+        ttl_secs: Option<u64>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut conn = self.connection().await?;
+        let redis_key = self.prefixed_key(key);
+
+        let mut p = pipe();
+        p.atomic().exists(&redis_key);
+        if let Some(ttl) = ttl_secs {
+            p.expire(&redis_key, ttl as i64).ignore();
+        }
+        self.publish_update(&mut p, UpdateOp::Touch, key, ttl_secs)?;
+
+        let (exists,): (bool,) = p.query_async(&mut conn).await?;
+
+        Ok(exists)
+    }
+
+    fn publish_update(
+        &self,
+        p: &mut Pipeline,
+        op: UpdateOp,
+        key: &str,
+        ttl_secs: Option<u64>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let payload = self.encode_update(op, key, ttl_secs, false, None)?;
+        self.push_update(p, payload);
+        Ok(())
+    }
+
+    /// One `MGET` per `REFETCH_BATCH` of `keys`, replying with a list of
+    /// values for each.
+    pub(super) fn refetch_pipeline(&self, keys: &[String]) -> Pipeline {
+        let mut p = pipe();
+        for batch in keys.chunks(REFETCH_BATCH) {
+            let redis_keys = batch
+                .iter()
+                .map(|key| self.prefixed_key(key))
+                .collect::<Vec<_>>();
+            p.mget(redis_keys);
+        }
+        p
+    }
+
+    /// Queues an encoded update: published on `channel`, or appended to the
+    /// stream of that name.
+    fn push_update(&self, p: &mut Pipeline, payload: Vec<u8>) {
+        match self.transport {
+            SyncTransport::Pubsub => p.publish(&self.channel, payload).ignore(),
+            SyncTransport::Stream => p
+                .xadd_maxlen(
+                    &self.channel,
+                    StreamMaxlen::Approx(self.stream_maxlen as usize),
+                    "*",
+                    &[(STREAM_FIELD, payload)],
+                )
+                .ignore(),
+        };
+    }
+
+    /// The update announcing a write of `key`: the value for peers to store,
+    /// or only an invalidation when `key` is synced in `Invalidate` mode.
+    fn encode_write(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl_secs: Option<u64>,
+        sliding: bool,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        match self.mode(key) {
+            SyncMode::Replicate => {
+                self.encode_update(UpdateOp::Set, key, ttl_secs, sliding, Some(value))
+            }
+            SyncMode::Invalidate => self.encode_update(UpdateOp::Delete, key, None, false, None),
+        }
+    }
+
+    fn encode_update(
+        &self,
+        op: UpdateOp,
+        key: &str,
+        ttl_secs: Option<u64>,
+        sliding: bool,
+        value: Option<&[u8]>,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
         let message = CacheUpdate {
+            op,
             key: key.to_string(),
             ttl_secs,
+            sliding,
+            node_id: self.node_id.clone(),
+            seq: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            value: value
+                .filter(|value| self.inlines(value.len()))
+                .map(InlineValue::new),
         };
-        let payload = bincode::encode_to_vec(message, bincode::config::standard())?;
-        p.publish(&self.channel, payload);
+        message.encode()
+    }
 
-        let (_, _): ((), i64) = p.query_async(&mut conn).await?;
+    fn override_for(&self, key: &str) -> Option<&SyncOverride> {
+        self.overrides
+            .iter()
+            .filter(|rule| key.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+    }
 
-        Ok(())
+    /// How writes of `key` are synced: its longest matching override, or
+    /// `redis.mode`.
+    pub fn mode(&self, key: &str) -> SyncMode {
+        self.override_for(key).map_or(self.mode, |rule| rule.mode)
+    }
+
+    /// Whether an L1 miss for `key` is loaded from Redis.
+    pub fn reads_through(&self, key: &str) -> bool {
+        self.override_for(key)
+            .and_then(|rule| rule.read_through)
+            .unwrap_or(self.read_through)
+    }
+
+    fn inlines(&self, len: usize) -> bool {
+        self.inline_max_bytes > 0 && len as u64 <= self.inline_max_bytes
+    }
+
+    /// Whether `update` was published by this server.
+    pub(super) fn is_own(&self, update: &CacheUpdate) -> bool {
+        update.node_id == self.node_id
     }
 
     pub(super) fn prefixed_key(&self, key: &str) -> String {
@@ -74,3 +532,206 @@ impl RedisSync {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheUpdate, InlineValue, REFETCH_BATCH, RedisSync, UPDATE_VERSION, UpdateOp};
+    use crate::config::{RedisConfig, SyncMode, SyncOverride, SyncTransport, TimeoutConfig};
+
+    fn redis_sync(node_id: Option<&str>) -> RedisSync {
+        let config = RedisConfig {
+            url: Some("redis://127.0.0.1:6379".to_string()),
+            node_id: node_id.map(str::to_string),
+            ..RedisConfig::default()
+        };
+        RedisSync::from_config(&config, &TimeoutConfig::default())
+            .unwrap()
+            .expect("url is set")
+    }
+
+    /// The arguments of the commands in `p`, each following its `$<len>`
+    /// header.
+    fn packed_args(p: &redis::Pipeline) -> Vec<String> {
+        let packed = String::from_utf8_lossy(&p.get_packed_pipeline()).into_owned();
+        let mut lines = packed.split("\r\n");
+        let mut args = Vec::new();
+        while let Some(line) = lines.next() {
+            if line.starts_with('$') {
+                args.extend(lines.next().map(str::to_string));
+            }
+        }
+        args
+    }
+
+    fn decode(payload: &[u8]) -> CacheUpdate {
+        CacheUpdate::decode(payload).unwrap()
+    }
+
+    #[test]
+    fn updates_carry_node_id_and_increasing_sequence() {
+        let node = redis_sync(Some("node-a"));
+        let first = decode(
+            &node
+                .encode_update(UpdateOp::Set, "alpha", None, false, None)
+                .unwrap(),
+        );
+        let second = decode(
+            &node
+                .encode_update(UpdateOp::Delete, "alpha", None, false, None)
+                .unwrap(),
+        );
+
+        assert_eq!(first.node_id, "node-a");
+        assert!(second.seq > first.seq);
+        assert!(node.is_own(&first));
+        assert!(!redis_sync(Some("node-b")).is_own(&first));
+    }
+
+    #[test]
+    fn updates_stay_readable_across_versions() {
+        let config = bincode::config::standard();
+        let legacy = bincode::encode_to_vec(("alpha".to_string(), Some(60u64)), config).unwrap();
+        let update = decode(&legacy);
+        assert!(matches!(update.op, UpdateOp::Set));
+        assert_eq!((update.key.as_str(), update.ttl_secs), ("alpha", Some(60)));
+        assert!(update.node_id.is_empty() && update.value.is_none());
+
+        let current = redis_sync(Some("node-a"))
+            .encode_update(UpdateOp::Delete, "alpha", None, false, None)
+            .unwrap();
+        let (header, _): ((String, Option<u64>), _) =
+            bincode::decode_from_slice(&current, config).unwrap();
+        assert_eq!(header, ("alpha".to_string(), None));
+        assert!(matches!(decode(&current).op, UpdateOp::Delete));
+
+        let mut unknown = legacy.clone();
+        unknown.push(UPDATE_VERSION + 1);
+        assert!(CacheUpdate::decode(&unknown).is_err());
+    }
+
+    #[test]
+    fn generated_node_id_is_stable() {
+        let node = redis_sync(None);
+        assert!(!node.node_id.is_empty());
+        assert_eq!(redis_sync(None).node_id, node.node_id);
+    }
+
+    #[test]
+    fn small_values_are_inlined_and_compressed() {
+        let node = redis_sync(Some("node-a"));
+        let text = b"synapse ".repeat(64);
+        let encode = |value: &[u8]| {
+            decode(
+                &node
+                    .encode_update(UpdateOp::Set, "alpha", None, false, Some(value))
+                    .unwrap(),
+            )
+        };
+
+        let update = encode(&text);
+        assert!(matches!(update.value, Some(InlineValue::Zstd(ref c)) if c.len() < text.len()));
+        assert_eq!(update.value.unwrap().into_bytes().unwrap(), text);
+
+        let update = encode(b"v1");
+        assert!(matches!(update.value, Some(InlineValue::Plain(ref v)) if v == b"v1"));
+
+        let large = vec![7; node.inline_max_bytes as usize + 1];
+        assert!(encode(&large).value.is_none());
+    }
+
+    #[test]
+    fn inlining_can_be_disabled() {
+        let config = RedisConfig {
+            url: Some("redis://127.0.0.1:6379".to_string()),
+            inline_max_bytes: 0,
+            ..RedisConfig::default()
+        };
+        let node = RedisSync::from_config(&config, &TimeoutConfig::default())
+            .unwrap()
+            .expect("url is set");
+        let payload = node
+            .encode_update(UpdateOp::Set, "alpha", None, false, Some(b""))
+            .unwrap();
+        assert!(decode(&payload).value.is_none());
+    }
+
+    #[test]
+    fn invalidate_mode_publishes_only_an_invalidation() {
+        let config = RedisConfig {
+            url: Some("redis://127.0.0.1:6379".to_string()),
+            overrides: vec![
+                SyncOverride {
+                    prefix: "report:".to_string(),
+                    mode: SyncMode::Invalidate,
+                    read_through: Some(true),
+                },
+                SyncOverride {
+                    prefix: "report:live:".to_string(),
+                    mode: SyncMode::Replicate,
+                    read_through: None,
+                },
+            ],
+            ..RedisConfig::default()
+        };
+        let node = RedisSync::from_config(&config, &TimeoutConfig::default())
+            .unwrap()
+            .expect("url is set");
+
+        assert_eq!(node.mode("user:1"), SyncMode::Replicate);
+        assert_eq!(node.mode("report:1"), SyncMode::Invalidate);
+        assert_eq!(node.mode("report:live:1"), SyncMode::Replicate);
+        assert!(!node.reads_through("user:1"));
+        assert!(node.reads_through("report:1"));
+        assert!(!node.reads_through("report:live:1"));
+
+        let update = decode(
+            &node
+                .encode_write("report:1", b"v1", Some(60), false)
+                .unwrap(),
+        );
+        assert!(matches!(update.op, UpdateOp::Delete));
+        assert!(update.value.is_none());
+        let update = decode(&node.encode_write("user:1", b"v1", Some(60), false).unwrap());
+        assert!(matches!(update.op, UpdateOp::Set));
+        assert_eq!(update.ttl_secs, Some(60));
+    }
+
+    #[test]
+    fn stream_transport_appends_to_a_capped_stream() {
+        let config = RedisConfig {
+            url: Some("redis://127.0.0.1:6379".to_string()),
+            transport: SyncTransport::Stream,
+            stream_maxlen: 500,
+            ..RedisConfig::default()
+        };
+        let node = RedisSync::from_config(&config, &TimeoutConfig::default())
+            .unwrap()
+            .expect("url is set");
+        let mut p = redis::pipe();
+        node.publish_update(&mut p, UpdateOp::Delete, "alpha", None)
+            .unwrap();
+
+        let args = packed_args(&p);
+        assert_eq!(
+            &args[..6],
+            ["XADD", "synapse:cache_updates", "MAXLEN", "~", "500", "*"]
+        );
+        assert_eq!(args[6], "u");
+    }
+
+    #[test]
+    fn refetch_batches_prefixed_keys_into_mgets() {
+        let node = redis_sync(None);
+        let keys = (0..REFETCH_BATCH + 1)
+            .map(|i| format!("key{}", i))
+            .collect::<Vec<_>>();
+
+        let args = packed_args(&node.refetch_pipeline(&keys));
+        assert_eq!(args.iter().filter(|arg| *arg == "MGET").count(), 2);
+        assert_eq!(args[1], node.prefixed_key("key0"));
+        assert_eq!(
+            args[REFETCH_BATCH + 1..],
+            ["MGET".to_string(), node.prefixed_key(&keys[REFETCH_BATCH])]
+        );
+    }
+}
@@ -1,13 +1,248 @@
-use std::{error::Error, time::Duration};
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use redis::AsyncCommands;
-use synapse_core::L1Cache;
+use bytes::Bytes;
+use redis::{
+    AsyncCommands,
+    aio::MultiplexedConnection,
+    streams::{StreamRangeReply, StreamReadOptions, StreamReadReply},
+};
+use synapse_core::{ExpirationMode, L1Cache, key_hash};
 use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, debug_span, error, info, info_span, warn};
 
-use crate::redis::client::{CacheUpdate, RedisSync};
+use crate::config::{ReconnectPolicy, SyncMode, SyncTransport};
+use crate::metrics::METRICS;
+use crate::redis::client::{CacheUpdate, InlineValue, RedisSync, STREAM_FIELD, UpdateOp};
 use futures::StreamExt;
 
+/// How long an `XREAD` waits for new entries.
+const STREAM_BLOCK: Duration = Duration::from_secs(5);
+const STREAM_BATCH: usize = 256;
+/// Where a reader starts on a stream that has no entries yet.
+const EMPTY_STREAM_ID: &str = "0-0";
+/// Keys refetched per round-trip by `ReconnectPolicy::Refetch`.
+const REFETCH_CHUNK: usize = 8192;
+
+/// Takes back its `synapse_pubsub_subscribed` count however the subscriber
+/// returns. A count rather than a flag, since a replaced subscriber may only
+/// let go after its replacement is up.
+struct Unsubscribed;
+
+impl Drop for Unsubscribed {
+    fn drop(&mut self) {
+        METRICS.pubsub_subscribed.dec();
+    }
+}
+
+/// Keeps L1 from serving entries while updates it missed are applied or it
+/// is resynced, until dropped.
+struct CatchingUp<'a>(&'a L1Cache);
+
+impl<'a> CatchingUp<'a> {
+    fn start(l1_cache: &'a L1Cache) -> Self {
+        l1_cache.start_catch_up();
+        Self(l1_cache)
+    }
+}
+
+impl Drop for CatchingUp<'_> {
+    fn drop(&mut self) {
+        self.0.finish_catch_up();
+    }
+}
+
+/// Applies one encoded `CacheUpdate` from a peer to L1.
+async fn apply_update(
+    payload: &[u8],
+    l1_cache: &L1Cache,
+    redis_sync: &RedisSync,
+    conn: &mut MultiplexedConnection,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    METRICS.pubsub_received.inc();
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        METRICS.pubsub_last_message.set(now.as_secs_f64());
+    }
+    let cache_update = match CacheUpdate::decode(payload) {
+        Ok(cache_update) => {
+            METRICS.pubsub_decoded.inc();
+            cache_update
+        }
+        Err(err) => {
+            METRICS.pubsub_failed.inc();
+            warn!(error = %err, "Cannot decode cache update");
+            return Ok(());
+        }
+    };
+    if redis_sync.is_own(&cache_update) {
+        METRICS.pubsub_echoes_suppressed.inc();
+        return Ok(());
+    }
+    debug!(
+        op = ?cache_update.op,
+        key_hash = %format_args!("{:016x}", key_hash(&cache_update.key)),
+        node_id = %cache_update.node_id,
+        seq = cache_update.seq,
+        "Cache update received"
+    );
+
+    match cache_update.op {
+        // A peer replicating a key this server only invalidates.
+        UpdateOp::Set if redis_sync.mode(&cache_update.key) == SyncMode::Invalidate => {
+            l1_cache.invalidate(&cache_update.key).await;
+        }
+        UpdateOp::Set => {
+            let inline = match cache_update.value.map(InlineValue::into_bytes) {
+                Some(Ok(value)) => {
+                    METRICS.pubsub_inline_values.inc();
+                    Some(value)
+                }
+                Some(Err(err)) => {
+                    warn!(error = %err, "Cannot decompress inline value; reading it from Redis");
+                    None
+                }
+                None => None,
+            };
+            let value = match inline {
+                Some(value) => Some(value),
+                None => {
+                    let prefix_key = redis_sync.prefixed_key(&cache_update.key);
+                    let value: Option<Vec<u8>> = conn.get(&prefix_key).await?;
+                    value.map(Bytes::from)
+                }
+            };
+
+            if let Some(bytes) = value {
+                let mode = if cache_update.sliding {
+                    ExpirationMode::Sliding
+                } else {
+                    ExpirationMode::Absolute
+                };
+                l1_cache
+                    .set_with_mode(cache_update.key.clone(), bytes, cache_update.ttl_secs, mode)
+                    .await;
+            };
+        }
+        UpdateOp::Delete => l1_cache.invalidate(&cache_update.key).await,
+        UpdateOp::Expire => {
+            if let Some(ttl) = cache_update.ttl_secs {
+                l1_cache.expire(&cache_update.key, ttl).await;
+            }
+        }
+        UpdateOp::Persist => {
+            l1_cache.persist(&cache_update.key).await;
+        }
+        UpdateOp::Touch => {
+            l1_cache.touch(&cache_update.key).await;
+        }
+    }
+    Ok(())
+}
+
+/// Reads every key held in L1 back from Redis. Keys gone from Redis are
+/// dropped; the others keep their local TTL.
+async fn refetch(
+    l1_cache: &L1Cache,
+    redis_sync: &RedisSync,
+    conn: &mut MultiplexedConnection,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for keys in l1_cache.keys().chunks(REFETCH_CHUNK) {
+        // Local writes after this are newer than what the `MGET`s return.
+        let fetched = Instant::now();
+        let values: Vec<Vec<Option<Vec<u8>>>> = redis_sync
+            .refetch_pipeline(keys)
+            .query_async(conn)
+            .instrument(debug_span!("redis_pipeline", op = "refetch"))
+            .await?;
+        for (key, value) in keys.iter().zip(values.into_iter().flatten()) {
+            match value {
+                Some(value) => {
+                    l1_cache.refresh_if_older(key, value.into(), fetched).await;
+                }
+                None => {
+                    l1_cache.invalidate_if_older(key, fetched).await;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Brings L1 back in line after cache updates may have been missed.
+async fn resync(
+    policy: ReconnectPolicy,
+    l1_cache: &L1Cache,
+    redis_sync: &RedisSync,
+    conn: &mut MultiplexedConnection,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match policy {
+        ReconnectPolicy::Resume => {}
+        ReconnectPolicy::Flush => l1_cache.invalidate_all(),
+        ReconnectPolicy::Stale => l1_cache.mark_all_stale(),
+        ReconnectPolicy::Refetch => refetch(l1_cache, redis_sync, conn).await?,
+    }
+    Ok(())
+}
+
+/// When the subscriber lost Redis, until one is back. It outlives the
+/// subscriber task, so the one a reload spawns resyncs L1 for the updates
+/// missed while it replaced the old one.
+static DISCONNECTED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Whether a subscriber was ever spawned; any later spawn replaces one.
+static SPAWNED: AtomicBool = AtomicBool::new(false);
+
+/// Subscriber tasks still running, so a server with Redis sync turned off
+/// does not report itself disconnected.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+fn disconnected_at() -> std::sync::MutexGuard<'static, Option<Instant>> {
+    DISCONNECTED_AT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn mark_disconnected() {
+    disconnected_at().get_or_insert_with(Instant::now);
+}
+
+/// How long the running subscriber has been without Redis; zero while it is
+/// connected or when none is running.
+pub fn disconnected_for() -> Duration {
+    if RUNNING.load(Ordering::Relaxed) == 0 {
+        return Duration::ZERO;
+    }
+    disconnected_at().map_or(Duration::ZERO, |since| since.elapsed())
+}
+
+/// Ends `RUNNING`'s count for a subscriber task however it exits.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Records how long the subscriber was disconnected, once it is back.
+fn reconnected() {
+    let since = disconnected_at().take();
+    if let Some(since) = since {
+        let elapsed = since.elapsed();
+        METRICS.pubsub_disconnected.observe(elapsed.as_secs_f64());
+        info!(
+            disconnected_ms = elapsed.as_millis() as u64,
+            "Update subscriber reconnected"
+        );
+    }
+}
+
 async fn run_subscriber(
     l1_cache: L1Cache,
     shutdown: CancellationToken,
@@ -17,29 +252,174 @@ async fn run_subscriber(
     pubsub.subscribe(&redis_sync.channel).await?;
     let mut stream = pubsub.on_message();
 
-    let mut conn = redis_sync.client.get_multiplexed_async_connection().await?;
+    let mut conn = redis_sync.dedicated_connection().await?;
+    // Updates published from here on queue up in `stream` meanwhile.
+    let missed_updates = disconnected_at().is_some();
+    if missed_updates {
+        let _catching_up = CatchingUp::start(&l1_cache);
+        resync(redis_sync.on_reconnect, &l1_cache, redis_sync, &mut conn).await?;
+    }
+    reconnected();
+    METRICS.pubsub_subscribed.inc();
+    let _unsubscribed = Unsubscribed;
 
     loop {
         tokio::select! {
             _ = shutdown.cancelled() => break,
             msg = stream.next() => {
                 let Some(msg) = msg else { break };
-                let payload = msg.get_payload_bytes();
-                let cache_update: CacheUpdate = match bincode::decode_from_slice::<CacheUpdate, _>(payload, bincode::config::standard()) {
-                    Ok((cache_update, _)) => cache_update,
-                    Err(err) => {
-                        eprintln!("Redis pub/sub decode error: {err}");
-                        continue;
-                    }
-                };
+                apply_update(msg.get_payload_bytes(), &l1_cache, redis_sync, &mut conn).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
 
-                let prefix_key = redis_sync.prefixed_key(&cache_update.key);
-                let value: Option<Vec<u8>> = conn.get(&prefix_key).await?;
+/// The last stream entry this process applied. It outlives the reader, so a
+/// reader restarted after an error or a reload resumes right after it.
+static STREAM_POSITION: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+fn stream_position(stream: &str) -> Option<String> {
+    let position = STREAM_POSITION.lock().unwrap_or_else(|e| e.into_inner());
+    position
+        .as_ref()
+        .filter(|(name, _)| name == stream)
+        .map(|(_, id)| id.clone())
+}
+
+fn set_stream_position(stream: &str, id: &str) {
+    *STREAM_POSITION.lock().unwrap_or_else(|e| e.into_inner()) =
+        Some((stream.to_string(), id.to_string()));
+}
+
+/// The id of the newest entry, or `EMPTY_STREAM_ID`.
+async fn latest_stream_id(
+    conn: &mut MultiplexedConnection,
+    stream: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let newest: StreamRangeReply = conn.xrevrange_count(stream, "+", "-", 1).await?;
+    Ok(newest
+        .ids
+        .into_iter()
+        .next()
+        .map_or_else(|| EMPTY_STREAM_ID.to_string(), |entry| entry.id))
+}
+
+/// Whether every entry after `id` is still in the stream. Redis 7 reports the
+/// newest id it ever trimmed or deleted; older servers do not, and neither a
+/// kept `id` nor the stream length proves nothing after it went, so anything
+/// short of that answer resyncs.
+async fn can_resume(
+    conn: &mut MultiplexedConnection,
+    redis_sync: &RedisSync,
+    id: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let exists: bool = conn.exists(&redis_sync.channel).await?;
+    if !exists {
+        return Ok(false);
+    }
+    let info: HashMap<String, redis::Value> = redis::cmd("XINFO")
+        .arg("STREAM")
+        .arg(&redis_sync.channel)
+        .query_async(conn)
+        .await?;
+    let max_deleted = info
+        .get("max-deleted-entry-id")
+        .and_then(|value| redis::from_redis_value_ref::<String>(value).ok());
+    Ok(nothing_deleted_after(id, max_deleted.as_deref()))
+}
+
+/// Whether `max_deleted`, the newest id removed from the stream, comes no
+/// later than `id`. An unknown or unparsable id counts as a deletion.
+fn nothing_deleted_after(id: &str, max_deleted: Option<&str>) -> bool {
+    match (parse_stream_id(id), max_deleted.and_then(parse_stream_id)) {
+        (Some(id), Some(max_deleted)) => max_deleted <= id,
+        _ => false,
+    }
+}
+
+/// Splits a `<ms>-<seq>` stream id so ids compare in stream order.
+fn parse_stream_id(id: &str) -> Option<(u64, u64)> {
+    let (ms, seq) = id.split_once('-')?;
+    Some((ms.parse().ok()?, seq.parse().ok()?))
+}
+
+async fn run_stream_reader(
+    l1_cache: L1Cache,
+    shutdown: CancellationToken,
+    redis_sync: &RedisSync,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let stream = redis_sync.channel.as_str();
+    let mut conn = redis_sync.dedicated_connection().await?;
+    let mut reader = redis_sync.dedicated_connection().await?;
+    reader.set_response_timeout(STREAM_BLOCK * 2);
 
-                if let Some(bytes) = value {
-                    l1_cache.set(cache_update.key.clone(), bytes, cache_update.ttl_secs).await;
+    // Entries added since the last one applied are read back before L1
+    // serves again; it starts out empty, so there is nothing to hold back.
+    let position = stream_position(stream);
+    let mut catching_up = position.is_some().then(|| CatchingUp::start(&l1_cache));
+    let mut id = match position {
+        Some(id) if can_resume(&mut conn, redis_sync, &id).await? => id,
+        last_seen => {
+            let latest = latest_stream_id(&mut conn, stream).await?;
+            if last_seen.is_some() {
+                METRICS.stream_full_invalidations.inc();
+                // Resuming would leave L1 stale, so that falls back to a flush.
+                let policy = match redis_sync.on_reconnect {
+                    ReconnectPolicy::Resume => ReconnectPolicy::Flush,
+                    policy => policy,
                 };
+                warn!(
+                    ?policy,
+                    "Updates were trimmed from the stream before this server read them; resyncing L1"
+                );
+                resync(policy, &l1_cache, redis_sync, &mut conn).await?;
             }
+            set_stream_position(stream, &latest);
+            latest
+        }
+    };
+    reconnected();
+
+    METRICS.pubsub_subscribed.inc();
+    let _unsubscribed = Unsubscribed;
+    let streams = [stream];
+    let catch_up_options = StreamReadOptions::default().count(STREAM_BATCH);
+    let options = StreamReadOptions::default()
+        .block(STREAM_BLOCK.as_millis() as usize)
+        .count(STREAM_BATCH);
+
+    loop {
+        let ids = [id.as_str()];
+        // Catching up doesn't wait for new entries once the backlog is read.
+        let options = match catching_up {
+            Some(_) => &catch_up_options,
+            None => &options,
+        };
+        let reply: Option<StreamReadReply> = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            reply = reader.xread_options(&streams, &ids, options) => reply?,
+        };
+        let entries: Vec<_> = reply
+            .into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|key| key.ids)
+            .collect();
+        let drained = entries.len() < STREAM_BATCH;
+        for entry in entries {
+            match entry.get::<Vec<u8>>(STREAM_FIELD) {
+                Some(payload) => apply_update(&payload, &l1_cache, redis_sync, &mut conn).await?,
+                None => {
+                    METRICS.pubsub_failed.inc();
+                    warn!(id = %entry.id, "Stream entry carries no cache update");
+                }
+            }
+            id = entry.id;
+            set_stream_position(stream, &id);
+        }
+        if drained {
+            catching_up = None;
         }
     }
 
@@ -51,34 +431,99 @@ pub fn spawn_redis_subscriber(
     shutdown: CancellationToken,
     redis_sync: RedisSync,
 ) {
-    tokio::spawn(async move {
-        let mut backoff = Duration::from_millis(200);
-        loop {
-            if shutdown.is_cancelled() {
-                break;
-            }
-
-            let res = run_subscriber(l1_cache.clone(), shutdown.clone(), &redis_sync).await;
-
-            let failed = match res {
-                Ok(()) => {
-                    backoff = Duration::from_millis(200);
-                    false
+    let span = info_span!("redis_subscriber", channel = %redis_sync.channel);
+    if SPAWNED.swap(true, Ordering::Relaxed) {
+        // Replacing a subscriber: whatever is published until this one is
+        // up never reaches L1.
+        mark_disconnected();
+    }
+    RUNNING.fetch_add(1, Ordering::Relaxed);
+    tokio::spawn(
+        async move {
+            let _running = Running;
+            let mut backoff = Duration::from_millis(200);
+            loop {
+                if shutdown.is_cancelled() {
+                    break;
                 }
-                Err(err) => {
-                    eprintln!("Redis pub/sub error: {}", err);
-                    true
+
+                let res = match redis_sync.transport {
+                    SyncTransport::Pubsub => {
+                        run_subscriber(l1_cache.clone(), shutdown.clone(), &redis_sync).await
+                    }
+                    SyncTransport::Stream => {
+                        run_stream_reader(l1_cache.clone(), shutdown.clone(), &redis_sync).await
+                    }
+                };
+                // A cancelled subscriber is being stopped or replaced; the
+                // spawn of its replacement marks the gap.
+                if shutdown.is_cancelled() {
+                    break;
                 }
-            };
+                mark_disconnected();
 
-            if failed {
-                backoff = (backoff * 2).min(Duration::from_secs(30));
-            };
+                let failed = match res {
+                    Ok(()) => {
+                        backoff = Duration::from_millis(200);
+                        false
+                    }
+                    Err(err) => {
+                        error!(error = %err, "Redis subscriber failed");
+                        true
+                    }
+                };
+
+                if failed {
+                    backoff = (backoff * 2).min(redis_sync.reconnect_max);
+                };
 
-            tokio::select! {
-                _ = shutdown.cancelled() => break,
-                _ = sleep(backoff) => {},
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = sleep(backoff) => {},
+                }
+                METRICS.pubsub_reconnects.inc();
             }
         }
-    });
+        .instrument(span),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EMPTY_STREAM_ID, RUNNING, Running, disconnected_for, mark_disconnected,
+        nothing_deleted_after, reconnected,
+    };
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    #[test]
+    fn disconnected_time_counts_until_reconnected() {
+        mark_disconnected();
+        assert_eq!(disconnected_for(), Duration::ZERO);
+
+        RUNNING.fetch_add(1, Ordering::Relaxed);
+        let running = Running;
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(disconnected_for() >= Duration::from_millis(5));
+
+        reconnected();
+        assert_eq!(disconnected_for(), Duration::ZERO);
+        drop(running);
+    }
+
+    #[test]
+    fn resume_needs_every_deletion_before_the_last_read_id() {
+        assert!(nothing_deleted_after("1700-3", Some("0-0")));
+        assert!(nothing_deleted_after("1700-3", Some("1700-3")));
+        assert!(nothing_deleted_after("1700-3", Some("1700-2")));
+        // Same millisecond, later sequence: trimmed past the last read entry.
+        assert!(!nothing_deleted_after("1700-3", Some("1700-4")));
+        // Numeric, not lexicographic, order.
+        assert!(!nothing_deleted_after("999-0", Some("1000-0")));
+        assert!(nothing_deleted_after(EMPTY_STREAM_ID, Some("0-0")));
+        assert!(!nothing_deleted_after(EMPTY_STREAM_ID, Some("5-0")));
+        // Redis before 7 does not report deletions.
+        assert!(!nothing_deleted_after("1700-3", None));
+        assert!(!nothing_deleted_after("1700-3", Some("garbage")));
+    }
 }
@@ -1,17 +1,398 @@
-use std::error::Error;
+use std::{
+    error::Error,
+    fs::{create_dir_all, remove_file},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+};
 
-use synapse_core::L1Cache;
+use futures::{Stream, stream};
+use synapse_core::{
+    CacheCommand, CacheEvent, CacheResponce, ExpirationMode, L1Cache, MAX_FRAME_LENGTH,
+};
+use tokio::{net::UnixListener, sync::broadcast};
+use tokio_stream::wrappers::UnixListenerStream;
 use tokio_util::sync::CancellationToken;
+use tonic::{Request, Response, Status, transport::Server};
+use tracing::info;
 
+use crate::config::ListenerConfig;
+use crate::redis::client::SharedRedisSync;
+use crate::server::uds::execute_command;
+
+pub mod proto {
+    tonic::include_proto!("synapse.v1");
+}
+
+use proto::{
+    DeleteRequest, DeleteResponse, GetManyRequest, GetManyResponse, GetRequest, GetResponse,
+    SetManyRequest, SetManyResponse, SetRequest, SetResponse, WatchEvent, WatchRequest,
+    cache_server::{Cache, CacheServer},
+    watch_event::Kind,
+};
+
+/// gRPC front-end for the cache. Commands go through the same path as UDS
+/// requests, so writes are mirrored to Redis and published to other nodes.
+pub struct CacheService {
+    l1_cache: L1Cache,
+    redis_sync: SharedRedisSync,
+    shutdown: CancellationToken,
+}
+
+impl CacheService {
+    pub fn new(
+        l1_cache: L1Cache,
+        redis_sync: SharedRedisSync,
+        shutdown: CancellationToken,
+    ) -> Self {
+        Self {
+            l1_cache,
+            redis_sync,
+            shutdown,
+        }
+    }
+
+    async fn execute(&self, cmd: CacheCommand) -> Result<CacheResponce, Status> {
+        match execute_command(cmd, None, &self.l1_cache, self.redis_sync.load().as_deref()).await {
+            CacheResponce::Error(err) => Err(Status::internal(err)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn ttl_secs(ttl_secs: Option<u64>) -> Option<u64> {
+    ttl_secs.filter(|&ttl| ttl > 0)
+}
+
+fn watch_event(event: CacheEvent, key_prefix: &str) -> Option<WatchEvent> {
+    let event = match event {
+        CacheEvent::Set { key, value } => WatchEvent {
+            kind: Kind::Set.into(),
+            key,
+            value: Some(value),
+        },
+        CacheEvent::Delete { key } => WatchEvent {
+            kind: Kind::Delete.into(),
+            key,
+            value: None,
+        },
+    };
+    event.key.starts_with(key_prefix).then_some(event)
+}
+
+type WatchState = (broadcast::Receiver<CacheEvent>, String, CancellationToken);
+
+async fn next_watch_event(
+    state: Option<WatchState>,
+) -> Option<(Result<WatchEvent, Status>, Option<WatchState>)> {
+    let (mut events, key_prefix, shutdown) = state?;
+    loop {
+        let event = tokio::select! {
+            _ = shutdown.cancelled() => return None,
+            event = events.recv() => event,
+        };
+        match event {
+            Ok(event) => {
+                if let Some(event) = watch_event(event, &key_prefix) {
+                    return Some((Ok(event), Some((events, key_prefix, shutdown))));
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                let status =
+                    Status::data_loss(format!("Watch fell behind and missed {} events", missed));
+                return Some((Err(status), None));
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Cache for CacheService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let GetRequest { key } = request.into_inner();
+        let value = match self.execute(CacheCommand::Get { key }).await? {
+            CacheResponce::Hit(value) => Some(value),
+            _ => None,
+        };
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        let SetRequest {
+            key,
+            value,
+            ttl_secs: ttl,
+            sliding,
+        } = request.into_inner();
+        let mode = if sliding {
+            ExpirationMode::Sliding
+        } else {
+            ExpirationMode::Absolute
+        };
+        self.execute(CacheCommand::Set {
+            key,
+            value,
+            ttl_secs: ttl_secs(ttl),
+            mode,
+        })
+        .await?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let DeleteRequest { key } = request.into_inner();
+        self.execute(CacheCommand::Delete { key }).await?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn get_many(
+        &self,
+        request: Request<GetManyRequest>,
+    ) -> Result<Response<GetManyResponse>, Status> {
+        let GetManyRequest { keys } = request.into_inner();
+        let values = match self.execute(CacheCommand::MGet { keys }).await? {
+            CacheResponce::Values(values) => values
+                .into_iter()
+                .map(|value| GetResponse { value })
+                .collect(),
+            _ => return Err(Status::internal("Unexpected response")),
+        };
+        Ok(Response::new(GetManyResponse { values }))
+    }
+
+    async fn set_many(
+        &self,
+        request: Request<SetManyRequest>,
+    ) -> Result<Response<SetManyResponse>, Status> {
+        let SetManyRequest {
+            entries,
+            ttl_secs: ttl,
+        } = request.into_inner();
+        let entries = entries
+            .into_iter()
+            .map(|entry| (entry.key, entry.value))
+            .collect();
+        self.execute(CacheCommand::MSet {
+            entries,
+            ttl_secs: ttl_secs(ttl),
+        })
+        .await?;
+        Ok(Response::new(SetManyResponse {}))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<WatchEvent, Status>> + Send>>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let WatchRequest { key_prefix } = request.into_inner();
+        let state = (self.l1_cache.watch(), key_prefix, self.shutdown.clone());
+        let events = stream::unfold(Some(state), next_watch_event);
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+/// Serves the gRPC API on `grpc.addr`, either `host:port` or
+/// `unix:/path/to.sock`. Without it the gRPC server stays off.
 pub async fn run_grpc(
-    _l1_cache: L1Cache,
+    config: ListenerConfig,
+    l1_cache: L1Cache,
     shutdown: CancellationToken,
+    redis_sync: SharedRedisSync,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    tokio::select! {
-        _ = shutdown.cancelled() => {
-            println!("gRPC server shutdown requested");
+    let Some(addr) = config.addr else {
+        shutdown.cancelled().await;
+        return Ok(());
+    };
+
+    let service = CacheServer::new(CacheService::new(l1_cache, redis_sync, shutdown.clone()))
+        .max_decoding_message_size(MAX_FRAME_LENGTH)
+        .max_encoding_message_size(MAX_FRAME_LENGTH);
+    let router = Server::builder().add_service(service);
+
+    match addr.strip_prefix("unix:") {
+        Some(socket_path) => {
+            if let Some(parent) = Path::new(socket_path).parent() {
+                create_dir_all(parent)?;
+            }
+            if Path::new(socket_path).exists() {
+                remove_file(socket_path)?;
+            }
+            let listener = UnixListener::bind(socket_path)?;
+
+            info!("Synapse gRPC server started on UDS: {}", socket_path);
+            router
+                .serve_with_incoming_shutdown(
+                    UnixListenerStream::new(listener),
+                    shutdown.cancelled(),
+                )
+                .await?;
+        }
+        None => {
+            let addr: SocketAddr = addr.parse()?;
+
+            info!("Synapse gRPC server started on TCP: {}", addr);
+            router
+                .serve_with_shutdown(addr, shutdown.cancelled())
+                .await?;
         }
     }
 
+    info!("gRPC server shutdown requested");
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CacheService;
+    use super::proto::{
+        DeleteRequest, Entry, GetManyRequest, GetRequest, SetManyRequest, SetRequest, WatchRequest,
+        cache_server::Cache, watch_event::Kind,
+    };
+    use crate::redis::client::SharedRedisSync;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use synapse_core::L1Cache;
+    use tokio_util::sync::CancellationToken;
+    use tonic::Request;
+
+    fn service() -> CacheService {
+        CacheService::new(
+            L1Cache::new(10),
+            SharedRedisSync::default(),
+            CancellationToken::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn grpc_set_get_delete() {
+        let service = service();
+
+        service
+            .set(Request::new(SetRequest {
+                key: "alpha".into(),
+                value: Bytes::from_static(b"v1"),
+                ttl_secs: None,
+                sliding: false,
+            }))
+            .await
+            .unwrap();
+        let response = service
+            .get(Request::new(GetRequest {
+                key: "alpha".into(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().value.as_deref(), Some(&b"v1"[..]));
+
+        service
+            .delete(Request::new(DeleteRequest {
+                key: "alpha".into(),
+            }))
+            .await
+            .unwrap();
+        let response = service
+            .get(Request::new(GetRequest {
+                key: "alpha".into(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().value, None);
+    }
+
+    #[tokio::test]
+    async fn grpc_set_many_get_many() {
+        let service = service();
+
+        service
+            .set_many(Request::new(SetManyRequest {
+                entries: vec![
+                    Entry {
+                        key: "k1".into(),
+                        value: Bytes::from_static(b"1"),
+                    },
+                    Entry {
+                        key: "k2".into(),
+                        value: Bytes::from_static(b"2"),
+                    },
+                ],
+                ttl_secs: Some(0),
+            }))
+            .await
+            .unwrap();
+        let response = service
+            .get_many(Request::new(GetManyRequest {
+                keys: vec!["k1".into(), "nope".into(), "k2".into()],
+            }))
+            .await
+            .unwrap();
+        let values: Vec<_> = response
+            .into_inner()
+            .values
+            .into_iter()
+            .map(|value| value.value)
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                Some(Bytes::from_static(b"1")),
+                None,
+                Some(Bytes::from_static(b"2"))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn grpc_watch_filters_by_prefix() {
+        let shutdown = CancellationToken::new();
+        let service = CacheService::new(
+            L1Cache::new(10),
+            SharedRedisSync::default(),
+            shutdown.clone(),
+        );
+
+        let mut events = service
+            .watch(Request::new(WatchRequest {
+                key_prefix: "user:".into(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        for key in ["session:1", "user:1"] {
+            service
+                .set(Request::new(SetRequest {
+                    key: key.into(),
+                    value: Bytes::from_static(b"v"),
+                    ttl_secs: None,
+                    sliding: false,
+                }))
+                .await
+                .unwrap();
+        }
+        service
+            .delete(Request::new(DeleteRequest {
+                key: "user:1".into(),
+            }))
+            .await
+            .unwrap();
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), Kind::Set);
+        assert_eq!(event.key, "user:1");
+        assert_eq!(event.value.as_deref(), Some(&b"v"[..]));
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), Kind::Delete);
+        assert_eq!(event.key, "user:1");
+
+        shutdown.cancel();
+        assert!(events.next().await.is_none());
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    error::Error,
+    fs::{create_dir_all, remove_file},
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::Path,
+};
+
+use synapse_core::L1Cache;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_util::{either::Either, sync::CancellationToken};
+use tracing::info;
+
+use crate::redis::client::SharedRedisSync;
+use crate::server::connection_failed;
+
+pub(crate) type Stream = Either<TcpStream, UnixStream>;
+
+/// Listener for the Redis and memcached front ends, bound from either
+/// `host:port` or `unix:/path/to.sock`.
+pub(crate) struct Listener(Either<TcpListener, UnixListener>);
+
+impl Listener {
+    pub(crate) async fn bind(
+        addr: &str,
+        protocol: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let listener = match addr.strip_prefix("unix:") {
+            Some(socket_path) => {
+                if let Some(parent) = Path::new(socket_path).parent() {
+                    create_dir_all(parent)?;
+                }
+                if Path::new(socket_path).exists() {
+                    remove_file(socket_path)?;
+                }
+                info!(
+                    "Synapse {} server started on UDS: {}",
+                    protocol, socket_path
+                );
+                Either::Right(UnixListener::bind(socket_path)?)
+            }
+            None => {
+                let addr: SocketAddr = addr.parse()?;
+                info!("Synapse {} server started on TCP: {}", protocol, addr);
+                Either::Left(TcpListener::bind(addr).await?)
+            }
+        };
+        Ok(Self(listener))
+    }
+
+    async fn accept(&self) -> io::Result<Stream> {
+        Ok(match &self.0 {
+            Either::Left(listener) => {
+                let (stream, _) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                Either::Left(stream)
+            }
+            Either::Right(listener) => Either::Right(listener.accept().await?.0),
+        })
+    }
+
+    /// Accepts connections until `shutdown`, running `handle` for each one.
+    pub(crate) async fn serve<F, Fut>(
+        self,
+        protocol: &str,
+        l1_cache: L1Cache,
+        shutdown: CancellationToken,
+        redis_sync: SharedRedisSync,
+        handle: F,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        F: Fn(Stream, L1Cache, SharedRedisSync) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("{} server shutdown requested", protocol);
+                    break;
+                }
+                accept_res = self.accept() => match accept_res {
+                    Ok(stream) => {
+                        tokio::spawn(handle(stream, l1_cache.clone(), redis_sync.clone()));
+                    }
+                    Err(err) => connection_failed(protocol, err).await,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,793 @@
+use std::{
+    error::Error,
+    fmt::Write,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use synapse_core::{
+    CacheCommand, CacheResponce, ExpirationMode, L1Cache, MAX_FRAME_LENGTH, SetCondition,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::{
+    codec::{Decoder, Encoder, Framed},
+    sync::CancellationToken,
+};
+use tracing::instrument;
+
+use crate::config::ListenerConfig;
+use crate::metrics::METRICS;
+use crate::redis::client::{RedisSync, SharedRedisSync};
+use crate::server::listener::Listener;
+use crate::server::next_connection_id;
+use crate::server::uds::{execute_command, execute_delete, execute_set_if};
+
+/// Longest command line accepted, data blocks excluded.
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+const MAX_KEY_LENGTH: usize = 250;
+/// Exptimes up to 30 days are relative; larger ones are unix timestamps.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format";
+
+/// Starts a stored value that carries client flags, followed by the flags as
+/// a big-endian `u32` and then the value itself.
+const FLAGS_HEADER: &[u8] = b"\0mcflags";
+const FLAGS_LEN: usize = FLAGS_HEADER.len() + 4;
+
+/// One command line and, for storage commands, the data block after it.
+#[derive(Debug, PartialEq)]
+struct Request {
+    args: Vec<Bytes>,
+    data: Option<Bytes>,
+}
+
+/// Splits the byte stream into requests; replies are written as-is.
+#[derive(Debug, Default)]
+struct MemcacheCodec;
+
+fn client_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Length of the data block announced by a storage command line, if any.
+fn data_len(line: &[u8], tokens: &[Range<usize>]) -> Option<usize> {
+    let index = match &line[tokens.first()?.clone()] {
+        b"set" | b"add" | b"replace" => 4,
+        b"ms" => 2,
+        _ => return None,
+    };
+    std::str::from_utf8(&line[tokens.get(index)?.clone()])
+        .ok()?
+        .parse()
+        .ok()
+}
+
+impl Decoder for MemcacheCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Request>> {
+        let Some(line_end) = src.iter().position(|&b| b == b'\n') else {
+            if src.len() > MAX_LINE_LENGTH {
+                return Err(client_error("CLIENT_ERROR line too long"));
+            }
+            return Ok(None);
+        };
+
+        let line = &src[..line_end];
+        let mut tokens = Vec::new();
+        let mut start = None;
+        for (i, b) in line.iter().enumerate() {
+            match (b.is_ascii_whitespace(), start) {
+                (false, None) => start = Some(i),
+                (true, Some(s)) => {
+                    tokens.push(s..i);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            tokens.push(s..line.len());
+        }
+
+        // A malformed length is reported by the command itself, like memcached
+        // does, and the data block is then read as the next command.
+        let mut data = None;
+        let mut frame_len = line_end + 1;
+        if let Some(len) = data_len(line, &tokens) {
+            if len > MAX_FRAME_LENGTH {
+                return Err(client_error("SERVER_ERROR object too large for cache"));
+            }
+            frame_len += len + 2;
+            if src.len() < frame_len {
+                src.reserve(frame_len - src.len());
+                return Ok(None);
+            }
+            if &src[frame_len - 2..frame_len] != b"\r\n" {
+                return Err(client_error("CLIENT_ERROR bad data chunk"));
+            }
+            data = Some(line_end + 1..frame_len - 2);
+        }
+
+        let frame = src.split_to(frame_len).freeze();
+        Ok(Some(Request {
+            args: tokens.into_iter().map(|r| frame.slice(r)).collect(),
+            // Copied, since a cached slice would pin the whole read buffer.
+            data: data.map(|r| Bytes::copy_from_slice(&frame[r])),
+        }))
+    }
+}
+
+impl Encoder<Bytes> for MemcacheCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, reply: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&reply);
+        Ok(())
+    }
+}
+
+/// What an exptime (or meta `T` flag) asks for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Expiry {
+    Never,
+    After(u64),
+    Expired,
+}
+
+fn expiry(exptime: i64) -> Expiry {
+    match exptime {
+        0 => Expiry::Never,
+        t if t < 0 => Expiry::Expired,
+        t if t <= MAX_RELATIVE_EXPTIME => Expiry::After(t as u64),
+        t => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() as i64);
+            if t > now {
+                Expiry::After((t - now) as u64)
+            } else {
+                Expiry::Expired
+            }
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(arg: &[u8]) -> Result<T, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| BAD_FORMAT.to_string())
+}
+
+/// L1 keys are strings, so keys must be UTF-8 on top of memcached's limits.
+fn key(arg: &Bytes) -> Result<String, String> {
+    if arg.len() > MAX_KEY_LENGTH {
+        return Err(BAD_FORMAT.into());
+    }
+    String::from_utf8(arg.to_vec()).map_err(|_| BAD_FORMAT.to_string())
+}
+
+/// Whether a trailing `noreply` follows the `expected` leading arguments.
+fn noreply(args: &[Bytes], expected: usize) -> Result<bool, String> {
+    match args.len() {
+        len if len == expected => Ok(false),
+        len if len == expected + 1 && args[expected] == b"noreply"[..] => Ok(true),
+        _ => Err(BAD_FORMAT.into()),
+    }
+}
+
+/// The value L1 and Redis store for `value` with client `flags`. Values
+/// without flags are stored as they are, so other protocols can read them,
+/// unless they would be mistaken for a flagged value.
+fn with_flags(value: Bytes, flags: u32) -> Bytes {
+    if flags == 0 && !value.starts_with(FLAGS_HEADER) {
+        return value;
+    }
+    let mut stored = BytesMut::with_capacity(FLAGS_LEN + value.len());
+    stored.extend_from_slice(FLAGS_HEADER);
+    stored.extend_from_slice(&flags.to_be_bytes());
+    stored.extend_from_slice(&value);
+    stored.freeze()
+}
+
+/// Splits a stored value into its client flags and the value.
+fn split_flags(stored: Bytes) -> (u32, Bytes) {
+    match stored.strip_prefix(FLAGS_HEADER) {
+        Some(rest) if rest.len() >= 4 => {
+            let flags = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            (flags, stored.slice(FLAGS_LEN..))
+        }
+        _ => (0, stored),
+    }
+}
+
+/// L1 has no item versions; the CAS token reported by `gets` and `mg c` is a
+/// hash of the value.
+fn cas(value: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+async fn execute(
+    cmd: CacheCommand,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> Result<CacheResponce, String> {
+    match execute_command(cmd, None, l1_cache, redis_sync).await {
+        CacheResponce::Error(err) => Err(format!("SERVER_ERROR {}", err)),
+        response => Ok(response),
+    }
+}
+
+/// Reads `key` as its client flags and value.
+async fn get(
+    key: String,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> Result<Option<(u32, Bytes)>, String> {
+    match execute(CacheCommand::Get { key }, l1_cache, redis_sync).await? {
+        CacheResponce::Hit(stored) => Ok(Some(split_flags(stored))),
+        _ => Ok(None),
+    }
+}
+
+/// Stores `value` when `condition` allows it. Already expired items are never
+/// visible, so storing one only removes the key.
+async fn store(
+    key: String,
+    value: Bytes,
+    expiry: Expiry,
+    condition: Option<SetCondition>,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> Result<bool, String> {
+    let ttl_secs = match expiry {
+        Expiry::Never => None,
+        Expiry::After(ttl_secs) => Some(ttl_secs),
+        Expiry::Expired => {
+            let allowed = match condition {
+                Some(SetCondition::IfAbsent) => !l1_cache.contains(&key).await,
+                Some(SetCondition::IfPresent) => l1_cache.contains(&key).await,
+                None => true,
+            };
+            if allowed {
+                execute(CacheCommand::Delete { key }, l1_cache, redis_sync).await?;
+            }
+            return Ok(allowed);
+        }
+    };
+
+    match condition {
+        Some(condition) => {
+            Ok(execute_set_if(key, value, ttl_secs, condition, l1_cache, redis_sync).await)
+        }
+        None => {
+            let cmd = CacheCommand::Set {
+                key,
+                value,
+                ttl_secs,
+                mode: ExpirationMode::Absolute,
+            };
+            execute(cmd, l1_cache, redis_sync).await?;
+            Ok(true)
+        }
+    }
+}
+
+/// Applies a new exptime to an existing key. Returns whether it was cached.
+async fn touch(
+    key: String,
+    expiry: Expiry,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> Result<bool, String> {
+    let cmd = match expiry {
+        Expiry::Never => CacheCommand::Persist { key },
+        Expiry::After(ttl_secs) => CacheCommand::Expire { key, ttl_secs },
+        Expiry::Expired => return Ok(execute_delete(key, l1_cache, redis_sync).await),
+    };
+    Ok(!matches!(
+        execute(cmd, l1_cache, redis_sync).await?,
+        CacheResponce::Miss
+    ))
+}
+
+/// Splits meta flags into their letter and token, e.g. `T30` into `(b'T', "30")`.
+fn meta_flags(args: &[Bytes], allowed: &[u8]) -> Result<Vec<(u8, Bytes)>, String> {
+    args.iter()
+        .map(|arg| match arg.first() {
+            Some(flag) if allowed.contains(flag) => Ok((*flag, arg.slice(1..))),
+            _ => Err("CLIENT_ERROR invalid flag".to_string()),
+        })
+        .collect()
+}
+
+fn write_meta_flags(out: &mut BytesMut, returned: &[String]) {
+    for flag in returned {
+        out.extend_from_slice(b" ");
+        out.extend_from_slice(flag.as_bytes());
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Opaque (`O`) and key (`k`) flags are echoed by every meta command.
+fn echoed_flag(flag: u8, token: &Bytes, key: &str) -> Option<String> {
+    match flag {
+        b'O' => Some(format!("O{}", String::from_utf8_lossy(token))),
+        b'k' => Some(format!("k{}", key)),
+        _ => None,
+    }
+}
+
+/// `mg <key> <flags>*`
+async fn meta_get(
+    args: &[Bytes],
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+    out: &mut BytesMut,
+) -> Result<(), String> {
+    let key = key(args.get(1).ok_or(BAD_FORMAT)?)?;
+    let flags = meta_flags(&args[2..], b"vtfkscqO")?;
+    let quiet = flags.iter().any(|(flag, _)| *flag == b'q');
+
+    let Some((client_flags, value)) = get(key.clone(), l1_cache, redis_sync).await? else {
+        if !quiet {
+            out.extend_from_slice(b"EN\r\n");
+        }
+        return Ok(());
+    };
+
+    let mut returned = Vec::new();
+    for (flag, token) in &flags {
+        let ret = match flag {
+            b't' => Some(match l1_cache.ttl(&key).await {
+                CacheResponce::Ttl(Some(ms)) => format!("t{}", ms.div_ceil(1000)),
+                _ => "t-1".to_string(),
+            }),
+            b'f' => Some(format!("f{}", client_flags)),
+            b's' => Some(format!("s{}", value.len())),
+            b'c' => Some(format!("c{}", cas(&value))),
+            _ => echoed_flag(*flag, token, &key),
+        };
+        returned.extend(ret);
+    }
+
+    if flags.iter().any(|(flag, _)| *flag == b'v') {
+        let _ = write!(out, "VA {}", value.len());
+        write_meta_flags(out, &returned);
+        out.extend_from_slice(&value);
+        out.extend_from_slice(b"\r\n");
+    } else {
+        out.extend_from_slice(b"HD");
+        write_meta_flags(out, &returned);
+    }
+    Ok(())
+}
+
+/// `ms <key> <datalen> <flags>*` followed by the data block. Modes `S` (set,
+/// the default), `E` (add) and `R` (replace) are supported.
+async fn meta_set(
+    args: &[Bytes],
+    data: Option<Bytes>,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+    out: &mut BytesMut,
+) -> Result<(), String> {
+    let (Some(key_arg), Some(value)) = (args.get(1), data) else {
+        return Err(BAD_FORMAT.into());
+    };
+    let key = key(key_arg)?;
+    let flags = meta_flags(&args[3..], b"TFMqOkc")?;
+
+    let mut expiry = Expiry::Never;
+    let mut client_flags = 0;
+    let mut condition = None;
+    let mut quiet = false;
+    for (flag, token) in &flags {
+        match flag {
+            b'T' => expiry = self::expiry(parse(token)?),
+            b'F' => client_flags = parse(token)?,
+            b'M' => {
+                condition = match &token[..] {
+                    b"S" | b"s" => None,
+                    b"E" | b"e" => Some(SetCondition::IfAbsent),
+                    b"R" | b"r" => Some(SetCondition::IfPresent),
+                    _ => return Err("CLIENT_ERROR invalid mode for ms".into()),
+                }
+            }
+            b'q' => quiet = true,
+            _ => {}
+        }
+    }
+
+    let returned: Vec<String> = flags
+        .iter()
+        .filter_map(|(flag, token)| match flag {
+            b'c' => Some(format!("c{}", cas(&value))),
+            _ => echoed_flag(*flag, token, &key),
+        })
+        .collect();
+    let value = with_flags(value, client_flags);
+    let stored = store(key, value, expiry, condition, l1_cache, redis_sync).await?;
+    if stored && quiet {
+        return Ok(());
+    }
+    out.extend_from_slice(if stored { b"HD" } else { b"NS" });
+    write_meta_flags(out, &returned);
+    Ok(())
+}
+
+/// `md <key> <flags>*`
+async fn meta_delete(
+    args: &[Bytes],
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+    out: &mut BytesMut,
+) -> Result<(), String> {
+    let key = key(args.get(1).ok_or(BAD_FORMAT)?)?;
+    let flags = meta_flags(&args[2..], b"qOk")?;
+    let quiet = flags.iter().any(|(flag, _)| *flag == b'q');
+    let returned: Vec<String> = flags
+        .iter()
+        .filter_map(|(flag, token)| echoed_flag(*flag, token, &key))
+        .collect();
+
+    let found = execute_delete(key, l1_cache, redis_sync).await;
+    if found && quiet {
+        return Ok(());
+    }
+    out.extend_from_slice(if found { b"HD" } else { b"NF" });
+    write_meta_flags(out, &returned);
+    Ok(())
+}
+
+/// Runs one request, appending its reply (if any) to `out`. Errors are the
+/// reply line to send instead.
+async fn execute_memcache(
+    request: Request,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+    out: &mut BytesMut,
+) -> Result<(), String> {
+    let Request { args, data } = request;
+    let Some(name) = args.first() else {
+        return Err("ERROR".into());
+    };
+
+    match &name[..] {
+        b"get" | b"gets" => {
+            if args.len() < 2 {
+                return Err("ERROR".into());
+            }
+            let with_cas = &name[..] == b"gets";
+            for arg in &args[1..] {
+                let key = key(arg)?;
+                if let Some((flags, value)) = get(key.clone(), l1_cache, redis_sync).await? {
+                    let _ = write!(out, "VALUE {} {} {}", key, flags, value.len());
+                    if with_cas {
+                        let _ = write!(out, " {}", cas(&value));
+                    }
+                    out.extend_from_slice(b"\r\n");
+                    out.extend_from_slice(&value);
+                    out.extend_from_slice(b"\r\n");
+                }
+            }
+            out.extend_from_slice(b"END\r\n");
+        }
+        command @ (b"set" | b"add" | b"replace") => {
+            let quiet = noreply(&args, 5)?;
+            let key = key(&args[1])?;
+            let flags = parse(&args[2])?;
+            let expiry = expiry(parse(&args[3])?);
+            let value = with_flags(data.ok_or(BAD_FORMAT)?, flags);
+            let condition = match command {
+                b"add" => Some(SetCondition::IfAbsent),
+                b"replace" => Some(SetCondition::IfPresent),
+                _ => None,
+            };
+
+            let stored = store(key, value, expiry, condition, l1_cache, redis_sync).await?;
+            if !quiet {
+                out.extend_from_slice(if stored {
+                    b"STORED\r\n"
+                } else {
+                    b"NOT_STORED\r\n"
+                });
+            }
+        }
+        b"delete" => {
+            let quiet = noreply(&args, 2)?;
+            let found = execute_delete(key(&args[1])?, l1_cache, redis_sync).await;
+            if !quiet {
+                out.extend_from_slice(if found {
+                    b"DELETED\r\n"
+                } else {
+                    b"NOT_FOUND\r\n"
+                });
+            }
+        }
+        b"touch" => {
+            let quiet = noreply(&args, 3)?;
+            let key = key(&args[1])?;
+            let expiry = expiry(parse(&args[2])?);
+            let found = touch(key, expiry, l1_cache, redis_sync).await?;
+            if !quiet {
+                out.extend_from_slice(if found {
+                    b"TOUCHED\r\n"
+                } else {
+                    b"NOT_FOUND\r\n"
+                });
+            }
+        }
+        b"mg" => meta_get(&args, l1_cache, redis_sync, out).await?,
+        b"ms" => meta_set(&args, data, l1_cache, redis_sync, out).await?,
+        b"md" => meta_delete(&args, l1_cache, redis_sync, out).await?,
+        b"mn" => out.extend_from_slice(b"MN\r\n"),
+        b"version" => {
+            let _ = write!(out, "VERSION synapse-{}\r\n", env!("CARGO_PKG_VERSION"));
+        }
+        _ => return Err("ERROR".into()),
+    }
+    Ok(())
+}
+
+/// Serves memcached clients on one connection, answering requests in order.
+#[instrument(
+    name = "connection",
+    skip_all,
+    fields(conn_id = next_connection_id(), protocol = "memcache")
+)]
+pub(crate) async fn handle_memcache_stream<S>(
+    stream: S,
+    l1_cache: L1Cache,
+    redis_sync: SharedRedisSync,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _connection = METRICS.connection("memcache");
+    let mut framed = Framed::new(stream, MemcacheCodec);
+
+    while let Some(request) = framed.next().await {
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = framed.send(Bytes::from(format!("{}\r\n", err))).await;
+                break;
+            }
+        };
+        if request
+            .args
+            .first()
+            .is_some_and(|name| name == &b"quit"[..])
+        {
+            break;
+        }
+
+        let mut out = BytesMut::new();
+        if let Err(line) =
+            execute_memcache(request, &l1_cache, redis_sync.load().as_deref(), &mut out).await
+        {
+            out.clear();
+            out.extend_from_slice(line.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        if !out.is_empty() && framed.send(out.freeze()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Serves the memcached text and meta protocols on `memcache.addr`,
+/// either `host:port` or `unix:/path/to.sock`. Without it the memcached
+/// listener stays off.
+pub async fn run_memcache(
+    config: ListenerConfig,
+    l1_cache: L1Cache,
+    shutdown: CancellationToken,
+    redis_sync: SharedRedisSync,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(addr) = config.addr else {
+        shutdown.cancelled().await;
+        return Ok(());
+    };
+
+    Listener::bind(&addr, "memcached")
+        .await?
+        .serve(
+            "memcached",
+            l1_cache,
+            shutdown,
+            redis_sync,
+            handle_memcache_stream,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expiry, MemcacheCodec, Request, cas, expiry, handle_memcache_stream};
+    use crate::redis::client::SharedRedisSync;
+    use bytes::{Bytes, BytesMut};
+    use synapse_core::{CacheResponce, L1Cache};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex};
+    use tokio_util::codec::Decoder;
+
+    /// Sends `request` and reads until `expected.len()` bytes have arrived.
+    async fn roundtrip(client: &mut DuplexStream, request: &[u8], expected: &[u8]) {
+        client.write_all(request).await.unwrap();
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&response),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    fn connect(cache: &L1Cache) -> DuplexStream {
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(handle_memcache_stream(
+            server,
+            cache.clone(),
+            SharedRedisSync::default(),
+        ));
+        client
+    }
+
+    #[test]
+    fn decode_waits_for_data_block() {
+        let mut codec = MemcacheCodec;
+        let mut buf = BytesMut::from(&b"set a 0 0 5\r\nhel"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"lo\r\nget a\r\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Request {
+                args: [&b"set"[..], b"a", b"0", b"0", b"5"]
+                    .into_iter()
+                    .map(Bytes::from_static)
+                    .collect(),
+                data: Some(Bytes::from_static(b"hello")),
+            })
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Request {
+                args: vec![Bytes::from_static(b"get"), Bytes::from_static(b"a")],
+                data: None,
+            })
+        );
+
+        let mut buf = BytesMut::from(&b"set a 0 0 2\r\nabcd\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn decoded_data_does_not_pin_the_read_buffer() {
+        let mut codec = MemcacheCodec;
+        let mut buf = BytesMut::with_capacity(4096);
+        buf.extend_from_slice(b"set a 0 0 5\r\nhello\r\n");
+        let read_buffer = buf.as_ptr_range();
+
+        let request = codec.decode(&mut buf).unwrap().expect("request");
+        let data = request.data.expect("data block");
+        assert_eq!(data, &b"hello"[..]);
+        assert!(!read_buffer.contains(&data.as_ptr()));
+    }
+
+    #[test]
+    fn exptime_relative_absolute_and_expired() {
+        assert_eq!(expiry(0), Expiry::Never);
+        assert_eq!(expiry(-1), Expiry::Expired);
+        assert_eq!(expiry(60), Expiry::After(60));
+        assert_eq!(expiry(60 * 60 * 24 * 30 + 1), Expiry::Expired);
+    }
+
+    #[tokio::test]
+    async fn memcache_storage_commands() {
+        let cache = L1Cache::new(10);
+        let mut client = connect(&cache);
+
+        roundtrip(&mut client, b"get a b\r\n", b"END\r\n").await;
+        roundtrip(&mut client, b"replace a 0 0 1\r\n1\r\n", b"NOT_STORED\r\n").await;
+        roundtrip(&mut client, b"add a 0 0 1\r\n1\r\n", b"STORED\r\n").await;
+        roundtrip(&mut client, b"add a 0 0 1\r\n2\r\n", b"NOT_STORED\r\n").await;
+        roundtrip(&mut client, b"replace a 0 60 2\r\n22\r\n", b"STORED\r\n").await;
+        roundtrip(&mut client, b"set b 0 0 3\r\nbee\r\n", b"STORED\r\n").await;
+        roundtrip(
+            &mut client,
+            b"get a x b\r\n",
+            b"VALUE a 0 2\r\n22\r\nVALUE b 0 3\r\nbee\r\nEND\r\n",
+        )
+        .await;
+        assert!(matches!(cache.ttl("a").await, CacheResponce::Ttl(Some(_))));
+
+        let gets = format!("VALUE b 0 3 {}\r\nbee\r\nEND\r\n", cas(b"bee"));
+        roundtrip(&mut client, b"gets b\r\n", gets.as_bytes()).await;
+
+        roundtrip(&mut client, b"set c 0 -1 1\r\nx\r\n", b"STORED\r\n").await;
+        roundtrip(&mut client, b"bogus\r\n", b"ERROR\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn memcache_client_flags_round_trip() {
+        let cache = L1Cache::new(10);
+        let mut client = connect(&cache);
+
+        roundtrip(&mut client, b"set a 4 0 2\r\n42\r\n", b"STORED\r\n").await;
+        roundtrip(&mut client, b"ms b 3 F16\r\nzip\r\n", b"HD\r\n").await;
+        roundtrip(&mut client, b"set c 0 0 5\r\nplain\r\n", b"STORED\r\n").await;
+        roundtrip(
+            &mut client,
+            b"get a b c\r\n",
+            b"VALUE a 4 2\r\n42\r\nVALUE b 16 3\r\nzip\r\nVALUE c 0 5\r\nplain\r\nEND\r\n",
+        )
+        .await;
+        let gets = format!("VALUE a 4 2 {}\r\n42\r\nEND\r\n", cas(b"42"));
+        roundtrip(&mut client, b"gets a\r\n", gets.as_bytes()).await;
+        roundtrip(&mut client, b"mg b v f s\r\n", b"VA 3 f16 s3\r\nzip\r\n").await;
+        assert!(matches!(cache.get("c").await, CacheResponce::Hit(v) if v == "plain"));
+
+        // A flagless value that looks like a flagged one keeps its bytes.
+        let mut set = b"set d 0 0 12\r\n\0mcflags".to_vec();
+        set.extend_from_slice(b"\0\0\0\x07\r\n");
+        roundtrip(&mut client, &set, b"STORED\r\n").await;
+        let mut expected = b"VALUE d 0 12\r\n\0mcflags".to_vec();
+        expected.extend_from_slice(b"\0\0\0\x07\r\nEND\r\n");
+        roundtrip(&mut client, b"get d\r\n", &expected).await;
+    }
+
+    #[tokio::test]
+    async fn memcache_delete_touch_noreply() {
+        let cache = L1Cache::new(10);
+        let mut client = connect(&cache);
+
+        roundtrip(
+            &mut client,
+            b"set a 0 30 1\r\n1\r\nset b 0 0 1 noreply\r\n2\r\n",
+            b"STORED\r\n",
+        )
+        .await;
+        roundtrip(&mut client, b"touch a 0\r\n", b"TOUCHED\r\n").await;
+        assert!(matches!(cache.ttl("a").await, CacheResponce::Ttl(None)));
+        roundtrip(&mut client, b"touch nope 10\r\n", b"NOT_FOUND\r\n").await;
+
+        roundtrip(
+            &mut client,
+            b"delete b noreply\r\ndelete a\r\n",
+            b"DELETED\r\n",
+        )
+        .await;
+        roundtrip(&mut client, b"delete a\r\n", b"NOT_FOUND\r\n").await;
+        assert!(!cache.contains("b").await);
+    }
+
+    #[tokio::test]
+    async fn memcache_meta_commands() {
+        let cache = L1Cache::new(10);
+        let mut client = connect(&cache);
+
+        roundtrip(&mut client, b"mg a v\r\n", b"EN\r\n").await;
+        roundtrip(&mut client, b"mg a v q\r\nmn\r\n", b"MN\r\n").await;
+        roundtrip(&mut client, b"ms a 2 T60 O7\r\nhi\r\n", b"HD O7\r\n").await;
+        roundtrip(&mut client, b"ms a 2 ME\r\nho\r\n", b"NS\r\n").await;
+        roundtrip(
+            &mut client,
+            b"mg a v t f k s\r\n",
+            b"VA 2 t60 f0 ka s2\r\nhi\r\n",
+        )
+        .await;
+        roundtrip(&mut client, b"mg a k\r\n", b"HD ka\r\n").await;
+        roundtrip(&mut client, b"ms b 1 MR q\r\nx\r\nmn\r\n", b"NS\r\nMN\r\n").await;
+        roundtrip(&mut client, b"md a q\r\nmd a\r\n", b"NF\r\n").await;
+        roundtrip(&mut client, b"mg a X\r\n", b"CLIENT_ERROR invalid flag\r\n").await;
+    }
+}
@@ -1,2 +1,34 @@
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::time::sleep;
+use tracing::warn;
+
 pub mod grpc;
+mod listener;
+pub mod memcache;
+pub mod resp;
+pub mod tcp;
 pub mod uds;
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Numbers connections across every front end, so the `conn_id` on a log
+/// line identifies one client whichever protocol it speaks.
+pub(crate) fn next_connection_id() -> u64 {
+    NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Pause after a failed `accept`, so running out of file descriptors doesn't
+/// spin the accept loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Logs a connection that could not be accepted or set up. Only that
+/// connection is dropped; the listener keeps serving.
+pub(crate) async fn connection_failed(protocol: &str, err: io::Error) {
+    warn!(protocol, error = %err, "Cannot accept connection");
+    sleep(ACCEPT_BACKOFF).await;
+}
@@ -0,0 +1,800 @@
+use std::{error::Error, fmt::Write, io, ops::Range};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use synapse_core::{
+    CacheCommand, CacheResponce, ExpirationMode, L1Cache, MAX_FRAME_LENGTH, SetCondition,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::{
+    codec::{Decoder, Encoder, Framed},
+    sync::CancellationToken,
+};
+use tracing::{Span, field, instrument};
+
+use crate::config::ListenerConfig;
+use crate::metrics::METRICS;
+use crate::redis::client::{RedisSync, SharedRedisSync};
+use crate::server::listener::Listener;
+use crate::server::next_connection_id;
+use crate::server::uds::{execute_command, execute_delete, execute_set_if};
+
+/// Version reported to clients that check it before using newer commands.
+const REDIS_VERSION: &str = "7.2.0";
+/// Longest `*<count>` / `$<len>` header or inline command line accepted.
+const MAX_INLINE_LENGTH: usize = 64 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+/// Arguments allocated for up front; the header's count is only a claim.
+const PREALLOCATED_ARGS: usize = 64;
+
+/// A reply in the RESP2/RESP3 wire format.
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK")
+    }
+
+    fn bulk(value: impl Into<Bytes>) -> Self {
+        Reply::Bulk(value.into())
+    }
+
+    fn write(&self, dst: &mut BytesMut, resp3: bool) {
+        match self {
+            Reply::Simple(s) => {
+                dst.put_u8(b'+');
+                dst.extend_from_slice(s.as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Reply::Error(err) => {
+                dst.put_u8(b'-');
+                dst.extend_from_slice(err.replace(['\r', '\n'], " ").as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Reply::Integer(n) => {
+                let _ = write!(dst, ":{}\r\n", n);
+            }
+            Reply::Bulk(value) => {
+                let _ = write!(dst, "${}\r\n", value.len());
+                dst.extend_from_slice(value);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if resp3 => dst.extend_from_slice(b"_\r\n"),
+            Reply::Null => dst.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                let _ = write!(dst, "*{}\r\n", items.len());
+                for item in items {
+                    item.write(dst, resp3);
+                }
+            }
+            // RESP2 has no maps; they are sent as flat key/value arrays.
+            Reply::Map(pairs) => {
+                if resp3 {
+                    let _ = write!(dst, "%{}\r\n", pairs.len());
+                } else {
+                    let _ = write!(dst, "*{}\r\n", pairs.len() * 2);
+                }
+                for (key, value) in pairs {
+                    key.write(dst, resp3);
+                    value.write(dst, resp3);
+                }
+            }
+        }
+    }
+}
+
+/// Splits the byte stream into commands (multibulk or inline) and encodes
+/// replies in the protocol version chosen with `HELLO`.
+#[derive(Debug, Default)]
+struct RespCodec {
+    resp3: bool,
+    /// The multibulk being received, so each read resumes where the last one
+    /// stopped instead of parsing the whole command again.
+    partial: Option<PartialMultibulk>,
+}
+
+#[derive(Debug)]
+struct PartialMultibulk {
+    count: usize,
+    /// Where the next bulk string header starts.
+    pos: usize,
+    ranges: Vec<Range<usize>>,
+}
+
+fn protocol_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", message.into()),
+    )
+}
+
+/// Reads a `<prefix><number>\r\n` header starting at `pos`, moving `pos` past it.
+fn read_header(src: &[u8], pos: &mut usize, prefix: u8) -> io::Result<Option<i64>> {
+    let Some(&first) = src.get(*pos) else {
+        return Ok(None);
+    };
+    if first != prefix {
+        return Err(protocol_error(format!(
+            "expected '{}', got '{}'",
+            prefix as char,
+            first.escape_ascii()
+        )));
+    }
+    let Some(end) = src[*pos..].windows(2).position(|w| w == b"\r\n") else {
+        if src.len() - *pos > MAX_INLINE_LENGTH {
+            return Err(protocol_error("too big header"));
+        }
+        return Ok(None);
+    };
+    let number = std::str::from_utf8(&src[*pos + 1..*pos + end])
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    *pos += end + 2;
+    Ok(Some(number))
+}
+
+impl RespCodec {
+    fn decode_multibulk(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<Bytes>>> {
+        let mut partial = match self.partial.take() {
+            Some(partial) => partial,
+            None => {
+                let mut pos = 0;
+                let Some(count) = read_header(src, &mut pos, b'*')? else {
+                    return Ok(None);
+                };
+                if count > MAX_ARGS as i64 {
+                    return Err(protocol_error("invalid multibulk length"));
+                }
+                let count = count.max(0) as usize;
+                PartialMultibulk {
+                    count,
+                    pos,
+                    ranges: Vec::with_capacity(count.min(PREALLOCATED_ARGS)),
+                }
+            }
+        };
+
+        while partial.ranges.len() < partial.count {
+            let mut pos = partial.pos;
+            let Some(len) = read_header(src, &mut pos, b'$')? else {
+                self.partial = Some(partial);
+                return Ok(None);
+            };
+            if len < 0 || len as usize > MAX_FRAME_LENGTH {
+                return Err(protocol_error("invalid bulk length"));
+            }
+            let end = pos + len as usize;
+            if src.len() < end + 2 {
+                src.reserve(end + 2 - src.len());
+                self.partial = Some(partial);
+                return Ok(None);
+            }
+            if &src[end..end + 2] != b"\r\n" {
+                return Err(protocol_error("expected CRLF after bulk string"));
+            }
+            partial.ranges.push(pos..end);
+            partial.pos = end + 2;
+        }
+
+        // Copied, since a cached slice would pin the whole read buffer.
+        let frame = src.split_to(partial.pos);
+        Ok(Some(
+            partial
+                .ranges
+                .into_iter()
+                .map(|r| Bytes::copy_from_slice(&frame[r]))
+                .collect(),
+        ))
+    }
+
+    /// Space separated commands as typed into `telnet` or `nc`.
+    fn decode_inline(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<Bytes>>> {
+        let Some(end) = src.iter().position(|&b| b == b'\n') else {
+            if src.len() > MAX_INLINE_LENGTH {
+                return Err(protocol_error("too big inline request"));
+            }
+            return Ok(None);
+        };
+        let line = src.split_to(end + 1);
+        Ok(Some(
+            line[..]
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(Bytes::copy_from_slice)
+                .collect(),
+        ))
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Bytes>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<Bytes>>> {
+        loop {
+            let Some(&first) = src.first() else {
+                return Ok(None);
+            };
+            let args = if first == b'*' {
+                self.decode_multibulk(src)?
+            } else {
+                self.decode_inline(src)?
+            };
+            // Empty multibulks and blank lines are skipped, as Redis does.
+            match args {
+                Some(args) if args.is_empty() => continue,
+                args => return Ok(args),
+            }
+        }
+    }
+}
+
+impl Encoder<Reply> for RespCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, reply: Reply, dst: &mut BytesMut) -> io::Result<()> {
+        reply.write(dst, self.resp3);
+        Ok(())
+    }
+}
+
+fn wrong_arity(name: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".into())
+}
+
+fn parse_int(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| Reply::Error("ERR value is not an integer or out of range".into()))
+}
+
+/// L1 keys are strings, so binary keys that are not UTF-8 are refused.
+fn key(arg: &Bytes) -> Result<String, Reply> {
+    String::from_utf8(arg.to_vec()).map_err(|_| Reply::Error("ERR keys must be valid UTF-8".into()))
+}
+
+fn keys(args: &[Bytes]) -> Result<Vec<String>, Reply> {
+    args.iter().map(key).collect()
+}
+
+async fn execute(
+    cmd: CacheCommand,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> Result<CacheResponce, Reply> {
+    match execute_command(cmd, None, l1_cache, redis_sync).await {
+        CacheResponce::Error(err) => Err(Reply::Error(format!("ERR {}", err))),
+        response => Ok(response),
+    }
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX | XX]`. L1 TTLs have
+/// second granularity, so `PX` is rounded up to whole seconds.
+async fn set(
+    args: &[Bytes],
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> Result<Reply, Reply> {
+    let key = key(&args[1])?;
+    let value = args[2].clone();
+    let mut ttl_ms = None;
+    let mut condition = None;
+
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        match &option.to_ascii_uppercase()[..] {
+            unit @ (b"EX" | b"PX") if ttl_ms.is_none() => {
+                let ttl = parse_int(options.next().ok_or_else(syntax_error)?)?;
+                let scale = if unit == b"EX" { 1000 } else { 1 };
+                ttl_ms = Some(
+                    u64::try_from(ttl)
+                        .ok()
+                        .filter(|&ttl| ttl > 0)
+                        .and_then(|ttl| ttl.checked_mul(scale))
+                        .ok_or_else(|| {
+                            Reply::Error("ERR invalid expire time in 'set' command".into())
+                        })?,
+                );
+            }
+            b"NX" if condition.is_none() => condition = Some(SetCondition::IfAbsent),
+            b"XX" if condition.is_none() => condition = Some(SetCondition::IfPresent),
+            _ => return Err(syntax_error()),
+        }
+    }
+    let ttl_secs = ttl_ms.map(|ttl_ms: u64| ttl_ms.div_ceil(1000));
+
+    let Some(condition) = condition else {
+        let cmd = CacheCommand::Set {
+            key,
+            value,
+            ttl_secs,
+            mode: ExpirationMode::Absolute,
+        };
+        execute(cmd, l1_cache, redis_sync).await?;
+        return Ok(Reply::ok());
+    };
+
+    // NX/XX are decided by this node's L1 only.
+    if !execute_set_if(key, value, ttl_secs, condition, l1_cache, redis_sync).await {
+        return Ok(Reply::Null);
+    }
+    Ok(Reply::ok())
+}
+
+fn info(l1_cache: &L1Cache) -> Reply {
+    let mut info = String::new();
+    let _ = write!(
+        info,
+        "# Server\r\nredis_version:{}\r\nsynapse_version:{}\r\nredis_mode:standalone\r\n\r\n\
+         # Persistence\r\nloading:0\r\n\r\n\
+         # Synapse\r\nl1_entries:{}\r\nl1_weighted_size:{}\r\n\r\n\
+         # Keyspace\r\ndb0:keys={}\r\n",
+        REDIS_VERSION,
+        env!("CARGO_PKG_VERSION"),
+        l1_cache.entry_count(),
+        l1_cache.weighted_size(),
+        l1_cache.entry_count(),
+    );
+    Reply::bulk(info)
+}
+
+/// Runs one data command. Connection-level commands (`HELLO`, `QUIT`) are
+/// handled by `handle_resp_stream`.
+async fn execute_resp(
+    name: &[u8],
+    args: &[Bytes],
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> Result<Reply, Reply> {
+    let arity_ok = match name {
+        b"GET" | b"TTL" => args.len() == 2,
+        b"SET" => args.len() >= 3,
+        b"DEL" | b"MGET" | b"EXISTS" => args.len() >= 2,
+        b"MSET" => args.len() >= 3 && args.len() % 2 == 1,
+        b"PING" => args.len() <= 2,
+        b"SELECT" => args.len() == 2,
+        b"CLIENT" => args.len() >= 2,
+        _ => true,
+    };
+    if !arity_ok {
+        return Err(wrong_arity(&String::from_utf8_lossy(name)));
+    }
+
+    match name {
+        b"GET" => match execute(
+            CacheCommand::Get {
+                key: key(&args[1])?,
+            },
+            l1_cache,
+            redis_sync,
+        )
+        .await?
+        {
+            CacheResponce::Hit(value) => Ok(Reply::Bulk(value)),
+            _ => Ok(Reply::Null),
+        },
+        b"SET" => set(args, l1_cache, redis_sync).await,
+        b"DEL" => {
+            let mut deleted = 0;
+            for key in keys(&args[1..])? {
+                if execute_delete(key, l1_cache, redis_sync).await {
+                    deleted += 1;
+                }
+            }
+            Ok(Reply::Integer(deleted))
+        }
+        b"MGET" => {
+            let keys = keys(&args[1..])?;
+            match execute(CacheCommand::MGet { keys }, l1_cache, redis_sync).await? {
+                CacheResponce::Values(values) => Ok(Reply::Array(
+                    values
+                        .into_iter()
+                        .map(|value| value.map_or(Reply::Null, Reply::Bulk))
+                        .collect(),
+                )),
+                _ => Err(Reply::Error("ERR unexpected response".into())),
+            }
+        }
+        b"MSET" => {
+            let entries = args[1..]
+                .chunks(2)
+                .map(|pair| Ok((key(&pair[0])?, pair[1].clone())))
+                .collect::<Result<_, Reply>>()?;
+            let cmd = CacheCommand::MSet {
+                entries,
+                ttl_secs: None,
+            };
+            execute(cmd, l1_cache, redis_sync).await?;
+            Ok(Reply::ok())
+        }
+        b"EXISTS" => {
+            let mut found = 0;
+            for key in keys(&args[1..])? {
+                let cmd = CacheCommand::Ttl { key };
+                if let CacheResponce::Ttl(_) = execute(cmd, l1_cache, redis_sync).await? {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        // Rounded to the nearest second; -1 without a TTL and -2 when missing.
+        b"TTL" => match execute(
+            CacheCommand::Ttl {
+                key: key(&args[1])?,
+            },
+            l1_cache,
+            redis_sync,
+        )
+        .await?
+        {
+            CacheResponce::Ttl(Some(ms)) => Ok(Reply::Integer(((ms + 500) / 1000) as i64)),
+            CacheResponce::Ttl(None) => Ok(Reply::Integer(-1)),
+            _ => Ok(Reply::Integer(-2)),
+        },
+        b"PING" => match args.get(1) {
+            Some(message) => Ok(Reply::Bulk(message.clone())),
+            None => Ok(Reply::Simple("PONG")),
+        },
+        b"INFO" => Ok(info(l1_cache)),
+        b"SELECT" => match parse_int(&args[1])? {
+            0 => Ok(Reply::ok()),
+            _ => Err(Reply::Error("ERR DB index is out of range".into())),
+        },
+        // Client libraries name their connections on connect; there is nothing to track.
+        b"CLIENT" => match &args[1].to_ascii_uppercase()[..] {
+            b"SETNAME" | b"SETINFO" => Ok(Reply::ok()),
+            _ => Err(Reply::Error(format!(
+                "ERR unknown subcommand '{}'",
+                String::from_utf8_lossy(&args[1])
+            ))),
+        },
+        _ => Err(Reply::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(&args[0])
+        ))),
+    }
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME name]]`. Switches the
+/// connection to RESP3 when asked and describes the server.
+fn hello(args: &[Bytes], codec: &mut RespCodec, conn_id: u64) -> Result<Reply, Reply> {
+    let mut options = args.iter().skip(2);
+    while let Some(option) = options.next() {
+        if option.eq_ignore_ascii_case(b"AUTH") {
+            // There are no users to check the credentials against; accepting
+            // them would let a client believe it had authenticated.
+            return Err(Reply::Error(
+                "ERR AUTH is not supported, this server has no authentication".into(),
+            ));
+        }
+        if !option.eq_ignore_ascii_case(b"SETNAME") || options.next().is_none() {
+            return Err(Reply::Error(format!(
+                "ERR Syntax error in HELLO option '{}'",
+                String::from_utf8_lossy(option)
+            )));
+        }
+    }
+
+    if let Some(protover) = args.get(1) {
+        match parse_int(protover) {
+            Ok(2) => codec.resp3 = false,
+            Ok(3) => codec.resp3 = true,
+            Ok(_) => {
+                return Err(Reply::Error("NOPROTO unsupported protocol version".into()));
+            }
+            Err(_) => {
+                return Err(Reply::Error(
+                    "ERR Protocol version is not an integer or out of range".into(),
+                ));
+            }
+        }
+    }
+
+    let proto = if codec.resp3 { 3 } else { 2 };
+    Ok(Reply::Map(vec![
+        (Reply::bulk("server"), Reply::bulk("redis")),
+        (Reply::bulk("version"), Reply::bulk(REDIS_VERSION)),
+        (Reply::bulk("proto"), Reply::Integer(proto)),
+        (Reply::bulk("id"), Reply::Integer(conn_id as i64)),
+        (Reply::bulk("mode"), Reply::bulk("standalone")),
+        (Reply::bulk("role"), Reply::bulk("master")),
+        (Reply::bulk("modules"), Reply::Array(Vec::new())),
+    ]))
+}
+
+/// Serves Redis clients on one connection. Commands are answered in order,
+/// so pipelining works as with Redis itself.
+#[instrument(name = "connection", skip_all, fields(conn_id = field::Empty, protocol = "resp"))]
+pub(crate) async fn handle_resp_stream<S>(stream: S, l1_cache: L1Cache, redis_sync: SharedRedisSync)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _connection = METRICS.connection("resp");
+    let conn_id = next_connection_id();
+    Span::current().record("conn_id", conn_id);
+    let mut framed = Framed::new(stream, RespCodec::default());
+
+    while let Some(args) = framed.next().await {
+        let args = match args {
+            Ok(args) => args,
+            Err(err) => {
+                let _ = framed.send(Reply::Error(format!("ERR {}", err))).await;
+                break;
+            }
+        };
+
+        let name = args[0].to_ascii_uppercase();
+        let reply = match &name[..] {
+            b"HELLO" => hello(&args, framed.codec_mut(), conn_id),
+            b"QUIT" => {
+                let _ = framed.send(Reply::ok()).await;
+                break;
+            }
+            _ => execute_resp(&name, &args, &l1_cache, redis_sync.load().as_deref()).await,
+        };
+
+        if framed.send(reply.unwrap_or_else(|err| err)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Serves the Redis protocol on `resp.addr`, either `host:port` or
+/// `unix:/path/to.sock`. Without it the RESP listener stays off.
+pub async fn run_resp(
+    config: ListenerConfig,
+    l1_cache: L1Cache,
+    shutdown: CancellationToken,
+    redis_sync: SharedRedisSync,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(addr) = config.addr else {
+        shutdown.cancelled().await;
+        return Ok(());
+    };
+
+    Listener::bind(&addr, "RESP")
+        .await?
+        .serve("RESP", l1_cache, shutdown, redis_sync, handle_resp_stream)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PREALLOCATED_ARGS, Reply, RespCodec, handle_resp_stream};
+    use crate::redis::client::SharedRedisSync;
+    use bytes::{Bytes, BytesMut};
+    use synapse_core::{CacheResponce, L1Cache};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex};
+    use tokio_util::codec::Decoder;
+
+    fn encode(reply: Reply, resp3: bool) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        reply.write(&mut buf, resp3);
+        buf.to_vec()
+    }
+
+    /// Sends `request` and reads until `expected.len()` bytes have arrived.
+    async fn roundtrip(client: &mut DuplexStream, request: &[u8], expected: &[u8]) {
+        client.write_all(request).await.unwrap();
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&response),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    fn connect(cache: &L1Cache) -> DuplexStream {
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(handle_resp_stream(
+            server,
+            cache.clone(),
+            SharedRedisSync::default(),
+        ));
+        client
+    }
+
+    #[test]
+    fn decode_multibulk_waits_for_full_command() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$5\r\nal"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"pha\r\n*1\r\n$4\r\nPING\r\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(vec![
+                Bytes::from_static(b"GET"),
+                Bytes::from_static(b"alpha")
+            ])
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(vec![Bytes::from_static(b"PING")])
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_inline_and_errors() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"\r\nset  a 1\r\n"[..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(vec![
+                Bytes::from_static(b"set"),
+                Bytes::from_static(b"a"),
+                Bytes::from_static(b"1")
+            ])
+        );
+
+        let mut buf = BytesMut::from(&b"*1\r\n:3\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"*1\r\n$-5\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn encode_null_and_map_per_protocol() {
+        assert_eq!(encode(Reply::Null, false), b"$-1\r\n");
+        assert_eq!(encode(Reply::Null, true), b"_\r\n");
+
+        let map = Reply::Map(vec![(Reply::bulk("proto"), Reply::Integer(3))]);
+        assert_eq!(encode(map.clone(), false), b"*2\r\n$5\r\nproto\r\n:3\r\n");
+        assert_eq!(encode(map, true), b"%1\r\n$5\r\nproto\r\n:3\r\n");
+    }
+
+    #[tokio::test]
+    async fn resp_get_set_del_exists() {
+        let cache = L1Cache::new(10);
+        let mut client = connect(&cache);
+
+        roundtrip(&mut client, b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n", b"$-1\r\n").await;
+        roundtrip(
+            &mut client,
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$2\r\nv1\r\n",
+            b"+OK\r\n",
+        )
+        .await;
+        roundtrip(
+            &mut client,
+            b"*2\r\n$3\r\nget\r\n$1\r\na\r\n",
+            b"$2\r\nv1\r\n",
+        )
+        .await;
+        roundtrip(
+            &mut client,
+            b"*4\r\n$6\r\nEXISTS\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\na\r\n",
+            b":2\r\n",
+        )
+        .await;
+        roundtrip(
+            &mut client,
+            b"*3\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n",
+            b":1\r\n",
+        )
+        .await;
+        assert!(matches!(cache.get("a").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn resp_set_options() {
+        let cache = L1Cache::new(10);
+        let mut client = connect(&cache);
+
+        roundtrip(&mut client, b"SET a 1 XX\r\n", b"$-1\r\n").await;
+        roundtrip(&mut client, b"SET a 1 NX EX 30\r\n", b"+OK\r\n").await;
+        roundtrip(&mut client, b"SET a 2 NX\r\n", b"$-1\r\n").await;
+        roundtrip(&mut client, b"TTL a\r\n", b":30\r\n").await;
+        roundtrip(&mut client, b"SET a 3 XX PX 1500\r\n", b"+OK\r\n").await;
+        roundtrip(&mut client, b"TTL a\r\n", b":2\r\n").await;
+        roundtrip(&mut client, b"SET a 4\r\n", b"+OK\r\n").await;
+        roundtrip(&mut client, b"TTL a\r\n", b":-1\r\n").await;
+        roundtrip(&mut client, b"TTL nope\r\n", b":-2\r\n").await;
+        roundtrip(&mut client, b"GET a\r\n", b"$1\r\n4\r\n").await;
+
+        roundtrip(&mut client, b"SET a 5 NX XX\r\n", b"-ERR syntax error\r\n").await;
+        roundtrip(
+            &mut client,
+            b"SET a 5 EX 0\r\n",
+            b"-ERR invalid expire time in 'set' command\r\n",
+        )
+        .await;
+        roundtrip(
+            &mut client,
+            b"SET a 5 EX soon\r\n",
+            b"-ERR value is not an integer or out of range\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn resp_mset_mget_pipelined() {
+        let cache = L1Cache::new(10);
+        let mut client = connect(&cache);
+
+        roundtrip(
+            &mut client,
+            b"MSET a 1 b 2\r\nMGET a x b\r\nPING\r\nPING hi\r\n",
+            b"+OK\r\n*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n2\r\n+PONG\r\n$2\r\nhi\r\n",
+        )
+        .await;
+        roundtrip(
+            &mut client,
+            b"MSET a 1 b\r\n",
+            b"-ERR wrong number of arguments for 'mset' command\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn resp_hello_switches_to_resp3() {
+        let cache = L1Cache::new(10);
+        let mut client = connect(&cache);
+
+        client.write_all(b"HELLO 3\r\n").await.unwrap();
+        let mut prefix = [0; 4];
+        client.read_exact(&mut prefix).await.unwrap();
+        assert_eq!(&prefix, b"%7\r\n");
+        let mut rest = vec![0; 1024];
+        let _ = client.read(&mut rest).await.unwrap();
+
+        roundtrip(&mut client, b"GET a\r\n", b"_\r\n").await;
+        roundtrip(
+            &mut client,
+            b"HELLO 4\r\n",
+            b"-NOPROTO unsupported protocol version\r\n",
+        )
+        .await;
+        roundtrip(
+            &mut client,
+            b"HELLO 2 AUTH default secret\r\n",
+            b"-ERR AUTH is not supported, this server has no authentication\r\n",
+        )
+        .await;
+        // The rejected HELLO did not switch back to RESP2.
+        roundtrip(&mut client, b"GET a\r\n", b"_\r\n").await;
+        roundtrip(&mut client, b"QUIT\r\n", b"+OK\r\n").await;
+        assert_eq!(client.read(&mut rest).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn decode_multibulk_resumes_and_copies_values() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::with_capacity(4096);
+        buf.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$5\r\nhel");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        let partial = codec.partial.as_ref().expect("partial multibulk");
+        assert_eq!(partial.ranges.len(), 2);
+
+        buf.extend_from_slice(b"lo\r\n");
+        let read_buffer = buf.as_ptr_range();
+        let args = codec.decode(&mut buf).unwrap().expect("command");
+        assert_eq!(args[2], &b"hello"[..]);
+        assert!(!read_buffer.contains(&args[2].as_ptr()));
+        assert!(codec.partial.is_none());
+
+        let mut buf = BytesMut::from(&b"*1000000\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        let partial = codec.partial.as_ref().expect("partial multibulk");
+        assert!(partial.ranges.capacity() <= PREALLOCATED_ARGS);
+    }
+}
@@ -0,0 +1,269 @@
+use std::{error::Error, net::SocketAddr, sync::Arc};
+
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use synapse_core::L1Cache;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::config::TcpConfig;
+use crate::redis::client::SharedRedisSync;
+use crate::server::{connection_failed, uds::handle_uds_stream};
+
+/// Builds a TLS acceptor from PEM files. With `client_ca` set, clients must
+/// present a certificate signed by one of the CAs in that file.
+pub fn load_tls_acceptor(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+    let provider = Arc::new(ring::default_provider());
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(client_ca_path)? {
+                roots.add(cert?)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves the frame protocol on `tcp.addr`. TLS is enabled by `tls_cert` and
+/// `tls_key`, and `tls_client_ca` additionally requires client certificates.
+/// Without an address the TCP listener stays off.
+pub async fn run_tcp(
+    config: TcpConfig,
+    l1_cache: L1Cache,
+    shutdown: CancellationToken,
+    redis_sync: SharedRedisSync,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(addr) = config.addr else {
+        shutdown.cancelled().await;
+        return Ok(());
+    };
+    let addr: SocketAddr = addr.parse()?;
+
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls_acceptor(
+            cert,
+            key,
+            config.tls_client_ca.as_deref(),
+        )?),
+        (None, None) if config.tls_client_ca.is_none() => None,
+        _ => {
+            return Err("tcp.tls_cert and tcp.tls_key must be set together to enable TLS".into());
+        }
+    };
+
+    let listener = TcpListener::bind(addr).await?;
+
+    info!(
+        "Synapse Server started on TCP{}: {}",
+        if tls.is_some() { " (TLS)" } else { "" },
+        addr
+    );
+
+    serve_tcp(listener, tls, l1_cache, shutdown, redis_sync).await
+}
+
+async fn serve_tcp(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    l1_cache: L1Cache,
+    shutdown: CancellationToken,
+    redis_sync: SharedRedisSync,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                info!("TCP server shutdown requested");
+                break;
+            }
+            accept_res = listener.accept() => {
+                let (stream, peer) = match accept_res {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        connection_failed("tcp", err).await;
+                        continue;
+                    }
+                };
+                // Requests are small and latency bound; don't wait to coalesce them.
+                if let Err(err) = stream.set_nodelay(true) {
+                    warn!(%peer, error = %err, "Cannot set TCP_NODELAY; dropping the connection");
+                    continue;
+                }
+                let l1_cache_clone = l1_cache.clone();
+                let redis_sync_clone = redis_sync.clone();
+                let tls = tls.clone();
+
+                tokio::spawn(async move {
+                    match tls {
+                        Some(tls) => match tls.accept(stream).await {
+                            Ok(stream) => {
+                                handle_uds_stream(stream, l1_cache_clone, redis_sync_clone).await
+                            }
+                            Err(err) => warn!(%peer, error = %err, "TLS handshake failed"),
+                        },
+                        None => handle_uds_stream(stream, l1_cache_clone, redis_sync_clone).await,
+                    }
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_tls_acceptor, serve_tcp};
+    use crate::redis::client::SharedRedisSync;
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use rcgen::{CertifiedKey, generate_simple_self_signed};
+    use rustls::{
+        ClientConfig, RootCertStore,
+        crypto::ring,
+        pki_types::{CertificateDer, ServerName},
+    };
+    use std::{env, fs, net::SocketAddr, path::PathBuf, sync::Arc};
+    use synapse_core::{CacheResponce, L1Cache, decode_response, encode_get};
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use tokio_util::{
+        codec::{Framed, LengthDelimitedCodec},
+        sync::CancellationToken,
+    };
+
+    async fn start(tls: Option<TlsAcceptor>, cache: L1Cache) -> (SocketAddr, CancellationToken) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(serve_tcp(
+            listener,
+            tls,
+            cache,
+            shutdown.clone(),
+            SharedRedisSync::default(),
+        ));
+        (addr, shutdown)
+    }
+
+    async fn get<S: AsyncRead + AsyncWrite + Unpin>(stream: S, key: &str) -> CacheResponce {
+        let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+        framed.send(encode_get(key)).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        decode_response(&response).unwrap()
+    }
+
+    /// Writes a self-signed certificate for `localhost` to temp PEM files.
+    fn write_cert(name: &str) -> (CertifiedKey<rcgen::KeyPair>, PathBuf, PathBuf) {
+        let certified = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = env::temp_dir();
+        let cert_path = dir.join(format!("synapse-{}-{}.crt", name, std::process::id()));
+        let key_path = dir.join(format!("synapse-{}-{}.key", name, std::process::id()));
+        fs::write(&cert_path, certified.cert.pem()).unwrap();
+        fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+        (certified, cert_path, key_path)
+    }
+
+    fn connector(server_cert: CertificateDer<'static>) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(server_cert).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn tcp_serves_frames() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("alpha".to_string(), Bytes::from_static(b"v1"), None)
+            .await;
+        let (addr, shutdown) = start(None, cache).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(matches!(get(stream, "alpha").await, CacheResponce::Hit(v) if v == b"v1"[..]));
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn tls_serves_frames() {
+        let (certified, cert_path, key_path) = write_cert("tls");
+        let tls = load_tls_acceptor(
+            cert_path.to_str().unwrap(),
+            key_path.to_str().unwrap(),
+            None,
+        )
+        .unwrap();
+        let cache = L1Cache::new(10);
+        cache
+            .set("alpha".to_string(), Bytes::from_static(b"v1"), None)
+            .await;
+        let (addr, shutdown) = start(Some(tls), cache).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = connector(certified.cert.der().clone())
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        assert!(matches!(get(stream, "alpha").await, CacheResponce::Hit(v) if v == b"v1"[..]));
+
+        shutdown.cancel();
+        let _ = fs::remove_file(cert_path);
+        let _ = fs::remove_file(key_path);
+    }
+
+    #[tokio::test]
+    async fn tls_client_ca_rejects_anonymous_clients() {
+        let (certified, cert_path, key_path) = write_cert("mtls");
+        let tls = load_tls_acceptor(
+            cert_path.to_str().unwrap(),
+            key_path.to_str().unwrap(),
+            cert_path.to_str(),
+        )
+        .unwrap();
+        let (addr, shutdown) = start(Some(tls), L1Cache::new(10)).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = connector(certified.cert.der().clone())
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+        // TLS 1.3 reports a missing client certificate after the handshake,
+        // so the first request fails instead.
+        let _ = framed.send(encode_get("alpha")).await;
+        assert!(!matches!(framed.next().await, Some(Ok(_))));
+
+        shutdown.cancel();
+        let _ = fs::remove_file(cert_path);
+        let _ = fs::remove_file(key_path);
+    }
+}
@@ -1,26 +1,100 @@
 use std::{
-    env,
     error::Error,
-    fs::{create_dir_all, remove_file},
+    fmt,
+    fs::{Permissions, create_dir_all, remove_file, set_permissions},
+    io::{self, IoSlice},
+    os::unix::fs::PermissionsExt,
     path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use futures::{SinkExt, StreamExt};
+use futures::StreamExt;
 use synapse_core::{
-    CacheCommand, CacheResponce, L1Cache, MAX_FRAME_LENGTH, OP_GET, OP_SET, RES_ERR, RES_HIT,
-    RES_MISS, RES_OK,
+    CAP_BATCH, CAP_LEASES, CAP_REQUEST_IDS, CAP_TRACE_CONTEXT, CAP_TTL, CacheCommand,
+    CacheResponce, ERR_MISSING_CAPABILITY, ERR_UNKNOWN_OPCODE, ERR_UNSUPPORTED_VERSION,
+    ExpirationMode, L1Cache, MAX_FRAME_LENGTH, MIN_PROTOCOL_VERSION, OP_DEL, OP_EXPIRE, OP_GET,
+    OP_GET_OR_LEASE, OP_HELLO, OP_INFO, OP_MGET, OP_MSET, OP_PERSIST, OP_RELEASE_LEASE, OP_SET,
+    OP_TOUCH, OP_TTL, PROTOCOL_VERSION, RES_ERR, RES_HELLO, RES_HIT, RES_INFO, RES_LEASE, RES_MISS,
+    RES_OK, RES_REJECTED, RES_TTL, RES_VALUES, SetCondition, TRUNCATED, TraceParent, key_hash,
+    tag_frame, untag_frame, untrace_frame,
 };
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::UnixListener;
+use tokio::sync::{Semaphore, mpsc};
 use tokio_util::{
-    codec::{Framed, LengthDelimitedCodec},
+    codec::{FramedRead, LengthDelimitedCodec},
     sync::CancellationToken,
 };
 
-use crate::redis::client::RedisSync;
+use tracing::{Instrument, Span, debug, debug_span, error, field, info, instrument};
 
-pub fn decode_command(mut buf: &[u8]) -> Result<CacheCommand, String> {
+use crate::{
+    config::UdsConfig,
+    info::server_info,
+    metrics::METRICS,
+    redis::client::{RedisSync, SharedRedisSync},
+    server::{connection_failed, next_connection_id},
+    telemetry::set_remote_parent,
+};
+
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// Capabilities this server offers during `OP_HELLO` negotiation.
+pub const SERVER_CAPABILITIES: u32 =
+    CAP_BATCH | CAP_REQUEST_IDS | CAP_LEASES | CAP_TTL | CAP_TRACE_CONTEXT;
+
+/// Copies `len` bytes out of `buf` into their own allocation. A slice would
+/// keep the whole batch alive for as long as the value stays cached, while L1
+/// only weighs the value itself.
+fn take_value(buf: &mut Bytes, len: usize) -> Bytes {
+    let value = Bytes::copy_from_slice(&buf[..len]);
+    buf.advance(len);
+    value
+}
+
+/// Slices a `SET` value out of its frame, which is little more than the value,
+/// unless the rest of the frame (a long key) outweighs it.
+fn slice_value(buf: &mut Bytes, len: usize, frame_len: usize) -> Bytes {
+    if frame_len - len <= len {
+        buf.split_to(len)
+    } else {
+        take_value(buf, len)
+    }
+}
+
+/// Why a frame could not be decoded into a command.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// An opcode this server does not know, e.g. from a newer client.
+    UnknownOp(u8),
+    Malformed(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOp(op) => write!(f, "Unknown op {:#04x}", op),
+            DecodeError::Malformed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<String> for DecodeError {
+    fn from(msg: String) -> Self {
+        DecodeError::Malformed(msg)
+    }
+}
+
+impl From<&str> for DecodeError {
+    fn from(msg: &str) -> Self {
+        DecodeError::Malformed(msg.to_string())
+    }
+}
+
+pub fn decode_command(mut buf: Bytes) -> Result<CacheCommand, DecodeError> {
+    let frame_len = buf.len();
     if !buf.has_remaining() {
         return Err("Buf is empty".into());
     }
@@ -28,7 +102,7 @@ pub fn decode_command(mut buf: &[u8]) -> Result<CacheCommand, String> {
     let op = buf.get_u8();
     match op {
         OP_GET => {
-            let key_len = buf.get_u32_le() as usize;
+            let key_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
             if buf.remaining() < key_len {
                 return Err("Bad key_len".into());
             }
@@ -37,23 +111,116 @@ pub fn decode_command(mut buf: &[u8]) -> Result<CacheCommand, String> {
             Ok(CacheCommand::Get { key })
         }
         OP_SET => {
-            let key_len = buf.get_u32_le() as usize;
-            let value_len = buf.get_u32_le() as usize;
-            let ttl_raw = buf.get_u64_le();
+            let key_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let value_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let ttl_raw = buf.try_get_u64_le().map_err(|_| TRUNCATED.to_string())?;
             if buf.remaining() < key_len + value_len {
                 return Err("Bad lenghts".into());
             }
             let key = String::from_utf8(buf.copy_to_bytes(key_len).to_vec())
                 .map_err(|e| format!("Bad key utf-8: {}", e))?;
-            let value = buf.copy_to_bytes(value_len).to_vec();
+            let value = slice_value(&mut buf, value_len, frame_len);
             let ttl_secs = if ttl_raw == 0 { None } else { Some(ttl_raw) };
+            // Older clients end the frame at the value; no flags means absolute TTL.
+            let mode = ExpirationMode::from_flags(buf.try_get_u8().unwrap_or(0));
             Ok(CacheCommand::Set {
                 key,
                 value,
                 ttl_secs,
+                mode,
+            })
+        }
+        OP_DEL => {
+            let key_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            if buf.remaining() < key_len {
+                return Err("Bad key_len".into());
+            }
+            let key = String::from_utf8(buf.copy_to_bytes(key_len).to_vec())
+                .map_err(|e| format!("Bad key utf-8: {}", e))?;
+            Ok(CacheCommand::Delete { key })
+        }
+        OP_MGET => {
+            let count = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let mut keys = Vec::with_capacity(count.min(buf.remaining() / 4));
+            for _ in 0..count {
+                if buf.remaining() < 4 {
+                    return Err("Bad key_len".into());
+                }
+                let key_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+                if buf.remaining() < key_len {
+                    return Err("Bad key_len".into());
+                }
+                let key = String::from_utf8(buf.copy_to_bytes(key_len).to_vec())
+                    .map_err(|e| format!("Bad key utf-8: {}", e))?;
+                keys.push(key);
+            }
+            Ok(CacheCommand::MGet { keys })
+        }
+        OP_MSET => {
+            let count = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let ttl_raw = buf.try_get_u64_le().map_err(|_| TRUNCATED.to_string())?;
+            let mut entries = Vec::with_capacity(count.min(buf.remaining() / 8));
+            for _ in 0..count {
+                if buf.remaining() < 8 {
+                    return Err("Bad lenghts".into());
+                }
+                let key_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+                let value_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+                if buf.remaining() < key_len + value_len {
+                    return Err("Bad lenghts".into());
+                }
+                let key = String::from_utf8(buf.copy_to_bytes(key_len).to_vec())
+                    .map_err(|e| format!("Bad key utf-8: {}", e))?;
+                let value = take_value(&mut buf, value_len);
+                entries.push((key, value));
+            }
+            let ttl_secs = if ttl_raw == 0 { None } else { Some(ttl_raw) };
+            Ok(CacheCommand::MSet { entries, ttl_secs })
+        }
+        OP_HELLO => {
+            let version = buf.try_get_u16_le().map_err(|_| TRUNCATED.to_string())?;
+            let capabilities = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())?;
+            Ok(CacheCommand::Hello {
+                version,
+                capabilities,
+            })
+        }
+        OP_GET_OR_LEASE => {
+            let key_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let lease_ms = buf.try_get_u64_le().map_err(|_| TRUNCATED.to_string())?;
+            if buf.remaining() < key_len {
+                return Err("Bad key_len".into());
+            }
+            let key = String::from_utf8(buf.copy_to_bytes(key_len).to_vec())
+                .map_err(|e| format!("Bad key utf-8: {}", e))?;
+            Ok(CacheCommand::GetOrLease { key, lease_ms })
+        }
+        OP_TTL | OP_PERSIST | OP_TOUCH | OP_RELEASE_LEASE => {
+            let key_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            if buf.remaining() < key_len {
+                return Err("Bad key_len".into());
+            }
+            let key = String::from_utf8(buf.copy_to_bytes(key_len).to_vec())
+                .map_err(|e| format!("Bad key utf-8: {}", e))?;
+            Ok(match op {
+                OP_TTL => CacheCommand::Ttl { key },
+                OP_PERSIST => CacheCommand::Persist { key },
+                OP_TOUCH => CacheCommand::Touch { key },
+                _ => CacheCommand::ReleaseLease { key },
             })
         }
-        _ => Err("Unknown op".into()),
+        OP_EXPIRE => {
+            let key_len = buf.try_get_u32_le().map_err(|_| TRUNCATED.to_string())? as usize;
+            let ttl_secs = buf.try_get_u64_le().map_err(|_| TRUNCATED.to_string())?;
+            if buf.remaining() < key_len {
+                return Err("Bad key_len".into());
+            }
+            let key = String::from_utf8(buf.copy_to_bytes(key_len).to_vec())
+                .map_err(|e| format!("Bad key utf-8: {}", e))?;
+            Ok(CacheCommand::Expire { key, ttl_secs })
+        }
+        OP_INFO => Ok(CacheCommand::Info),
+        _ => Err(DecodeError::UnknownOp(op)),
     }
 }
 
@@ -62,6 +229,7 @@ pub fn encode_response(response: CacheResponce) -> Bytes {
     match response {
         CacheResponce::Ok => out.put_u8(RES_OK),
         CacheResponce::Miss => out.put_u8(RES_MISS),
+        CacheResponce::Lease => out.put_u8(RES_LEASE),
         CacheResponce::Hit(val) => {
             out.put_u8(RES_HIT);
             out.put_u32_le(val.len() as u32);
@@ -72,76 +240,622 @@ pub fn encode_response(response: CacheResponce) -> Bytes {
             out.put_u32_le(e.len() as u32);
             out.extend_from_slice(e.as_bytes());
         }
+        CacheResponce::Values(values) => {
+            out.put_u8(RES_VALUES);
+            out.put_u32_le(values.len() as u32);
+            for value in values {
+                match value {
+                    Some(val) => {
+                        out.put_u8(RES_HIT);
+                        out.put_u32_le(val.len() as u32);
+                        out.extend_from_slice(&val);
+                    }
+                    None => out.put_u8(RES_MISS),
+                }
+            }
+        }
+        CacheResponce::Hello {
+            version,
+            capabilities,
+        } => {
+            out.put_u8(RES_HELLO);
+            out.put_u16_le(version);
+            out.put_u32_le(capabilities);
+        }
+        CacheResponce::Rejected { code, message } => {
+            out.put_u8(RES_REJECTED);
+            out.put_u16_le(code);
+            out.put_u32_le(message.len() as u32);
+            out.extend_from_slice(message.as_bytes());
+        }
+        CacheResponce::Ttl(ttl_ms) => {
+            out.put_u8(RES_TTL);
+            match ttl_ms {
+                Some(ms) => {
+                    out.put_u8(1);
+                    out.put_u64_le(ms);
+                }
+                None => out.put_u8(0),
+            }
+        }
+        CacheResponce::Info(info) => {
+            let document = serde_json::to_vec(&info).expect("info serializes");
+            out.put_u8(RES_INFO);
+            out.put_u32_le(document.len() as u32);
+            out.extend_from_slice(&document);
+        }
     }
     out.freeze()
 }
 
-async fn handle_uds_stream<S>(stream: S, l1_cache: L1Cache, redis_sync: Option<RedisSync>)
+/// Picks the highest version both sides speak and the intersection of their
+/// capabilities. Clients older than `MIN_PROTOCOL_VERSION` are rejected.
+pub fn negotiate_hello(version: u16, capabilities: u32) -> CacheResponce {
+    if version < MIN_PROTOCOL_VERSION {
+        return CacheResponce::Rejected {
+            code: ERR_UNSUPPORTED_VERSION,
+            message: format!(
+                "Protocol version {} is not supported (server speaks {}..={})",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        };
+    }
+
+    CacheResponce::Hello {
+        version: version.min(PROTOCOL_VERSION),
+        capabilities: capabilities & SERVER_CAPABILITIES,
+    }
+}
+
+/// Span for one command. Keys are logged as their FNV-1a hash so traces
+/// can be correlated without exposing them.
+fn command_span(
+    op: &'static str,
+    key: Option<&str>,
+    value_size: Option<usize>,
+    trace_parent: Option<&TraceParent>,
+) -> Span {
+    let span = debug_span!(
+        "command",
+        op,
+        key_hash = field::Empty,
+        value_size = field::Empty
+    );
+    if !span.is_disabled() {
+        if let Some(key) = key {
+            span.record("key_hash", format_args!("{:016x}", key_hash(key)));
+        }
+        if let Some(value_size) = value_size {
+            span.record("value_size", value_size);
+        }
+        if let Some(trace_parent) = trace_parent {
+            set_remote_parent(&span, trace_parent);
+        }
+    }
+    span
+}
+
+fn request_value_size(cmd: &CacheCommand) -> Option<usize> {
+    match cmd {
+        CacheCommand::Set { value, .. } => Some(value.len()),
+        CacheCommand::MSet { entries, .. } => {
+            Some(entries.iter().map(|(_, value)| value.len()).sum())
+        }
+        _ => None,
+    }
+}
+
+fn response_value_size(response: &CacheResponce) -> Option<usize> {
+    match response {
+        CacheResponce::Hit(value) => Some(value.len()),
+        CacheResponce::Values(values) => Some(values.iter().flatten().map(Bytes::len).sum()),
+        _ => None,
+    }
+}
+
+fn command_completed(span: &Span, op: &'static str, started: Instant) {
+    METRICS.observe(op, started);
+    let latency_us = started.elapsed().as_micros() as u64;
+    span.in_scope(|| debug!(latency_us, "command completed"));
+}
+
+/// Runs one command in a `command` span. With `trace_parent` the span joins
+/// the client's trace when exported.
+pub(crate) async fn execute_command(
+    cmd: CacheCommand,
+    trace_parent: Option<TraceParent>,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> CacheResponce {
+    let op = cmd.name();
+    let span = command_span(
+        op,
+        cmd.key(),
+        request_value_size(&cmd),
+        trace_parent.as_ref(),
+    );
+    let started = Instant::now();
+    let response = run_command(cmd, l1_cache, redis_sync)
+        .instrument(span.clone())
+        .await;
+    if let Some(value_size) = response_value_size(&response) {
+        span.record("value_size", value_size);
+    }
+    command_completed(&span, op, started);
+    response
+}
+
+fn redis_write_failed(op: &str, err: Box<dyn Error + Send + Sync>) {
+    METRICS.redis_write_failures.with_label_values(&[op]).inc();
+    error!(op, error = %err, "Redis write failed");
+}
+
+fn key_found(found: bool) -> CacheResponce {
+    if found {
+        CacheResponce::Ok
+    } else {
+        CacheResponce::Miss
+    }
+}
+
+async fn run_command(
+    cmd: CacheCommand,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> CacheResponce {
+    match cmd {
+        CacheCommand::Get { key } => match redis_sync {
+            Some(redis_sync) if redis_sync.reads_through(&key) => {
+                let fetch = async {
+                    match redis_sync.fetch(&key).await {
+                        Ok(value) => value,
+                        Err(err) => {
+                            METRICS.redis_read_failures.inc();
+                            error!(error = %err, "Redis read-through failed");
+                            None
+                        }
+                    }
+                };
+                l1_cache
+                    .get_or_fetch(&key, fetch)
+                    .instrument(debug_span!("l1_lookup"))
+                    .await
+            }
+            _ => {
+                l1_cache
+                    .get(&key)
+                    .instrument(debug_span!("l1_lookup"))
+                    .await
+            }
+        },
+        CacheCommand::Set {
+            key,
+            value,
+            ttl_secs,
+            mode,
+        } => {
+            METRICS.sets.inc();
+            if let Some(redis_sync) = redis_sync {
+                l1_cache
+                    .set_with_mode(key.clone(), value.clone(), ttl_secs, mode)
+                    .await;
+                if let Err(err) = redis_sync.set(&key, &value, ttl_secs, mode).await {
+                    redis_write_failed("set", err);
+                }
+            } else {
+                l1_cache.set_with_mode(key, value, ttl_secs, mode).await;
+            }
+            CacheResponce::Ok
+        }
+        CacheCommand::Delete { key } => {
+            delete(&key, l1_cache, redis_sync).await;
+            CacheResponce::Ok
+        }
+        CacheCommand::MGet { keys } => {
+            l1_cache
+                .get_many(&keys)
+                .instrument(debug_span!("l1_lookup", keys = keys.len()))
+                .await
+        }
+        CacheCommand::MSet { entries, ttl_secs } => {
+            METRICS.sets.inc_by(entries.len() as u64);
+            if let Some(redis_sync) = redis_sync {
+                l1_cache.set_many(entries.clone(), ttl_secs).await;
+                if let Err(err) = redis_sync.set_many(&entries, ttl_secs).await {
+                    redis_write_failed("mset", err);
+                }
+            } else {
+                l1_cache.set_many(entries, ttl_secs).await;
+            }
+            CacheResponce::Ok
+        }
+        CacheCommand::Hello {
+            version,
+            capabilities,
+        } => negotiate_hello(version, capabilities),
+        CacheCommand::GetOrLease { key, lease_ms } => {
+            l1_cache
+                .get_or_lease(&key, Duration::from_millis(lease_ms))
+                .instrument(debug_span!("l1_lookup"))
+                .await
+        }
+        CacheCommand::ReleaseLease { key } => key_found(l1_cache.release_lease(&key)),
+        // A key read-through would load is reported with its TTL in Redis.
+        CacheCommand::Ttl { key } => match (l1_cache.ttl(&key).await, redis_sync) {
+            (CacheResponce::Miss, Some(redis_sync)) if redis_sync.reads_through(&key) => {
+                match redis_sync.ttl(&key).await {
+                    Ok(Some(ttl)) => CacheResponce::Ttl(ttl),
+                    Ok(None) => CacheResponce::Miss,
+                    Err(err) => {
+                        METRICS.redis_read_failures.inc();
+                        error!(error = %err, "Redis read-through failed");
+                        CacheResponce::Miss
+                    }
+                }
+            }
+            (response, _) => response,
+        },
+        // Forwarded to Redis even when the key is not in this node's L1, since
+        // Redis and peers may still hold it. Redis then decides the reply.
+        CacheCommand::Expire { key, ttl_secs } => {
+            let local = l1_cache.expire(&key, ttl_secs).await;
+            let found = match redis_sync {
+                Some(redis_sync) => redis_sync
+                    .expire(&key, ttl_secs)
+                    .await
+                    .unwrap_or_else(|err| {
+                        redis_write_failed("expire", err);
+                        local
+                    }),
+                None => local,
+            };
+            key_found(found)
+        }
+        CacheCommand::Persist { key } => {
+            let local = l1_cache.persist(&key).await;
+            let found = match redis_sync {
+                Some(redis_sync) => redis_sync.persist(&key).await.unwrap_or_else(|err| {
+                    redis_write_failed("persist", err);
+                    local
+                }),
+                None => local,
+            };
+            key_found(found)
+        }
+        CacheCommand::Touch { key } => {
+            let local = l1_cache.touch(&key).await;
+            let found = match redis_sync {
+                Some(redis_sync) => redis_sync
+                    .touch(&key, local.flatten().map(|ttl| ttl.as_secs()))
+                    .await
+                    .unwrap_or_else(|err| {
+                        redis_write_failed("touch", err);
+                        local.is_some()
+                    }),
+                None => local.is_some(),
+            };
+            key_found(found)
+        }
+        CacheCommand::Info => CacheResponce::Info(Box::new(server_info(l1_cache, redis_sync))),
+    }
+}
+
+/// Drops `key` from L1 and Redis. Returns whether either had it, or whether
+/// L1 did if Redis cannot be reached.
+async fn delete(key: &str, l1_cache: &L1Cache, redis_sync: Option<&RedisSync>) -> bool {
+    let local = l1_cache.remove(key).await;
+    match redis_sync {
+        Some(redis_sync) => match redis_sync.delete(key).await {
+            Ok(deleted) => deleted || local,
+            Err(err) => {
+                redis_write_failed("del", err);
+                local
+            }
+        },
+        None => local,
+    }
+}
+
+/// `DEL` for the Redis and memcached front ends, which report whether the key
+/// existed. Returns that.
+pub(crate) async fn execute_delete(
+    key: String,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> bool {
+    let span = command_span("del", Some(&key), None, None);
+    let started = Instant::now();
+    let found = delete(&key, l1_cache, redis_sync)
+        .instrument(span.clone())
+        .await;
+    command_completed(&span, "del", started);
+    found
+}
+
+/// Conditional `SET` for the Redis and memcached front ends. The condition is
+/// checked against L1 only; once it holds the write is mirrored to Redis like
+/// any other `SET`. Returns whether the value was stored.
+pub(crate) async fn execute_set_if(
+    key: String,
+    value: Bytes,
+    ttl_secs: Option<u64>,
+    condition: SetCondition,
+    l1_cache: &L1Cache,
+    redis_sync: Option<&RedisSync>,
+) -> bool {
+    let span = command_span("set", Some(&key), Some(value.len()), None);
+    let started = Instant::now();
+    METRICS.sets.inc();
+    let stored = async {
+        let stored = l1_cache
+            .set_if(key.clone(), value.clone(), ttl_secs, condition)
+            .await;
+        if stored
+            && let Some(redis_sync) = redis_sync
+            && let Err(err) = redis_sync
+                .set(&key, &value, ttl_secs, ExpirationMode::Absolute)
+                .await
+        {
+            redis_write_failed("set", err);
+        }
+        stored
+    }
+    .instrument(span.clone())
+    .await;
+    command_completed(&span, "set", started);
+    stored
+}
+
+/// Splits a response into its encoded header and, for hits, the cached value.
+/// Writing the two back to back produces the same bytes as `encode_response`
+/// without copying the value.
+pub fn encode_response_parts(response: CacheResponce) -> (Bytes, Bytes) {
+    match response {
+        CacheResponce::Hit(val) => {
+            let mut out = BytesMut::with_capacity(5);
+            out.put_u8(RES_HIT);
+            out.put_u32_le(val.len() as u32);
+            (out.freeze(), val)
+        }
+        response => (encode_response(response), Bytes::new()),
+    }
+}
+
+fn frame_response(request_id: Option<u32>, response: CacheResponce) -> (Bytes, Bytes) {
+    let (header, value) = encode_response_parts(response);
+    match request_id {
+        Some(request_id) => (tag_frame(request_id, &header), value),
+        None => (header, value),
+    }
+}
+
+/// Writes one length-delimited frame (big-endian `u32` length, as
+/// `LengthDelimitedCodec` expects) with vectored writes, so `value` goes to the
+/// socket straight from the cache's buffer.
+async fn write_frame<W>(writer: &mut W, header: Bytes, value: Bytes) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = ((header.len() + value.len()) as u32).to_be_bytes();
+    let mut frame = (&len[..]).chain(header).chain(value);
+    while frame.has_remaining() {
+        let mut slices = [IoSlice::new(&[]); 3];
+        let count = frame.chunks_vectored(&mut slices);
+        let written = writer.write_vectored(&slices[..count]).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        frame.advance(written);
+    }
+    Ok(())
+}
+
+/// Names the first capability that `cmd`, or the tags it came with, needs
+/// but the connection did not negotiate.
+fn unnegotiated(
+    cmd: &CacheCommand,
+    tagged: bool,
+    traced: bool,
+    capabilities: u32,
+) -> Option<&'static str> {
+    let missing = |capability: u32| capability != 0 && capabilities & capability == 0;
+    if tagged && missing(CAP_REQUEST_IDS) {
+        Some("Request ids")
+    } else if traced && missing(CAP_TRACE_CONTEXT) {
+        Some("Trace contexts")
+    } else if missing(cmd.capability()) {
+        Some(match cmd.capability() {
+            CAP_BATCH => "Batch commands",
+            CAP_LEASES => "Leases",
+            _ => "TTL commands",
+        })
+    } else {
+        None
+    }
+}
+
+/// Untagged frames are answered in order before the next frame is read. Frames
+/// tagged with a request id run concurrently and may be answered out of order.
+/// Clients that never send `OP_HELLO` are treated as protocol version 1 with
+/// every capability enabled; a rejected `OP_HELLO` closes the connection.
+/// After a `HELLO`, commands and tags needing a capability that was not
+/// negotiated are rejected with `ERR_MISSING_CAPABILITY`.
+#[instrument(
+    name = "connection",
+    skip_all,
+    fields(conn_id = next_connection_id(), protocol = "frame")
+)]
+pub(crate) async fn handle_uds_stream<S>(stream: S, l1_cache: L1Cache, redis_sync: SharedRedisSync)
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let mut framed = Framed::new(
-        stream,
+    let _connection = METRICS.connection("frame");
+    let (read_half, mut write_half) = tokio::io::split(stream);
+    let mut frames = FramedRead::new(
+        read_half,
         LengthDelimitedCodec::builder()
             .max_frame_length(MAX_FRAME_LENGTH)
             .new_codec(),
     );
+    let (responses_tx, mut responses_rx) = mpsc::channel::<(Bytes, Bytes)>(MAX_IN_FLIGHT);
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let mut capabilities = SERVER_CAPABILITIES;
 
-    while let Some(Ok(packet)) = framed.next().await {
-        if let Ok(cmd) = decode_command(&packet) {
-            let response = match cmd {
-                CacheCommand::Get { key } => l1_cache.get(&key).await,
-                CacheCommand::Set {
-                    key,
-                    value,
-                    ttl_secs,
-                } => {
-                    if let Some(redis_sync) = redis_sync.as_ref() {
-                        l1_cache.set(key.clone(), value.clone(), ttl_secs).await;
-                        if let Err(err) = redis_sync.set(&key, &value, ttl_secs).await {
-                            eprintln!("Redis write failed: {}", err);
-                        }
-                    } else {
-                        l1_cache.set(key, value, ttl_secs).await;
-                    }
-                    CacheResponce::Ok
-                }
+    let writer = tokio::spawn(async move {
+        let bytes_sent = METRICS.bytes_sent.with_label_values(&["frame"]);
+        while let Some((header, value)) = responses_rx.recv().await {
+            let len = 4 + header.len() + value.len();
+            if write_frame(&mut write_half, header, value).await.is_err() {
+                break;
+            }
+            bytes_sent.inc_by(len as u64);
+        }
+    });
+
+    let bytes_received = METRICS.bytes_received.with_label_values(&["frame"]);
+    while let Some(Ok(packet)) = frames.next().await {
+        bytes_received.inc_by(4 + packet.len() as u64);
+        let packet = packet.freeze();
+        let (request_id, body) = match untag_frame(&packet) {
+            Ok((request_id, body)) => (request_id, packet.slice_ref(body)),
+            Err(err) => {
+                let _ = responses_tx
+                    .send(frame_response(None, CacheResponce::Error(err)))
+                    .await;
+                continue;
+            }
+        };
+
+        let (trace_parent, body) = match untrace_frame(&body) {
+            Ok((trace_parent, rest)) => (trace_parent, body.slice_ref(rest)),
+            Err(err) => {
+                let _ = responses_tx
+                    .send(frame_response(request_id, CacheResponce::Error(err)))
+                    .await;
+                continue;
+            }
+        };
+
+        let cmd = match decode_command(body) {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                let response = match err {
+                    DecodeError::UnknownOp(_) => CacheResponce::Rejected {
+                        code: ERR_UNKNOWN_OPCODE,
+                        message: err.to_string(),
+                    },
+                    DecodeError::Malformed(msg) => CacheResponce::Error(msg),
+                };
+                let _ = responses_tx
+                    .send(frame_response(request_id, response))
+                    .await;
+                continue;
+            }
+        };
+
+        if let Some(missing) = unnegotiated(
+            &cmd,
+            request_id.is_some(),
+            trace_parent.is_some(),
+            capabilities,
+        ) {
+            let response = CacheResponce::Rejected {
+                code: ERR_MISSING_CAPABILITY,
+                message: format!("{} were not negotiated", missing),
             };
+            let _ = responses_tx
+                .send(frame_response(request_id, response))
+                .await;
+            continue;
+        }
 
-            let _ = framed.send(encode_response(response)).await;
-        } else {
-            let response = CacheResponce::Error("Not implemented".into());
-            let _ = framed.send(encode_response(response)).await;
+        if request_id.is_none() || matches!(cmd, CacheCommand::Hello { .. }) {
+            let response =
+                execute_command(cmd, trace_parent, &l1_cache, redis_sync.load().as_deref()).await;
+            let rejected = matches!(response, CacheResponce::Rejected { .. });
+            if let CacheResponce::Hello {
+                capabilities: negotiated,
+                ..
+            } = response
+            {
+                capabilities = negotiated;
+            }
+            if responses_tx
+                .send(frame_response(request_id, response))
+                .await
+                .is_err()
+                || rejected
+            {
+                break;
+            }
+            continue;
         }
+
+        let Ok(permit) = in_flight.clone().acquire_owned().await else {
+            break;
+        };
+        let l1_cache = l1_cache.clone();
+        let redis_sync = redis_sync.clone();
+        let responses_tx = responses_tx.clone();
+        tokio::spawn(
+            async move {
+                let response =
+                    execute_command(cmd, trace_parent, &l1_cache, redis_sync.load().as_deref())
+                        .await;
+                let _ = responses_tx
+                    .send(frame_response(request_id, response))
+                    .await;
+                drop(permit);
+            }
+            .in_current_span(),
+        );
     }
+
+    drop(responses_tx);
+    let _ = writer.await;
 }
 
+/// Serves the frame protocol on `uds.socket_path`. With `uds.enabled` off
+/// the listener stays down until shutdown.
 pub async fn run_uds(
+    config: UdsConfig,
     l1_cache: L1Cache,
     shutdown: CancellationToken,
-    redis_sync: Option<RedisSync>,
+    redis_sync: SharedRedisSync,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let socket_path =
-        env::var("SYNAPSE_SOCKET_PATH").unwrap_or_else(|_| "/tmp/synapse.sock".to_string());
-    if let Some(parent) = Path::new(&socket_path).parent() {
+    if !config.enabled {
+        shutdown.cancelled().await;
+        return Ok(());
+    }
+    let socket_path = config.socket_path.as_str();
+    if let Some(parent) = Path::new(socket_path).parent() {
         create_dir_all(parent)?;
     }
-    if Path::new(&socket_path).exists() {
-        remove_file(&socket_path)?;
+    if Path::new(socket_path).exists() {
+        remove_file(socket_path)?;
     }
 
-    let listener = UnixListener::bind(&socket_path)?;
+    let listener = UnixListener::bind(socket_path)?;
+    if let Some(mode) = config.socket_mode()? {
+        set_permissions(socket_path, Permissions::from_mode(mode))?;
+    }
 
-    println!("Synapse Server started on UDS: {}", socket_path);
+    info!("Synapse Server started on UDS: {}", socket_path);
 
     loop {
         tokio::select! {
             _ = shutdown.cancelled() => {
-                println!("UDS server shutdown requested");
+                info!("UDS server shutdown requested");
                 break;
             }
             accept_res = listener.accept() => {
-                let (stream, _) = accept_res?;
+                let stream = match accept_res {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        connection_failed("uds", err).await;
+                        continue;
+                    }
+                };
                 let l1_cache_clone = l1_cache.clone();
                 let redis_sync_clone = redis_sync.clone();
 
@@ -157,11 +871,21 @@ pub async fn run_uds(
 
 #[cfg(test)]
 mod tests {
-    use super::{decode_command, encode_response, handle_uds_stream};
-    use bytes::{Buf, BufMut, BytesMut};
+    use super::{
+        DecodeError, SERVER_CAPABILITIES, decode_command, encode_response, encode_response_parts,
+        handle_uds_stream,
+    };
+    use crate::redis::client::SharedRedisSync;
+    use bytes::{Buf, BufMut, Bytes, BytesMut};
     use futures::{SinkExt, StreamExt};
     use synapse_core::{
-        CacheCommand, CacheResponce, L1Cache, OP_GET, OP_SET, RES_ERR, RES_HIT, RES_MISS, RES_OK,
+        CAP_BATCH, CAP_REQUEST_IDS, CacheCommand, CacheResponce, ERR_MISSING_CAPABILITY,
+        ERR_UNKNOWN_OPCODE, ERR_UNSUPPORTED_VERSION, ExpirationMode, L1Cache, OP_DEL, OP_GET,
+        OP_SET, PROTOCOL_VERSION, RES_ERR, RES_HIT, RES_MISS, RES_OK, RES_TTL, RES_VALUES,
+        TAG_TRACE_CONTEXT, TraceParent, decode_response, encode_del, encode_expire, encode_get,
+        encode_get_or_lease, encode_hello, encode_info, encode_mget, encode_mset, encode_persist,
+        encode_release_lease, encode_set, encode_set_with_mode, encode_touch, encode_ttl,
+        tag_frame, trace_frame, untag_frame,
     };
     use tokio::io::duplex;
     use tokio_util::codec::{Framed, LengthDelimitedCodec};
@@ -174,7 +898,7 @@ mod tests {
         buf.put_u32_le(key.len() as u32);
         buf.extend_from_slice(key.as_bytes());
 
-        let cmd = decode_command(&buf).expect("decode get");
+        let cmd = decode_command(buf.freeze()).expect("decode get");
         assert!(matches!(cmd, CacheCommand::Get { key: k } if k == key));
     }
 
@@ -186,7 +910,7 @@ mod tests {
         buf.put_u32_le(key.len() as u32);
         buf.extend_from_slice(key.as_bytes());
 
-        let cmd = decode_command(&buf).expect("decode get");
+        let cmd = decode_command(buf.freeze()).expect("decode get");
         assert!(matches!(cmd, CacheCommand::Get { key: k } if k == key));
     }
 
@@ -202,17 +926,51 @@ mod tests {
         buf.extend_from_slice(key.as_bytes());
         buf.extend_from_slice(value);
 
-        let cmd = decode_command(&buf).expect("decode set");
+        let cmd = decode_command(buf.freeze()).expect("decode set");
         assert!(matches!(
             cmd,
             CacheCommand::Set {
                 key: k,
                 value: v,
                 ttl_secs: None,
-            } if k == key && v == value
+                mode: ExpirationMode::Absolute,
+            } if k == key && v == value[..]
         ));
     }
 
+    #[test]
+    fn decode_command_set_slices_frame() {
+        let frame = encode_set("k", &[7; 256], None);
+
+        let cmd = decode_command(frame.clone()).expect("decode set");
+        let CacheCommand::Set { value, .. } = cmd else {
+            panic!("Expected Set, got {:?}", cmd);
+        };
+        let offset = 1 + 4 + 4 + 8 + 1;
+        assert_eq!(value.as_ptr(), frame[offset..].as_ptr());
+
+        let key = "k".repeat(256);
+        let frame = encode_set(&key, b"v1", None);
+        let cmd = decode_command(frame.clone()).expect("decode set");
+        let CacheCommand::Set { value, .. } = cmd else {
+            panic!("Expected Set, got {:?}", cmd);
+        };
+        assert_eq!(value, b"v1"[..]);
+        assert!(!frame.as_ptr_range().contains(&value.as_ptr()));
+    }
+
+    #[test]
+    fn decode_command_mset_values_do_not_pin_the_batch() {
+        let frame = encode_mset(&[("k1", &[1; 64][..]), ("k2", &[2; 64][..])], None);
+        let cmd = decode_command(frame.clone()).expect("decode mset");
+        let CacheCommand::MSet { entries, .. } = cmd else {
+            panic!("Expected MSet, got {:?}", cmd);
+        };
+        for (_, value) in entries {
+            assert!(!frame.as_ptr_range().contains(&value.as_ptr()));
+        }
+    }
+
     #[test]
     fn decode_command_set_ok_with_ttl() {
         let key = "k2";
@@ -226,21 +984,150 @@ mod tests {
         buf.extend_from_slice(key.as_bytes());
         buf.extend_from_slice(value);
 
-        let cmd = decode_command(&buf).expect("decode set ttl");
+        let cmd = decode_command(buf.freeze()).expect("decode set ttl");
         assert!(matches!(
             cmd,
             CacheCommand::Set {
                 key: k,
                 value: v,
                 ttl_secs: Some(t),
-            } if k == key && v == value && t == ttl
+                mode: ExpirationMode::Absolute,
+            } if k == key && v == value[..] && t == ttl
+        ));
+    }
+
+    #[test]
+    fn decode_command_del_ok() {
+        let key = "gone";
+        let mut buf = BytesMut::new();
+        buf.put_u8(OP_DEL);
+        buf.put_u32_le(key.len() as u32);
+        buf.extend_from_slice(key.as_bytes());
+
+        let cmd = decode_command(buf.freeze()).expect("decode del");
+        assert!(matches!(cmd, CacheCommand::Delete { key: k } if k == key));
+    }
+
+    #[test]
+    fn decode_command_mget_ok() {
+        let buf = encode_mget(&["k1", "k2"]);
+
+        let cmd = decode_command(buf).expect("decode mget");
+        assert!(matches!(cmd, CacheCommand::MGet { keys } if keys == vec!["k1", "k2"]));
+    }
+
+    #[test]
+    fn decode_command_mset_ok_with_ttl() {
+        let buf = encode_mset(
+            &[("k1", b"v1".as_slice()), ("k2", b"v2".as_slice())],
+            Some(7),
+        );
+
+        let cmd = decode_command(buf).expect("decode mset");
+        assert!(matches!(
+            cmd,
+            CacheCommand::MSet {
+                entries,
+                ttl_secs: Some(7),
+            } if entries == vec![("k1".to_string(), Bytes::from_static(b"v1")), ("k2".to_string(), Bytes::from_static(b"v2"))]
+        ));
+    }
+
+    #[test]
+    fn decode_command_mget_truncated() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(super::OP_MGET);
+        buf.put_u32_le(2);
+        buf.put_u32_le(1);
+        buf.extend_from_slice(b"a");
+
+        let err = decode_command(buf.freeze()).expect_err("truncated mget should error");
+        assert_eq!(err.to_string(), "Bad key_len");
+    }
+
+    #[test]
+    fn decode_command_hello_ok() {
+        let buf = encode_hello(2, 3);
+
+        let cmd = decode_command(buf).expect("decode hello");
+        assert!(matches!(
+            cmd,
+            CacheCommand::Hello {
+                version: 2,
+                capabilities: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn decode_command_truncated_header() {
+        let err = decode_command(Bytes::from_static(&[OP_SET, 1, 0]))
+            .expect_err("truncated set should error");
+        assert_eq!(err.to_string(), "Truncated frame");
+    }
+
+    #[test]
+    fn decode_command_get_or_lease_ok() {
+        let buf = encode_get_or_lease("hot", 250);
+
+        let cmd = decode_command(buf).expect("decode get_or_lease");
+        assert!(matches!(
+            cmd,
+            CacheCommand::GetOrLease { key, lease_ms: 250 } if key == "hot"
+        ));
+    }
+
+    #[test]
+    fn decode_command_expire_ok() {
+        let buf = encode_expire("alpha", 30);
+
+        let cmd = decode_command(buf).expect("decode expire");
+        assert!(matches!(
+            cmd,
+            CacheCommand::Expire { key, ttl_secs: 30 } if key == "alpha"
+        ));
+    }
+
+    #[test]
+    fn decode_command_ttl_persist_touch_ok() {
+        let cmd = decode_command(encode_ttl("alpha")).expect("decode ttl");
+        assert!(matches!(cmd, CacheCommand::Ttl { key } if key == "alpha"));
+
+        let cmd = decode_command(encode_persist("alpha")).expect("decode persist");
+        assert!(matches!(cmd, CacheCommand::Persist { key } if key == "alpha"));
+
+        let cmd = decode_command(encode_touch("alpha")).expect("decode touch");
+        assert!(matches!(cmd, CacheCommand::Touch { key } if key == "alpha"));
+
+        let cmd = decode_command(encode_release_lease("alpha")).expect("decode release_lease");
+        assert!(matches!(cmd, CacheCommand::ReleaseLease { key } if key == "alpha"));
+    }
+
+    #[test]
+    fn decode_command_info_ok() {
+        let cmd = decode_command(encode_info()).expect("decode info");
+        assert!(matches!(cmd, CacheCommand::Info));
+    }
+
+    #[test]
+    fn decode_command_set_sliding_flag() {
+        let buf = encode_set_with_mode("k3", b"v", Some(5), ExpirationMode::Sliding);
+
+        let cmd = decode_command(buf).expect("decode sliding set");
+        assert!(matches!(
+            cmd,
+            CacheCommand::Set {
+                ttl_secs: Some(5),
+                mode: ExpirationMode::Sliding,
+                ..
+            }
         ));
     }
 
     #[test]
     fn decode_command_empty_buf() {
-        let err = decode_command(&[]).expect_err("empty buf should error");
-        assert_eq!(err, "Buf is empty");
+        let err = decode_command(Bytes::new()).expect_err("empty buf should error");
+        assert_eq!(err.to_string(), "Buf is empty");
     }
 
     #[test]
@@ -250,8 +1137,8 @@ mod tests {
         buf.put_u32_le(10);
         buf.extend_from_slice(b"abc");
 
-        let err = decode_command(&buf).expect_err("bad key_len should error");
-        assert_eq!(err, "Bad key_len");
+        let err = decode_command(buf.freeze()).expect_err("bad key_len should error");
+        assert_eq!(err.to_string(), "Bad key_len");
     }
 
     #[test]
@@ -264,15 +1151,15 @@ mod tests {
         buf.extend_from_slice(b"k");
         buf.extend_from_slice(b"v");
 
-        let err = decode_command(&buf).expect_err("bad lengths should error");
-        assert_eq!(err, "Bad lenghts");
+        let err = decode_command(buf.freeze()).expect_err("bad lengths should error");
+        assert_eq!(err.to_string(), "Bad lenghts");
     }
 
     #[test]
     fn decode_command_unknown_op() {
-        let buf = [0xFF];
-        let err = decode_command(&buf).expect_err("unknown op should error");
-        assert_eq!(err, "Unknown op");
+        let buf = Bytes::from_static(&[0xFF]);
+        let err = decode_command(buf).expect_err("unknown op should error");
+        assert_eq!(err, DecodeError::UnknownOp(0xFF));
     }
 
     #[test]
@@ -289,7 +1176,7 @@ mod tests {
 
     #[test]
     fn encode_response_hit() {
-        let val = b"data".to_vec();
+        let val = Bytes::from_static(b"data");
         let out = encode_response(CacheResponce::Hit(val.clone()));
 
         let mut expected = BytesMut::new();
@@ -313,12 +1200,44 @@ mod tests {
         assert_eq!(out.as_ref(), expected.as_ref());
     }
 
+    #[test]
+    fn encode_response_parts_hit_matches_encode_response() {
+        let val = Bytes::from(vec![0xAB; 1024]);
+        let (header, value) = encode_response_parts(CacheResponce::Hit(val.clone()));
+        assert_eq!(value.as_ptr(), val.as_ptr());
+
+        let mut joined = BytesMut::from(header.as_ref());
+        joined.extend_from_slice(&value);
+        assert_eq!(
+            joined.as_ref(),
+            encode_response(CacheResponce::Hit(val)).as_ref()
+        );
+    }
+
+    #[test]
+    fn encode_response_values() {
+        let out = encode_response(CacheResponce::Values(vec![
+            Some(Bytes::from_static(b"a")),
+            None,
+        ]));
+
+        let mut expected = BytesMut::new();
+        expected.put_u8(RES_VALUES);
+        expected.put_u32_le(2);
+        expected.put_u8(RES_HIT);
+        expected.put_u32_le(1);
+        expected.extend_from_slice(b"a");
+        expected.put_u8(RES_MISS);
+
+        assert_eq!(out.as_ref(), expected.as_ref());
+    }
+
     #[tokio::test]
     async fn handle_uds_stream_decode_error_sends_error() {
         let cache = L1Cache::new(10);
         let (client, server) = duplex(1024);
 
-        tokio::spawn(handle_uds_stream(server, cache, None));
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
 
         let mut framed = Framed::new(
             client,
@@ -328,7 +1247,7 @@ mod tests {
         );
 
         framed
-            .send(BytesMut::from(&[0xFF][..]).freeze())
+            .send(Bytes::from_static(&[OP_SET, 1, 0]))
             .await
             .unwrap();
         let response = framed.next().await.unwrap().unwrap();
@@ -337,6 +1256,496 @@ mod tests {
         assert_eq!(buf.get_u8(), RES_ERR);
         let msg_len = buf.get_u32_le() as usize;
         let msg = std::str::from_utf8(&buf[..msg_len]).unwrap();
-        assert_eq!(msg, "Not implemented");
+        assert_eq!(msg, "Truncated frame");
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_accepts_trace_context() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(
+            server,
+            cache.clone(),
+            SharedRedisSync::default(),
+        ));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+        let trace_parent: TraceParent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            .parse()
+            .unwrap();
+
+        let set = trace_frame(&trace_parent, &encode_set("alpha", b"v1", None));
+        framed.send(set).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_OK]);
+
+        let get = tag_frame(9, &trace_frame(&trace_parent, &encode_get("alpha")));
+        framed.send(get).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap().freeze();
+        let (request_id, body) = untag_frame(&response).unwrap();
+        assert_eq!(request_id, Some(9));
+        assert!(
+            matches!(decode_response(body), Ok(CacheResponce::Hit(v)) if v == Bytes::from_static(b"v1"))
+        );
+
+        framed
+            .send(Bytes::from_static(&[TAG_TRACE_CONTEXT, 0]))
+            .await
+            .unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response[0], RES_ERR);
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_answers_info() {
+        let cache = L1Cache::new(10);
+        cache.set("alpha".to_string(), "v1".into(), None).await;
+        cache.get("alpha").await;
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(
+            server,
+            cache.clone(),
+            SharedRedisSync::default(),
+        ));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(64 * 1024)
+                .new_codec(),
+        );
+        framed.send(encode_info()).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        let Ok(CacheResponce::Info(info)) = decode_response(&response) else {
+            panic!("expected info, got {:?}", decode_response(&response));
+        };
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.cache.hits, 1);
+        assert!(!info.redis.enabled);
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_delete_removes_key() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(
+            server,
+            cache.clone(),
+            SharedRedisSync::default(),
+        ));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed.send(encode_set("alpha", b"v1", None)).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_OK]);
+
+        framed.send(encode_del("alpha")).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_OK]);
+
+        framed.send(encode_get("alpha")).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_MISS]);
+        assert!(matches!(cache.get("alpha").await, CacheResponce::Miss));
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_expire_persist_ttl() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed.send(encode_expire("alpha", 30)).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_MISS]);
+
+        framed.send(encode_set("alpha", b"v1", None)).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_OK]);
+
+        framed.send(encode_ttl("alpha")).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_TTL, 0]);
+
+        framed.send(encode_expire("alpha", 30)).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_OK]);
+
+        framed.send(encode_ttl("alpha")).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert!(matches!(
+            decode_response(&response).unwrap(),
+            CacheResponce::Ttl(Some(ms)) if ms > 29_000 && ms <= 30_000
+        ));
+
+        framed.send(encode_persist("alpha")).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_OK]);
+
+        framed.send(encode_touch("alpha")).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_OK]);
+
+        framed.send(encode_ttl("alpha")).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_TTL, 0]);
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_mset_mget_roundtrip() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed
+            .send(encode_mset(
+                &[("a", b"1".as_slice()), ("b", b"2".as_slice())],
+                None,
+            ))
+            .await
+            .unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response.as_ref(), &[RES_OK]);
+
+        framed.send(encode_mget(&["a", "x", "b"])).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        match decode_response(&response).unwrap() {
+            CacheResponce::Values(values) => {
+                assert_eq!(
+                    values,
+                    vec![
+                        Some(Bytes::from_static(b"1")),
+                        None,
+                        Some(Bytes::from_static(b"2"))
+                    ]
+                )
+            }
+            other => panic!("Expected Values, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_tagged_requests_echo_ids() {
+        let cache = L1Cache::new(10);
+        cache
+            .set("a".to_string(), Bytes::from_static(b"1"), None)
+            .await;
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed.send(tag_frame(7, &encode_get("a"))).await.unwrap();
+        framed.send(tag_frame(9, &encode_get("b"))).await.unwrap();
+
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            let packet = framed.next().await.unwrap().unwrap();
+            let (request_id, body) = untag_frame(&packet).unwrap();
+            let response = decode_response(body).unwrap();
+            seen.push((request_id.unwrap(), response));
+        }
+        seen.sort_by_key(|(id, _)| *id);
+
+        assert!(matches!(&seen[0], (7, CacheResponce::Hit(v)) if v == &b"1"[..]));
+        assert!(matches!(&seen[1], (9, CacheResponce::Miss)));
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_tagged_unknown_op_is_rejected_and_keeps_id() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed.send(tag_frame(3, &[0xFF])).await.unwrap();
+        let packet = framed.next().await.unwrap().unwrap();
+        let (request_id, body) = untag_frame(&packet).unwrap();
+        assert_eq!(request_id, Some(3));
+        assert!(matches!(
+            decode_response(body).unwrap(),
+            CacheResponce::Rejected {
+                code: ERR_UNKNOWN_OPCODE,
+                ..
+            }
+        ));
+
+        framed.send(tag_frame(4, &encode_get("k"))).await.unwrap();
+        let packet = framed.next().await.unwrap().unwrap();
+        let (request_id, body) = untag_frame(&packet).unwrap();
+        assert_eq!(request_id, Some(4));
+        assert!(matches!(
+            decode_response(body).unwrap(),
+            CacheResponce::Miss
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_hello_downgrades_to_server_version() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed.send(encode_hello(u16::MAX, u32::MAX)).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert!(matches!(
+            decode_response(&response).unwrap(),
+            CacheResponce::Hello { version, capabilities }
+                if version == PROTOCOL_VERSION && capabilities == SERVER_CAPABILITIES
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_hello_rejects_old_version_and_closes() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed.send(encode_hello(0, CAP_BATCH)).await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert!(matches!(
+            decode_response(&response).unwrap(),
+            CacheResponce::Rejected { code, .. } if code == ERR_UNSUPPORTED_VERSION
+        ));
+        assert!(framed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_tagged_without_negotiated_ids_is_rejected() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed
+            .send(encode_hello(PROTOCOL_VERSION, CAP_BATCH))
+            .await
+            .unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+
+        framed.send(tag_frame(5, &encode_get("a"))).await.unwrap();
+        let packet = framed.next().await.unwrap().unwrap();
+        let (request_id, body) = untag_frame(&packet).unwrap();
+        assert_eq!(request_id, Some(5));
+        assert!(matches!(
+            decode_response(body).unwrap(),
+            CacheResponce::Rejected { code, .. } if code == ERR_MISSING_CAPABILITY
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_unnegotiated_commands_are_rejected() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed
+            .send(encode_hello(PROTOCOL_VERSION, CAP_REQUEST_IDS))
+            .await
+            .unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+
+        let trace_parent = TraceParent {
+            trace_id: [1; 16],
+            parent_id: [2; 8],
+            flags: 1,
+        };
+        for frame in [
+            encode_mget(&["a"]),
+            encode_mset(&[("a", b"v")], None),
+            encode_get_or_lease("a", 1_000),
+            encode_release_lease("a"),
+            encode_set_with_mode("a", b"v", Some(5), ExpirationMode::Sliding),
+            encode_ttl("a"),
+            encode_expire("a", 5),
+            encode_persist("a"),
+            encode_touch("a"),
+            trace_frame(&trace_parent, &encode_get("a")),
+        ] {
+            framed.send(tag_frame(7, &frame)).await.unwrap();
+            let packet = framed.next().await.unwrap().unwrap();
+            let (_, body) = untag_frame(&packet).unwrap();
+            assert!(matches!(
+                decode_response(body).unwrap(),
+                CacheResponce::Rejected { code, .. } if code == ERR_MISSING_CAPABILITY
+            ));
+        }
+
+        framed
+            .send(tag_frame(8, &encode_set("a", b"v", Some(5))))
+            .await
+            .unwrap();
+        let packet = framed.next().await.unwrap().unwrap();
+        let (_, body) = untag_frame(&packet).unwrap();
+        assert!(matches!(decode_response(body).unwrap(), CacheResponce::Ok));
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_get_or_lease_fill_wakes_waiter() {
+        let cache = L1Cache::new(10);
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed
+            .send(tag_frame(1, &encode_get_or_lease("hot", 5_000)))
+            .await
+            .unwrap();
+        let packet = framed.next().await.unwrap().unwrap();
+        let (_, body) = untag_frame(&packet).unwrap();
+        assert!(matches!(
+            decode_response(body).unwrap(),
+            CacheResponce::Lease
+        ));
+
+        framed
+            .send(tag_frame(2, &encode_get_or_lease("hot", 5_000)))
+            .await
+            .unwrap();
+        framed
+            .send(tag_frame(3, &encode_set("hot", b"fresh", None)))
+            .await
+            .unwrap();
+
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            let packet = framed.next().await.unwrap().unwrap();
+            let (request_id, body) = untag_frame(&packet).unwrap();
+            seen.push((request_id.unwrap(), decode_response(body).unwrap()));
+        }
+        seen.sort_by_key(|(id, _)| *id);
+
+        assert!(matches!(&seen[0], (2, CacheResponce::Hit(v)) if v == &b"fresh"[..]));
+        assert!(matches!(&seen[1], (3, CacheResponce::Ok)));
+    }
+
+    #[tokio::test]
+    async fn handle_uds_stream_released_lease_misses_waiter() {
+        let (client, server) = duplex(1024);
+        let cache = L1Cache::new(10);
+        tokio::spawn(handle_uds_stream(server, cache, SharedRedisSync::default()));
+
+        let mut framed = Framed::new(
+            client,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1024)
+                .new_codec(),
+        );
+
+        framed
+            .send(tag_frame(1, &encode_get_or_lease("cold", 5_000)))
+            .await
+            .unwrap();
+        let packet = framed.next().await.unwrap().unwrap();
+        let (_, body) = untag_frame(&packet).unwrap();
+        assert!(matches!(
+            decode_response(body).unwrap(),
+            CacheResponce::Lease
+        ));
+
+        framed
+            .send(tag_frame(2, &encode_get_or_lease("cold", 5_000)))
+            .await
+            .unwrap();
+        framed
+            .send(tag_frame(3, &encode_release_lease("cold")))
+            .await
+            .unwrap();
+
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            let packet = tokio::time::timeout(std::time::Duration::from_secs(1), framed.next())
+                .await
+                .expect("waiter still blocked on a released lease")
+                .unwrap()
+                .unwrap();
+            let (request_id, body) = untag_frame(&packet).unwrap();
+            seen.push((request_id.unwrap(), decode_response(body).unwrap()));
+        }
+        seen.sort_by_key(|(id, _)| *id);
+
+        assert!(matches!(&seen[0], (2, CacheResponce::Miss)));
+        assert!(matches!(&seen[1], (3, CacheResponce::Ok)));
     }
 }
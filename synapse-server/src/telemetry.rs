@@ -0,0 +1,174 @@
+use std::error::Error;
+
+use opentelemetry::{
+    Context,
+    trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider,
+    },
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    trace::{Sampler, SdkTracerProvider},
+};
+use synapse_core::TraceParent;
+use tracing::{Level, Metadata, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{Layer, filter::filter_fn, registry::LookupSpan};
+
+use crate::config::OtlpConfig;
+
+/// Spans that last as long as a client or the subscriber loop. Exporting
+/// them would hang every command of a connection off one endless trace.
+const LONG_LIVED_SPANS: [&str; 2] = ["connection", "redis_subscriber"];
+
+/// Builds the OTLP/gRPC exporter pipeline, or `None` when no endpoint is set.
+pub fn tracer_provider(
+    config: &OtlpConfig,
+) -> Result<Option<SdkTracerProvider>, Box<dyn Error + Send + Sync>> {
+    let Some(endpoint) = &config.endpoint else {
+        return Ok(None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    Ok(Some(
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                config.sample_ratio,
+            ))))
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.service_name.clone())
+                    .build(),
+            )
+            .build(),
+    ))
+}
+
+fn exported(metadata: &Metadata<'_>) -> bool {
+    if !metadata.target().starts_with("synapse_server") {
+        return false;
+    }
+    if metadata.is_span() {
+        !LONG_LIVED_SPANS.contains(&metadata.name())
+    } else {
+        *metadata.level() <= Level::WARN
+    }
+}
+
+/// Exports command spans and their children, plus warnings and errors as
+/// span events. It has its own filter, so `logging.level` only affects what
+/// is logged.
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("synapse-server"))
+        .with_filter(filter_fn(exported))
+}
+
+/// Makes `span` a child of the client's span in the exported trace. Does
+/// nothing when spans are not exported.
+pub fn set_remote_parent(span: &Span, trace_parent: &TraceParent) {
+    let span_context = SpanContext::new(
+        TraceId::from_bytes(trace_parent.trace_id),
+        SpanId::from_bytes(trace_parent.parent_id),
+        TraceFlags::new(trace_parent.flags),
+        true,
+        TraceState::default(),
+    );
+    let _ = span.set_parent(Context::new().with_remote_span_context(span_context));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{layer, tracer_provider};
+    use crate::config::OtlpConfig;
+    use crate::server::uds::execute_command;
+    use opentelemetry_proto::tonic::{
+        collector::trace::v1::{
+            ExportTraceServiceRequest, ExportTraceServiceResponse,
+            trace_service_server::{TraceService, TraceServiceServer},
+        },
+        trace::v1::Span,
+    };
+    use synapse_core::{CacheCommand, L1Cache, TraceParent};
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{Request, Response, Status, transport::Server};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Stands in for an OTLP collector, passing on every span it receives.
+    struct Collector(mpsc::UnboundedSender<Span>);
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            for resource_spans in request.into_inner().resource_spans {
+                for scope_spans in resource_spans.scope_spans {
+                    for span in scope_spans.spans {
+                        let _ = self.0.send(span);
+                    }
+                }
+            }
+            Ok(Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn command_spans_are_exported_under_the_client_span() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (spans_tx, mut spans_rx) = mpsc::unbounded_channel();
+        tokio::spawn(
+            Server::builder()
+                .add_service(TraceServiceServer::new(Collector(spans_tx)))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let provider = tracer_provider(&OtlpConfig {
+            endpoint: Some(format!("http://{}", addr)),
+            ..OtlpConfig::default()
+        })
+        .unwrap()
+        .expect("endpoint is set");
+        let trace_parent: TraceParent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            .parse()
+            .unwrap();
+        let cache = L1Cache::new(10);
+        {
+            let subscriber = tracing_subscriber::registry().with(layer(&provider));
+            let _guard = tracing::subscriber::set_default(subscriber);
+            let get = |key: &str| CacheCommand::Get {
+                key: key.to_string(),
+            };
+            execute_command(get("alpha"), Some(trace_parent), &cache, None).await;
+            // No trace context and a sample ratio of 0: not exported.
+            execute_command(get("beta"), None, &cache, None).await;
+        }
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut spans = Vec::new();
+        while let Ok(span) = spans_rx.try_recv() {
+            spans.push(span);
+        }
+        assert_eq!(spans.len(), 2, "{:?}", spans);
+        let command = spans.iter().find(|span| span.name == "command").unwrap();
+        assert_eq!(command.trace_id, trace_parent.trace_id);
+        assert_eq!(command.parent_span_id, trace_parent.parent_id);
+        let lookup = spans.iter().find(|span| span.name == "l1_lookup").unwrap();
+        assert_eq!(lookup.trace_id, trace_parent.trace_id);
+        assert_eq!(lookup.parent_span_id, command.span_id);
+    }
+}
@@ -0,0 +1,599 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use synapse_core::{
+    CacheResponce, ExpirationMode, L1Cache, decode_response, encode_expire, encode_get,
+    encode_persist, encode_set, encode_touch,
+};
+use synapse_server::{
+    config::{ListenerConfig, RedisConfig, SyncTransport, TimeoutConfig, UdsConfig},
+    redis::{
+        client::{RedisSync, SharedRedisSync},
+        subscriber::spawn_redis_subscriber,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixStream},
+    time::sleep,
+};
+use tokio_util::{
+    codec::{Framed, LengthDelimitedCodec},
+    sync::CancellationToken,
+};
+
+/// Value and TTL in milliseconds; the TTL never counts down.
+type Keys = HashMap<Vec<u8>, (Vec<u8>, Option<i64>)>;
+/// Entry ids (`<n>-0`) and fields of each stream, oldest first. Nothing is
+/// ever trimmed.
+type Streams = HashMap<Vec<u8>, Vec<(u64, Vec<Vec<u8>>)>>;
+
+/// The subset of Redis the server's writes, read-through and stream reader
+/// use: strings with a TTL, `MULTI`/`EXEC`, `PUBLISH` and untrimmed streams.
+/// Every command received is recorded.
+#[derive(Clone, Default)]
+struct FakeRedis {
+    keys: Arc<Mutex<Keys>>,
+    streams: Arc<Mutex<Streams>>,
+    commands: Arc<Mutex<Vec<Vec<String>>>>,
+    connections: Arc<AtomicUsize>,
+    /// Cancelled to close every open connection.
+    open: Arc<Mutex<CancellationToken>>,
+    /// While set, `XREAD`s wait before they are answered.
+    hold_xread: Arc<AtomicBool>,
+}
+
+enum Reply {
+    Status(&'static str),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Reply::Int(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(value)) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// Splits one command off the front of `buf`, if it is complete.
+fn parse_command(buf: &mut BytesMut) -> Option<Vec<Vec<u8>>> {
+    fn line(buf: &[u8], pos: &mut usize) -> Option<i64> {
+        let end = buf[*pos..].windows(2).position(|w| w == b"\r\n")? + *pos;
+        let n = std::str::from_utf8(&buf[*pos + 1..end])
+            .ok()?
+            .parse()
+            .ok()?;
+        *pos = end + 2;
+        Some(n)
+    }
+
+    let mut pos = 0;
+    let count = line(buf, &mut pos)?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let len = line(buf, &mut pos)? as usize;
+        if buf.len() < pos + len + 2 {
+            return None;
+        }
+        args.push(buf[pos..pos + len].to_vec());
+        pos += len + 2;
+    }
+    buf.advance(pos);
+    Some(args)
+}
+
+impl FakeRedis {
+    async fn start(&self) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let redis = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                redis.connections.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(redis.clone().serve(stream));
+            }
+        });
+        format!("redis://{}", addr)
+    }
+
+    async fn serve(self, mut stream: TcpStream) {
+        let open = self.open.lock().unwrap().clone();
+        let mut buf = BytesMut::new();
+        let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+        loop {
+            while let Some(args) = parse_command(&mut buf) {
+                let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+                if name == "XREAD" {
+                    while self.hold_xread.load(Ordering::SeqCst) {
+                        sleep(Duration::from_millis(10)).await;
+                    }
+                }
+                let reply = match (name.as_str(), &mut queued) {
+                    ("MULTI", _) => {
+                        queued = Some(Vec::new());
+                        Reply::Status("OK")
+                    }
+                    ("EXEC", _) => Reply::Array(
+                        queued
+                            .take()
+                            .unwrap_or_default()
+                            .iter()
+                            .map(|args| self.execute(args))
+                            .collect(),
+                    ),
+                    (_, Some(queued)) => {
+                        queued.push(args);
+                        Reply::Status("QUEUED")
+                    }
+                    (_, None) => self.execute(&args),
+                };
+                // A blocking `XREAD` with nothing to return waits a little.
+                if let Reply::Bulk(None) = reply
+                    && name == "XREAD"
+                {
+                    sleep(Duration::from_millis(50)).await;
+                }
+                let mut out = Vec::new();
+                reply.encode(&mut out);
+                if stream.write_all(&out).await.is_err() {
+                    return;
+                }
+            }
+            tokio::select! {
+                _ = open.cancelled() => return,
+                read = stream.read_buf(&mut buf) => match read {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                },
+            }
+        }
+    }
+
+    fn execute(&self, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        self.commands.lock().unwrap().push(
+            args.iter()
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect(),
+        );
+        let number = |arg: &[u8]| -> i64 { String::from_utf8_lossy(arg).parse().unwrap() };
+        let position = |name: &[u8]| args.iter().position(|arg| arg.eq_ignore_ascii_case(name));
+        let mut keys = self.keys.lock().unwrap();
+        let mut streams = self.streams.lock().unwrap();
+        let entry = |(n, fields): &(u64, Vec<Vec<u8>>)| {
+            Reply::Array(vec![
+                Reply::Bulk(Some(format!("{}-0", n).into_bytes())),
+                Reply::Array(fields.iter().cloned().map(Some).map(Reply::Bulk).collect()),
+            ])
+        };
+        match name.as_str() {
+            "GET" => Reply::Bulk(keys.get(&args[1]).map(|(value, _)| value.clone())),
+            "SET" => {
+                let ttl = match args.get(3).map(|arg| arg.to_ascii_uppercase()) {
+                    Some(unit) if unit == b"EX" => Some(number(&args[4]) * 1000),
+                    Some(unit) if unit == b"PX" => Some(number(&args[4])),
+                    _ => None,
+                };
+                keys.insert(args[1].clone(), (args[2].clone(), ttl));
+                Reply::Status("OK")
+            }
+            "SETEX" => {
+                keys.insert(
+                    args[1].clone(),
+                    (args[3].clone(), Some(number(&args[2]) * 1000)),
+                );
+                Reply::Status("OK")
+            }
+            "DEL" => Reply::Int(
+                args[1..]
+                    .iter()
+                    .filter(|key| keys.remove(*key).is_some())
+                    .count() as i64,
+            ),
+            "EXISTS" => Reply::Int(
+                args[1..]
+                    .iter()
+                    .filter(|key| keys.contains_key(*key) || streams.contains_key(*key))
+                    .count() as i64,
+            ),
+            // `XADD <stream> MAXLEN ~ <n> * <field> <value> ...`
+            "XADD" => {
+                let fields = args[position(b"*").unwrap() + 1..].to_vec();
+                let entries = streams.entry(args[1].clone()).or_default();
+                let n = entries.last().map_or(1, |(n, _)| n + 1);
+                entries.push((n, fields));
+                Reply::Bulk(Some(format!("{}-0", n).into_bytes()))
+            }
+            // `XREAD [COUNT <n>] [BLOCK <ms>] STREAMS <stream> <id>`
+            "XREAD" => {
+                let streams_at = position(b"STREAMS").unwrap();
+                let count =
+                    position(b"COUNT").map_or(usize::MAX, |i| number(&args[i + 1]) as usize);
+                let after: u64 = String::from_utf8_lossy(&args[streams_at + 2])
+                    .split('-')
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let found: Vec<Reply> = streams
+                    .get(&args[streams_at + 1])
+                    .into_iter()
+                    .flatten()
+                    .filter(|(n, _)| *n > after)
+                    .take(count)
+                    .map(entry)
+                    .collect();
+                if found.is_empty() {
+                    Reply::Bulk(None)
+                } else {
+                    Reply::Array(vec![Reply::Array(vec![
+                        Reply::Bulk(Some(args[streams_at + 1].clone())),
+                        Reply::Array(found),
+                    ])])
+                }
+            }
+            // `XREVRANGE <stream> + - COUNT 1`
+            "XREVRANGE" => Reply::Array(
+                streams
+                    .get(&args[1])
+                    .and_then(|entries| entries.last())
+                    .map(entry)
+                    .into_iter()
+                    .collect(),
+            ),
+            "XINFO" => {
+                let length = streams.get(&args[2]).map_or(0, Vec::len);
+                Reply::Array(vec![
+                    Reply::Bulk(Some(b"length".to_vec())),
+                    Reply::Int(length as i64),
+                    Reply::Bulk(Some(b"max-deleted-entry-id".to_vec())),
+                    Reply::Bulk(Some(b"0-0".to_vec())),
+                ])
+            }
+            "EXPIRE" => match keys.get_mut(&args[1]) {
+                Some((_, ttl)) => {
+                    *ttl = Some(number(&args[2]) * 1000);
+                    Reply::Int(1)
+                }
+                None => Reply::Int(0),
+            },
+            "PERSIST" => match keys.get_mut(&args[1]) {
+                Some((_, ttl)) if ttl.is_some() => {
+                    *ttl = None;
+                    Reply::Int(1)
+                }
+                _ => Reply::Int(0),
+            },
+            "PTTL" => Reply::Int(match keys.get(&args[1]) {
+                Some((_, ttl)) => ttl.unwrap_or(-1),
+                None => -2,
+            }),
+            "PUBLISH" => Reply::Int(0),
+            _ => Reply::Status("OK"),
+        }
+    }
+
+    fn insert(&self, key: &str, value: &[u8], ttl_ms: Option<i64>) {
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(key.as_bytes().to_vec(), (value.to_vec(), ttl_ms));
+    }
+
+    fn ttl_ms(&self, key: &str) -> Option<Option<i64>> {
+        self.keys
+            .lock()
+            .unwrap()
+            .get(key.as_bytes())
+            .map(|(_, ttl)| *ttl)
+    }
+
+    /// Closes every open connection; new ones are accepted as before.
+    fn drop_connections(&self) {
+        let open = std::mem::take(&mut *self.open.lock().unwrap());
+        open.cancel();
+    }
+
+    fn count(&self, command: &str) -> usize {
+        self.commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|args| args[0].eq_ignore_ascii_case(command))
+            .count()
+    }
+}
+
+fn unique_socket_path() -> PathBuf {
+    let mut path = env::temp_dir();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_nanos(0))
+        .as_nanos();
+    path.push(format!(
+        "synapse-redis-{}-{}.sock",
+        std::process::id(),
+        nanos
+    ));
+    path
+}
+
+struct Server {
+    framed: Framed<UnixStream, LengthDelimitedCodec>,
+    socket_path: PathBuf,
+    shutdown: CancellationToken,
+}
+
+impl Server {
+    async fn start(cache: L1Cache, redis: RedisConfig) -> Self {
+        let socket_path = unique_socket_path();
+        let socket_str = socket_path.to_string_lossy().to_string();
+        let config = UdsConfig {
+            socket_path: socket_str.clone(),
+            ..UdsConfig::default()
+        };
+        let redis_sync = RedisSync::from_config(&redis, &TimeoutConfig::default()).unwrap();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(synapse_server::server::uds::run_uds(
+            config,
+            cache,
+            shutdown.clone(),
+            SharedRedisSync::new(redis_sync),
+        ));
+
+        for _ in 0..100 {
+            match UnixStream::connect(&socket_str).await {
+                Ok(stream) => {
+                    return Self {
+                        framed: Framed::new(stream, LengthDelimitedCodec::new()),
+                        socket_path,
+                        shutdown,
+                    };
+                }
+                Err(_) => sleep(Duration::from_millis(10)).await,
+            }
+        }
+        panic!("server did not accept UDS connection");
+    }
+
+    async fn call(&mut self, frame: Bytes) -> CacheResponce {
+        self.framed.send(frame).await.unwrap();
+        let response = self.framed.next().await.unwrap().unwrap();
+        decode_response(&response).unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+#[tokio::test]
+async fn ttl_changes_reach_redis_for_keys_cold_in_l1() {
+    let redis = FakeRedis::default();
+    let url = redis.start().await;
+    redis.insert("synapse:cache:alpha", b"v1", None);
+    let mut server = Server::start(
+        L1Cache::new(10),
+        RedisConfig {
+            url: Some(url),
+            ..RedisConfig::default()
+        },
+    )
+    .await;
+
+    assert!(matches!(
+        server.call(encode_expire("alpha", 60)).await,
+        CacheResponce::Ok
+    ));
+    assert_eq!(redis.ttl_ms("synapse:cache:alpha"), Some(Some(60_000)));
+    assert!(matches!(
+        server.call(encode_touch("alpha")).await,
+        CacheResponce::Ok
+    ));
+    assert!(matches!(
+        server.call(encode_persist("alpha")).await,
+        CacheResponce::Ok
+    ));
+    assert_eq!(redis.ttl_ms("synapse:cache:alpha"), Some(None));
+    assert_eq!(redis.count("PUBLISH"), 3);
+
+    assert!(matches!(
+        server.call(encode_expire("beta", 60)).await,
+        CacheResponce::Miss
+    ));
+    assert!(matches!(
+        server.call(encode_persist("beta")).await,
+        CacheResponce::Miss
+    ));
+    assert!(matches!(
+        server.call(encode_touch("beta")).await,
+        CacheResponce::Miss
+    ));
+}
+
+#[tokio::test]
+async fn get_misses_are_filled_from_redis_over_one_connection() {
+    let redis = FakeRedis::default();
+    let url = redis.start().await;
+    redis.insert("synapse:cache:alpha", b"v1", Some(60_000));
+    let cache = L1Cache::new(10);
+    let mut server = Server::start(
+        cache.clone(),
+        RedisConfig {
+            url: Some(url),
+            read_through: true,
+            ..RedisConfig::default()
+        },
+    )
+    .await;
+
+    for _ in 0..2 {
+        assert!(matches!(
+            server.call(encode_get("alpha")).await,
+            CacheResponce::Hit(value) if value == b"v1"[..]
+        ));
+    }
+    assert_eq!(redis.count("GET"), 1);
+    assert!(matches!(
+        cache.ttl("alpha").await,
+        CacheResponce::Ttl(Some(ttl)) if ttl > 50_000 && ttl <= 60_000
+    ));
+
+    assert!(matches!(
+        server.call(encode_get("beta")).await,
+        CacheResponce::Miss
+    ));
+    assert!(matches!(
+        server.call(encode_set("gamma", b"v3", None)).await,
+        CacheResponce::Ok
+    ));
+    assert_eq!(redis.count("GET"), 2);
+    assert_eq!(redis.count("SET"), 1);
+    assert_eq!(redis.connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn resp_key_commands_see_keys_only_redis_has() {
+    let redis = FakeRedis::default();
+    let url = redis.start().await;
+    redis.insert("synapse:cache:alpha", b"v1", Some(60_000));
+    let redis_sync = RedisSync::from_config(
+        &RedisConfig {
+            url: Some(url),
+            read_through: true,
+            ..RedisConfig::default()
+        },
+        &TimeoutConfig::default(),
+    )
+    .unwrap();
+    let socket_path = unique_socket_path();
+    let shutdown = CancellationToken::new();
+    tokio::spawn(synapse_server::server::resp::run_resp(
+        ListenerConfig {
+            addr: Some(format!("unix:{}", socket_path.display())),
+        },
+        L1Cache::new(10),
+        shutdown.clone(),
+        SharedRedisSync::new(redis_sync),
+    ));
+    let mut stream = None;
+    for _ in 0..100 {
+        match UnixStream::connect(&socket_path).await {
+            Ok(connected) => {
+                stream = Some(connected);
+                break;
+            }
+            Err(_) => sleep(Duration::from_millis(10)).await,
+        }
+    }
+    let mut stream = stream.expect("server did not accept RESP connection");
+
+    for (request, expected) in [
+        ("EXISTS alpha beta\r\n", ":1\r\n"),
+        ("TTL alpha\r\n", ":60\r\n"),
+        ("TTL beta\r\n", ":-2\r\n"),
+        ("DEL alpha beta\r\n", ":1\r\n"),
+        ("EXISTS alpha\r\n", ":0\r\n"),
+    ] {
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![0; expected.len()];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&response), expected, "{}", request);
+    }
+    assert_eq!(redis.count("DEL"), 2);
+
+    shutdown.cancel();
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+/// Waits for `done`, checking every 10ms for up to 5s.
+async fn eventually(mut done: impl FnMut() -> bool) {
+    for _ in 0..500 {
+        if done() {
+            return;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met in time");
+}
+
+#[tokio::test]
+async fn reconnecting_stream_reader_does_not_serve_missed_deletes() {
+    let redis = FakeRedis::default();
+    let url = redis.start().await;
+    let redis_sync = |node_id: &str| {
+        let config = RedisConfig {
+            url: Some(url.clone()),
+            transport: SyncTransport::Stream,
+            node_id: Some(node_id.to_string()),
+            ..RedisConfig::default()
+        };
+        RedisSync::from_config(&config, &TimeoutConfig::default())
+            .unwrap()
+            .expect("url is set")
+    };
+    let peer = redis_sync("peer");
+    peer.set("gamma", b"v3", None, ExpirationMode::Absolute)
+        .await
+        .unwrap();
+
+    let cache = L1Cache::new(10);
+    cache.set("alpha".to_string(), "v1".into(), None).await;
+    cache.set("beta".to_string(), "v2".into(), None).await;
+    let shutdown = CancellationToken::new();
+    spawn_redis_subscriber(cache.clone(), shutdown.clone(), redis_sync("local"));
+    eventually(|| redis.count("XREAD") > 0).await;
+
+    // Away: the peer deletes a key, and replaying that is slow.
+    redis.hold_xread.store(true, Ordering::SeqCst);
+    redis.drop_connections();
+    redis_sync("peer").delete("alpha").await.unwrap();
+    eventually(|| redis.count("XINFO") > 0).await;
+    sleep(Duration::from_millis(50)).await;
+    assert!(matches!(cache.get("alpha").await, CacheResponce::Miss));
+
+    let blocking_reads = || {
+        redis
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|args| args[0] == "XREAD" && args.iter().any(|arg| arg == "BLOCK"))
+            .count()
+    };
+    let caught_up = blocking_reads();
+    redis.hold_xread.store(false, Ordering::SeqCst);
+    eventually(|| blocking_reads() > caught_up).await;
+    assert!(matches!(cache.get("alpha").await, CacheResponce::Miss));
+    assert!(matches!(cache.get("beta").await, CacheResponce::Hit(v) if v == "v2"));
+    shutdown.cancel();
+}
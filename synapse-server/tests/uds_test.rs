@@ -1,13 +1,14 @@
 use std::{
     env,
+    os::unix::fs::PermissionsExt,
     path::PathBuf,
-    sync::{Mutex, OnceLock},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use bytes::{Buf, BufMut, BytesMut};
 use futures::{SinkExt, StreamExt};
 use synapse_core::{L1Cache, OP_GET, OP_SET, RES_HIT, RES_OK};
+use synapse_server::{config::UdsConfig, redis::client::SharedRedisSync};
 use tokio::net::UnixStream;
 use tokio::time::sleep;
 use tokio_util::{
@@ -15,8 +16,6 @@ use tokio_util::{
     sync::CancellationToken,
 };
 
-static ENV_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
-
 fn unique_socket_path() -> PathBuf {
     let mut path = env::temp_dir();
     let nanos = SystemTime::now()
@@ -48,21 +47,22 @@ fn encode_set(key: &str, value: &[u8]) -> BytesMut {
 
 #[tokio::test]
 async fn run_uds_set_get_roundtrip() {
-    let _guard = ENV_LOCK.get_or_init(|| Mutex::new(())).lock().unwrap();
-
     let socket_path = unique_socket_path();
     let socket_str = socket_path.to_string_lossy().to_string();
     let _ = std::fs::remove_file(&socket_path);
-    unsafe {
-        env::set_var("SYNAPSE_SOCKET_PATH", &socket_str);
-    }
+    let config = UdsConfig {
+        socket_path: socket_str.clone(),
+        socket_mode: Some("600".to_string()),
+        ..UdsConfig::default()
+    };
 
     let cache = L1Cache::new(10);
     let shutdown = CancellationToken::new();
     let mut server_task = tokio::spawn(synapse_server::server::uds::run_uds(
+        config,
         cache,
         shutdown.clone(),
-        None,
+        SharedRedisSync::default(),
     ));
 
     let mut framed = None;
@@ -81,6 +81,11 @@ async fn run_uds_set_get_roundtrip() {
         }
     }
     let mut framed = framed.expect("server did not accept UDS connection");
+    let mode = std::fs::metadata(&socket_path)
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
 
     framed
         .send(encode_set("alpha", b"v1").freeze())
@@ -105,7 +110,4 @@ async fn run_uds_set_get_roundtrip() {
         server_task.abort();
     }
     let _ = std::fs::remove_file(&socket_path);
-    unsafe {
-        env::remove_var("SYNAPSE_SOCKET_PATH");
-    }
 }